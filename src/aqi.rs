@@ -0,0 +1,125 @@
+// US EPA Air Quality Index for PM2.5, using the breakpoints from the
+// February 2024 PM NAAQS revision:
+// https://www.airnow.gov/sites/default/files/2024-02/pm-naaqs-air-quality-index-fact-sheet.pdf
+extern crate std;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Category {
+    Good,
+    Moderate,
+    UnhealthyForSensitiveGroups,
+    Unhealthy,
+    VeryUnhealthy,
+    Hazardous,
+}
+
+impl Category {
+    pub const ALL: [Category; 6] = [
+        Category::Good,
+        Category::Moderate,
+        Category::UnhealthyForSensitiveGroups,
+        Category::Unhealthy,
+        Category::VeryUnhealthy,
+        Category::Hazardous,
+    ];
+
+    // Short enough to fit next to the number on the display.
+    pub fn label(&self) -> &'static str {
+        return match *self {
+            Category::Good => "Good",
+            Category::Moderate => "Moderate",
+            Category::UnhealthyForSensitiveGroups => "USG",
+            Category::Unhealthy => "Unhealthy",
+            Category::VeryUnhealthy => "V. Unhealthy",
+            Category::Hazardous => "Hazardous",
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aqi {
+    pub value: u32,
+    pub category: Category,
+}
+
+// (concentration low, concentration high, index low, index high, category)
+const PM25_BREAKPOINTS: [(f32, f32, u32, u32, Category); 6] = [
+    (0.0, 9.0, 0, 50, Category::Good),
+    (9.1, 35.4, 51, 100, Category::Moderate),
+    (35.5, 55.4, 101, 150, Category::UnhealthyForSensitiveGroups),
+    (55.5, 125.4, 151, 200, Category::Unhealthy),
+    (125.5, 225.4, 201, 300, Category::VeryUnhealthy),
+    (225.5, 325.4, 301, 500, Category::Hazardous),
+];
+
+// Concentrations above the last breakpoint are "beyond the AQI" and are
+// reported as 500.
+pub fn from_pm25(pm25_ugm3: f32) -> Aqi {
+    // EPA truncates PM2.5 to one decimal place before looking it up. The
+    // epsilon keeps e.g. 2.3f32 (2.2999999...) from truncating to 2.2.
+    let concentration = (std::cmp::max(0, (pm25_ugm3 * 10.0 + 0.001).floor() as i64) as f32) / 10.0;
+
+    for &(c_low, c_high, i_low, i_high, category) in PM25_BREAKPOINTS.iter() {
+        if concentration <= c_high {
+            let value = (i_high - i_low) as f32 / (c_high - c_low) * (concentration - c_low) + i_low as f32;
+            return Aqi{
+                value: value.round() as u32,
+                category: category,
+            };
+        }
+    }
+
+    return Aqi{
+        value: 500,
+        category: Category::Hazardous,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::from_pm25;
+    use super::Category;
+
+    #[test]
+    fn breakpoint_edges() {
+        assert_eq!(0, from_pm25(0.0).value);
+        assert_eq!(50, from_pm25(9.0).value);
+        assert_eq!(51, from_pm25(9.1).value);
+        assert_eq!(100, from_pm25(35.4).value);
+        assert_eq!(101, from_pm25(35.5).value);
+        assert_eq!(150, from_pm25(55.4).value);
+        assert_eq!(151, from_pm25(55.5).value);
+        assert_eq!(200, from_pm25(125.4).value);
+        assert_eq!(201, from_pm25(125.5).value);
+        assert_eq!(300, from_pm25(225.4).value);
+        assert_eq!(301, from_pm25(225.5).value);
+        assert_eq!(500, from_pm25(325.4).value);
+    }
+
+    #[test]
+    fn interpolation_and_truncation() {
+        // 50 / 9.0 * 4.5
+        assert_eq!(25, from_pm25(4.5).value);
+        // Truncated to 9.0 rather than rounded up to 9.1
+        assert_eq!(50, from_pm25(9.09).value);
+        // (100 - 51) / (35.4 - 9.1) * (12.0 - 9.1) + 51
+        assert_eq!(56, from_pm25(12.0).value);
+    }
+
+    #[test]
+    fn categories() {
+        assert_eq!(Category::Good, from_pm25(4.66).category);
+        assert_eq!(Category::Moderate, from_pm25(20.0).category);
+        assert_eq!(Category::UnhealthyForSensitiveGroups, from_pm25(40.0).category);
+        assert_eq!(Category::Unhealthy, from_pm25(100.0).category);
+        assert_eq!(Category::VeryUnhealthy, from_pm25(200.0).category);
+        assert_eq!(Category::Hazardous, from_pm25(300.0).category);
+    }
+
+    #[test]
+    fn out_of_range() {
+        assert_eq!(0, from_pm25(-3.0).value);
+        assert_eq!(Category::Hazardous, from_pm25(800.0).category);
+        assert_eq!(500, from_pm25(800.0).value);
+    }
+}
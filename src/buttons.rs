@@ -0,0 +1,164 @@
+// Push buttons wired from spare GPIO pins to ground, like the keys on some
+// Waveshare HATs. Each press is passed on as an Action, along with those
+// simulated from the debug server, for the main loop to act on.
+extern crate rppal;
+extern crate std;
+
+use crate::result;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    // Redraw now, with a full refresh.
+    Refresh,
+    NextScreen,
+    // Go into or out of night mode, as quiet hours would.
+    ToggleNight,
+}
+
+pub const ACTIONS : [Action; 3] = [Action::Refresh, Action::NextScreen, Action::ToggleNight];
+
+impl std::str::FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Action, String> {
+        return match s {
+            "refresh" => Ok(Action::Refresh),
+            "next-screen" => Ok(Action::NextScreen),
+            "night" => Ok(Action::ToggleNight),
+            _ => Err(format!("Unknown button action '{}', expected refresh, next-screen or night", s)),
+        };
+    }
+}
+
+impl Action {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Action::Refresh => "refresh",
+            Action::NextScreen => "next-screen",
+            Action::ToggleNight => "night",
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Button {
+    // BCM numbering, like the panel's pins.
+    pub pin: u8,
+    pub action: Action,
+}
+
+// PIN:ACTION, e.g. 5:refresh.
+impl std::str::FromStr for Button {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Button, String> {
+        return match s.split_once(':') {
+            Some((pin, action)) => Ok(Button{
+                pin: pin.parse().map_err(|err| format!("Bad GPIO pin '{}' for button '{}': {}", pin, s, err))?,
+                action: action.parse()?,
+            }),
+            None => Err(format!("Unknown button '{}', expected PIN:ACTION", s)),
+        };
+    }
+}
+
+// Contacts bounce for a few milliseconds, and a press shouldn't be counted
+// more than once.
+const DEBOUNCE : std::time::Duration = std::time::Duration::from_millis(200);
+
+// Counts a press when the contacts close after being still for a while.
+// They bounce on release as well as on press, so every edge, rising or
+// falling, starts the wait over again.
+pub struct Debouncer {
+    interval: std::time::Duration,
+    // The last edge seen, whether or not it counted.
+    last: Option<std::time::Instant>,
+}
+
+impl Debouncer {
+    pub fn new(interval: std::time::Duration) -> Debouncer {
+        return Debouncer{interval: interval, last: None};
+    }
+
+    // For an edge at `at` that leaves the button `pressed` or not.
+    pub fn accept(&mut self, pressed: bool, at: std::time::Instant) -> bool {
+        let still = match self.last {
+            Some(last) => at.saturating_duration_since(last) >= self.interval,
+            None => true,
+        };
+        self.last = Some(at);
+        return pressed && still;
+    }
+}
+
+// Keeps the buttons' interrupts set up, until dropped.
+pub struct Listener {
+    _pins: Vec<rppal::gpio::InputPin>,
+}
+
+// Sends each button's Action to `presses` as it's pressed, converted to
+// whatever the channel carries. The pins are pulled up, so a press is a
+// falling edge, and a release a rising one.
+pub fn listen<T>(buttons: &[Button], presses: std::sync::mpsc::Sender<T>) -> result::TTDashResult<Listener>
+where T: From<Action> + Send + 'static {
+    let gpio = rppal::gpio::Gpio::new()?;
+    let mut pins = vec![];
+    for button in buttons {
+        let mut pin = gpio.get(button.pin)?.into_input_pullup();
+        let button = *button;
+        let presses = presses.clone();
+        let mut debouncer = Debouncer::new(DEBOUNCE);
+        pin.set_async_interrupt(rppal::gpio::Trigger::Both, move |level| {
+            if !debouncer.accept(level == rppal::gpio::Level::Low, std::time::Instant::now()) {
+                return;
+            }
+            info!("Button on GPIO {} pressed: {}", button.pin, button.action.name());
+            if presses.send(button.action.into()).is_err() {
+                warn!("Nothing's listening for button presses.");
+            }
+        })?;
+        pins.push(pin);
+    }
+    return Ok(Listener{_pins: pins});
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Action;
+    use super::Button;
+    use super::Debouncer;
+
+    #[test]
+    fn parse() {
+        assert_eq!(Ok(Button{pin: 5, action: Action::Refresh}), "5:refresh".parse());
+        assert_eq!(Ok(Button{pin: 19, action: Action::ToggleNight}), "19:night".parse());
+        assert!("5".parse::<Button>().is_err());
+        assert!("five:refresh".parse::<Button>().is_err());
+        assert!("5:reboot".parse::<Button>().is_err());
+        for action in super::ACTIONS.iter() {
+            assert_eq!(Ok(*action), action.name().parse());
+        }
+    }
+
+    #[test]
+    fn debounce() {
+        let interval = std::time::Duration::from_millis(200);
+        let start = std::time::Instant::now();
+        let mut debouncer = Debouncer::new(interval);
+        // (ms, pressed) for each edge.
+        let mut edges = |edges: Vec<(u64, bool)>| -> Vec<bool> {
+            return edges.into_iter()
+                .map(|(at, pressed)| debouncer.accept(pressed, start + std::time::Duration::from_millis(at)))
+                .collect();
+        };
+        // Bouncing on the way down counts once.
+        assert_eq!(vec![true, false, false, false, false],
+                   edges(vec![(0, true), (2, false), (4, true), (6, false), (8, true)]));
+        // Held for a while, then bouncing on the way up doesn't count at all.
+        assert_eq!(vec![false, false, false, false, false],
+                   edges(vec![(700, false), (702, true), (705, false), (707, true), (709, false)]));
+        // A quick tap after that does, and so does the next one.
+        assert_eq!(vec![true, false, false, false, true],
+                   edges(vec![(1000, true), (1080, false), (1083, true), (1085, false), (1400, true)]));
+    }
+}
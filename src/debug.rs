@@ -3,9 +3,28 @@ extern crate querystring;
 extern crate std;
 extern crate tiny_http;
 
+use crate::buttons;
+use crate::result;
 use crate::update;
 
-pub fn run_debug_server(port: &str, local_png: Option<String>) {
+// What the main loop is asked to do between refreshes, by buttons or from
+// here.
+pub enum Request {
+    Press(buttons::Action),
+    // A PNG of the named screen, drawn with the latest data.
+    Preview(String, std::sync::mpsc::Sender<result::TTDashResult<Vec<u8>>>),
+}
+
+impl From<buttons::Action> for Request {
+    fn from(action: buttons::Action) -> Request {
+        return Request::Press(action);
+    }
+}
+
+// Long enough for the main loop to finish a refresh first.
+const PREVIEW_TIMEOUT : std::time::Duration = std::time::Duration::from_secs(90);
+
+pub fn run_debug_server(port: &str, local_png: Option<String>, screens: Vec<String>, requests: std::sync::mpsc::Sender<Request>) {
     let server = tiny_http::Server::http(format!("0.0.0.0:{}", port)).expect("http server");
     debug!("Running debug HTTP server on port {}", port);
 
@@ -13,11 +32,15 @@ pub fn run_debug_server(port: &str, local_png: Option<String>) {
         let url = request.url().clone();
         info!("Request: {}", url);
         if url == "/" {
-            main_page(request, local_png.is_some())
+            main_page(request, local_png.is_some(), &screens)
         } else if url.starts_with("/dumplog") {
             dump_log(request)
         } else if url == "/current_image" {
             current_image(request, local_png.as_ref().map(String::as_str))
+        } else if url.starts_with("/button") {
+            press_button(request, &requests)
+        } else if url.starts_with("/screen") {
+            preview_screen(request, &requests)
         } else {
             let response = tiny_http::Response::from_string(
                 format!("Unknown URL: {}", url));
@@ -44,6 +67,66 @@ fn which_log(request: &tiny_http::Request) -> Option<String> {
     return None;
 }
 
+fn query_param(request: &tiny_http::Request, name: &str) -> Option<String> {
+    let query_string = request.url().splitn(2, '?').nth(1).unwrap_or("");
+    for (k, v) in querystring::querify(query_string) {
+        if k == name {
+            return Some(v.to_string());
+        }
+    }
+    return None;
+}
+
+// Does what pressing a button with this action would.
+fn press_button(request: tiny_http::Request, requests: &std::sync::mpsc::Sender<Request>) {
+    let action = match query_param(&request, "action").and_then(|action| action.parse::<buttons::Action>().ok()) {
+        Some(action) => action,
+        None => {
+            request.respond(
+                tiny_http::Response::from_string("action missing or unknown")).unwrap();
+            return;
+        },
+    };
+
+    info!("Simulated button pressed: {}", action.name());
+    let message = match requests.send(Request::Press(action)) {
+        Ok(_) => format!("Pressed {}", action.name()),
+        Err(err) => format!("ERROR: {:?}", err),
+    };
+    request.respond(
+        tiny_http::Response::from_string(message)).unwrap();
+}
+
+fn preview_screen(request: tiny_http::Request, requests: &std::sync::mpsc::Sender<Request>) {
+    let name = match query_param(&request, "name") {
+        Some(name) => name,
+        None => {
+            request.respond(
+                tiny_http::Response::from_string("name missing")).unwrap();
+            return;
+        },
+    };
+
+    let (reply, preview) = std::sync::mpsc::channel();
+    let png = requests.send(Request::Preview(name, reply))
+        .map_err(|err| format!("{:?}", err))
+        .and_then(|_| preview.recv_timeout(PREVIEW_TIMEOUT).map_err(|err| format!("{:?}", err)))
+        .and_then(|png| png.map_err(|err| err.to_string()));
+    match png {
+        Ok(bytes) => {
+            request.respond(
+                tiny_http::Response::from_data(bytes)
+                    .with_header(tiny_http::Header::from_bytes(
+                        &b"Content-Type"[..], &b"image/png"[..]).unwrap())).unwrap();
+        },
+        Err(err) => {
+            request.respond(
+                tiny_http::Response::from_string(
+                    format!("ERROR: {}", err))).unwrap();
+        },
+    }
+}
+
 fn current_image(request: tiny_http::Request, local_png: Option<&str>) {
     match local_png {
         None => {
@@ -92,7 +175,7 @@ fn dump_log(request: tiny_http::Request) {
     }
 }
 
-fn main_page(request: tiny_http::Request, has_local_png: bool)  {
+fn main_page(request: tiny_http::Request, has_local_png: bool, screens: &[String])  {
     let mut body = format!("<html><body><h1>TTDash Debug Server</h1><div>Version {}</div>",
                            update::local_version()
                            .map(|v| v.to_string())
@@ -102,6 +185,18 @@ fn main_page(request: tiny_http::Request, has_local_png: bool)  {
         body.push_str("<div><h2>Current image</h2><img style='border: 1px solid black;' src='/current_image' /></div>");
     }
 
+    body.push_str("<div><h2>Screens</h2>");
+    for screen in screens {
+        body.push_str(&format!("<div><h3>{}</h3><img style='border: 1px solid black;' src='/screen?name={}' /></div>", screen, screen));
+    }
+    body.push_str("</div>");
+
+    body.push_str("<div><h2>Buttons</h2><ul>");
+    for action in buttons::ACTIONS.iter() {
+        body.push_str(&format!("<li><a href='/button?action={}'>{}</a></li>", action.name(), action.name()));
+    }
+    body.push_str("</ul></div>");
+
     body.push_str("<div><h2>Log files</h2><ul>");
     let mut dirents: Vec<std::fs::DirEntry> = std::fs::read_dir("./")
         .expect("fs.read_dir")
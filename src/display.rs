@@ -1,10 +1,13 @@
+extern crate chrono;
 extern crate image;
 extern crate rppal;
 extern crate std;
 
-use rppal::gpio::{Gpio, Level, InputPin, OutputPin};
+use rppal::gpio::{Level, InputPin, OutputPin};
 use rppal::spi::{Spi};
 
+use crate::dither;
+use crate::layout;
 use crate::result;
 
 const RST_PIN : u8 = 17;
@@ -12,147 +15,1628 @@ const DC_PIN : u8 = 25;
 const _CS_PIN : u8 = 8;
 const BUSY_PIN : u8 = 24;
 
+// Commands shared by the UC8159 (7.5" v1), UC8179 (7.5" v2 and B v2) and
+// IL0398 (4.2" and B/C) controllers.
 const PANEL_SETTING : u8 = 0x00;
 const POWER_SETTING : u8 = 0x01;
+const POWER_OFF : u8 = 0x02;
 const POWER_ON : u8 = 0x04;
 const BOOSTER_SOFT_START : u8 = 0x06;
+const DEEP_SLEEP : u8 = 0x07;
 const DATA_START_TRANSMISSION : u8 = 0x10;
 const DISPLAY_REFRESH : u8 = 0x12;
+const DATA_START_TRANSMISSION_2 : u8 = 0x13;
+const DUAL_SPI : u8 = 0x15;
 const PLL_CONTROL : u8 = 0x30;
 const TEMPERATURE_CALIBRATION : u8 = 0x41;
 const VCOM_AND_DATA_INTERVAL_SETTING : u8 = 0x50;
 const TCON_SETTING : u8 = 0x60;
 const TCON_RESOLUTION : u8 = 0x61;
+const GET_STATUS : u8 = 0x71;
 const VCM_DC_SETTING : u8 = 0x82;
+const PARTIAL_WINDOW : u8 = 0x90;
+const PARTIAL_IN : u8 = 0x91;
+const GATE_SOURCE_START : u8 = 0x65;
+const CASCADE_SETTING : u8 = 0xE0;
+const FORCE_TEMPERATURE : u8 = 0xE5;
 
-pub fn setup_and_display_image(image: &image::GrayImage) -> result::TTDashResult<()>{
-    let mut gpio = rppal::gpio::Gpio::new()?;
-    let mut dc_pin = gpio.get(DC_PIN).expect("get dc pin").into_output();
-    let busy_pin = gpio.get(BUSY_PIN).expect("get busy pin").into_input();
+// Commands for the SSD1680 (2.9" v2).
+const DRIVER_OUTPUT_CONTROL : u8 = 0x01;
+const DEEP_SLEEP_MODE : u8 = 0x10;
+const DATA_ENTRY_MODE : u8 = 0x11;
+const SW_RESET : u8 = 0x12;
+const TEMPERATURE_SENSOR_CONTROL : u8 = 0x18;
+const MASTER_ACTIVATION : u8 = 0x20;
+const DISPLAY_UPDATE_CONTROL_1 : u8 = 0x21;
+const DISPLAY_UPDATE_CONTROL_2 : u8 = 0x22;
+const WRITE_RAM_BW : u8 = 0x24;
+const WRITE_RAM_RED : u8 = 0x26;
+const BORDER_WAVEFORM_CONTROL : u8 = 0x3C;
+const SET_RAM_X_ADDRESS_START_END : u8 = 0x44;
+const SET_RAM_Y_ADDRESS_START_END : u8 = 0x45;
+const SET_RAM_X_ADDRESS_COUNTER : u8 = 0x4E;
+const SET_RAM_Y_ADDRESS_COUNTER : u8 = 0x4F;
 
-    // Don't forget to enable SPI with sudo raspi-config
-    let mut spi = rppal::spi::Spi::new(
-        rppal::spi::Bus::Spi0,
-        rppal::spi::SlaveSelect::Ss0,
-        2000000,
-        rppal::spi::Mode::Mode0)?;
+const BLACK_AND_WHITE : [u8; 2] = [0, 255];
+// Black, the two grays drawing.rs uses, and white.
+const FOUR_GRAYS : [u8; 4] = [0, 128, 192, 255];
 
-    init_display(&mut gpio, &mut spi, &mut dc_pin, &busy_pin);
-    display_image(&mut dc_pin, &busy_pin, &mut spi, image);
+// Roughly how the pigments look, for the image sinks.
+const RED : image::Rgb<u8> = image::Rgb([200, 0, 0]);
+const YELLOW : image::Rgb<u8> = image::Rgb([240, 190, 0]);
 
+// A Waveshare e-paper panel: its size, how to wake it up, how its controller
+// wants pixels laid out and how to get them onto the screen.
+pub trait Panel {
+    fn name(&self) -> &'static str;
+    fn width(&self) -> u32;
+    fn height(&self) -> u32;
+    // The gray levels it can show, darkest first. `pack` expects images
+    // already reduced to these.
+    fn palette(&self) -> &'static [u8] {
+        return &BLACK_AND_WHITE;
+    }
+    // The red or yellow of three color panels, which `pack` also expects
+    // exactly.
+    fn accent(&self) -> Option<image::Rgb<u8>> {
+        return None;
+    }
+    fn init(&self, epd: &mut dyn Epd) -> result::TTDashResult<()>;
+    // The image, which is width x height, in the controller's framebuffer
+    // format. Three color panels get the black plane then the accent plane.
+    fn pack(&self, imgbuf: &image::RgbImage) -> Vec<u8>;
+    fn refresh(&self, epd: &mut dyn Epd, data: &[u8]) -> result::TTDashResult<()>;
+
+    // Between refreshes the panel is powered off, keeping its settings, so
+    // the next one only needs it powering on again. Controllers that power
+    // themselves down after every refresh, like the SSD1680, leave these be.
+    fn power_on(&self, _epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        return Ok(());
+    }
+
+    fn power_off(&self, _epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        return Ok(());
+    }
+
+    // Deep sleep, once powered off, until the next `init` resets the
+    // controller.
+    fn sleep(&self, epd: &mut dyn Epd) -> result::TTDashResult<()>;
+
+    // Panels that can redraw part of the screen without the full flashing
+    // refresh implement these too.
+    fn supports_partial_refresh(&self) -> bool {
+        return false;
+    }
+
+    fn init_partial(&self, epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        return self.init(epd);
+    }
+
+    fn refresh_partial(&self, _epd: &mut dyn Epd, _windows: &[Window]) -> result::TTDashResult<()> {
+        return Err(result::make_error(&format!("{} doesn't support partial refresh", self.name())));
+    }
+}
+
+// Part of the screen to update, with what's there now and what should be,
+// each packed the way the panel's `pack` would.
+pub struct Window {
+    pub rect: layout::Rect,
+    pub old: Vec<u8>,
+    pub new: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Model {
+    Epd7in5,
+    Epd7in5V2,
+    Epd4in2,
+    Epd2in9V2,
+    Epd7in5BV2,
+    Epd4in2B,
+    Epd4in2C,
+}
+
+impl std::str::FromStr for Model {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Model, String> {
+        return match s.to_lowercase().as_ref() {
+            "7in5" => Ok(Model::Epd7in5),
+            "7in5_v2" => Ok(Model::Epd7in5V2),
+            "4in2" => Ok(Model::Epd4in2),
+            "2in9_v2" => Ok(Model::Epd2in9V2),
+            "7in5b_v2" => Ok(Model::Epd7in5BV2),
+            "4in2b" => Ok(Model::Epd4in2B),
+            "4in2c" => Ok(Model::Epd4in2C),
+            _ => Err(format!("Unknown panel '{}', expected 7in5, 7in5_v2, 4in2, 2in9_v2, 7in5b_v2, 4in2b or 4in2c", s)),
+        };
+    }
+}
+
+impl Model {
+    pub fn panel(&self) -> Box<dyn Panel> {
+        return match self {
+            Model::Epd7in5 => Box::new(Epd7in5{}),
+            Model::Epd7in5V2 => Box::new(Epd7in5V2{}),
+            Model::Epd4in2 => Box::new(Epd4in2{}),
+            Model::Epd2in9V2 => Box::new(Epd2in9V2{}),
+            Model::Epd7in5BV2 => Box::new(Epd7in5BV2{}),
+            Model::Epd4in2B => Box::new(Epd4in2Bc{name: "4in2b", accent: RED}),
+            Model::Epd4in2C => Box::new(Epd4in2Bc{name: "4in2c", accent: YELLOW}),
+        };
+    }
+}
+
+// How panels talk to their controller: `SpiEpd` is the real thing, and the
+// tests' `RecordingEpd` keeps what would have been sent.
+pub trait Epd {
+    fn reset(&mut self) -> result::TTDashResult<()>;
+    fn send_command(&mut self, command: u8) -> result::TTDashResult<()>;
+    fn send_data(&mut self, data: u8) -> result::TTDashResult<()>;
+
+    fn send_all_data(&mut self, data: &[u8]) -> result::TTDashResult<()> {
+        for byte in data {
+            self.send_data(*byte)?;
+        }
+        return Ok(());
+    }
+
+    // Blocks until the busy pin reads `idle`; controllers disagree on which
+    // level that is. Some only update the pin after being sent `poll`. Gives
+    // up with PanelNotResponding after BUSY_TIMEOUT.
+    fn wait_until(&mut self, idle: Level, poll: Option<u8>) -> result::TTDashResult<()>;
+}
+
+// Well past the slowest full refresh, which can take most of a minute on a
+// cold 7.5" panel.
+const BUSY_TIMEOUT : std::time::Duration = std::time::Duration::from_secs(60);
+
+// The pins and SPI bus the panel's HAT is wired to.
+pub struct SpiEpd {
+    spi: Spi,
+    dc_pin: OutputPin,
+    busy_pin: InputPin,
+    rst_pin: OutputPin,
+    // The most spidev will take in one write.
+    max_transfer: usize,
+}
+
+const SPIDEV_BUFSIZ : &str = "/sys/module/spidev/parameters/bufsiz";
+
+// spidev's buffer size, 4096 unless it was changed with spidev.bufsiz=.
+fn max_transfer(bufsiz: Option<&str>) -> usize {
+    return bufsiz
+        .and_then(|s| s.trim().parse::<usize>().ok())
+        .filter(|size| *size > 0)
+        .unwrap_or(4096);
+}
+
+impl SpiEpd {
+    pub fn new() -> result::TTDashResult<SpiEpd> {
+        let gpio = rppal::gpio::Gpio::new()?;
+        let dc_pin = gpio.get(DC_PIN)?.into_output();
+        let busy_pin = gpio.get(BUSY_PIN)?.into_input();
+        let rst_pin = gpio.get(RST_PIN)?.into_output();
+
+        // Don't forget to enable SPI with sudo raspi-config
+        let spi = rppal::spi::Spi::new(
+            rppal::spi::Bus::Spi0,
+            rppal::spi::SlaveSelect::Ss0,
+            2000000,
+            rppal::spi::Mode::Mode0)?;
+
+        let bufsiz = std::fs::read_to_string(SPIDEV_BUFSIZ).ok();
+
+        return Ok(SpiEpd{
+            spi: spi,
+            dc_pin: dc_pin,
+            busy_pin: busy_pin,
+            rst_pin: rst_pin,
+            max_transfer: max_transfer(bufsiz.as_ref().map(String::as_ref)),
+        });
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> result::TTDashResult<()> {
+        let written = self.spi.write(bytes)?;
+        if written != bytes.len() {
+            return Err(result::make_error(&format!("Short SPI write: {} of {} bytes", written, bytes.len())));
+        }
+        return Ok(());
+    }
+}
+
+impl Epd for SpiEpd {
+    fn reset(&mut self) -> result::TTDashResult<()> {
+        self.rst_pin.set_low();
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        self.rst_pin.set_high();
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        return Ok(());
+    }
+
+    fn send_command(&mut self, command: u8) -> result::TTDashResult<()> {
+        self.dc_pin.set_low();
+        return self.write(&[command]);
+    }
+
+    fn send_data(&mut self, data: u8) -> result::TTDashResult<()> {
+        return self.send_all_data(&[data]);
+    }
+
+    // A frame is tens of kilobytes, so write it in as few transfers as spidev
+    // allows rather than a byte at a time.
+    fn send_all_data(&mut self, data: &[u8]) -> result::TTDashResult<()> {
+        self.dc_pin.set_high();
+        for chunk in data.chunks(self.max_transfer) {
+            self.write(chunk)?;
+        }
+        return Ok(());
+    }
+
+    fn wait_until(&mut self, idle: Level, poll: Option<u8>) -> result::TTDashResult<()> {
+        let start = std::time::Instant::now();
+        loop {
+            if let Some(command) = poll {
+                self.send_command(command)?;
+            }
+            if self.busy_pin.read() == idle {
+                return Ok(());
+            }
+            if start.elapsed() > BUSY_TIMEOUT {
+                return Err(result::TTDashError::PanelNotResponding(start.elapsed()));
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+    }
+}
+
+// Somewhere to show the dashboard.
+pub trait Screen {
+    // A partial refresh only updates what changed since the last image, if
+    // the screen can.
+    fn show(&mut self, image: &image::RgbImage, mode: RefreshMode) -> result::TTDashResult<()>;
+
+    // Whether redrawing is cheap enough to do whenever anything on screen may
+    // have changed.
+    fn supports_partial_refresh(&self) -> bool {
+        return false;
+    }
+
+    // Nothing will be shown for a while.
+    fn sleep(&mut self) -> result::TTDashResult<()> {
+        return Ok(());
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Backend {
+    Spi,
+    Png(String),
+    Gif(String),
+}
+
+impl std::str::FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Backend, String> {
+        if s == "spi" {
+            return Ok(Backend::Spi);
+        }
+        return match s.split_once(':') {
+            Some(("png", path)) if !path.is_empty() => Ok(Backend::Png(path.to_string())),
+            Some(("gif", path)) if !path.is_empty() => Ok(Backend::Gif(path.to_string())),
+            _ => Err(format!("Unknown display backend '{}', expected spi, png:FILE or gif:FILE", s)),
+        };
+    }
+}
+
+// Images sent to the screen are first reduced to the panel's palette and
+// accent, so the image sinks show what the panel would.
+pub fn open_screen(backend: &Backend, panel: Box<dyn Panel>, dither: dither::Dither) -> result::TTDashResult<Box<dyn Screen>> {
+    let palette = panel.palette();
+    let accent = panel.accent();
+    let screen: Box<dyn Screen> = match backend {
+        Backend::Spi => Box::new(Display::new(panel, SpiEpd::new()?)),
+        Backend::Png(path) => Box::new(PngSink{path: path.clone()}),
+        Backend::Gif(path) => Box::new(GifSink::new(path)?),
+    };
+    return Ok(Box::new(Quantized{screen: screen, palette: palette, accent: accent, dither: dither}));
+}
+
+// Reduces each image to `palette` and `accent` before passing it on to
+// `screen`.
+pub struct Quantized {
+    screen: Box<dyn Screen>,
+    palette: &'static [u8],
+    accent: Option<image::Rgb<u8>>,
+    dither: dither::Dither,
+}
+
+impl Screen for Quantized {
+    fn show(&mut self, image: &image::RgbImage, mode: RefreshMode) -> result::TTDashResult<()> {
+        return self.screen.show(&dither::quantize_rgb(image, self.palette, self.accent, self.dither), mode);
+    }
+
+    fn supports_partial_refresh(&self) -> bool {
+        return self.screen.supports_partial_refresh();
+    }
+
+    fn sleep(&mut self) -> result::TTDashResult<()> {
+        return self.screen.sleep();
+    }
+}
+
+// Writes each frame to a PNG, for running without a panel.
+pub struct PngSink {
+    path: String,
+}
+
+impl Screen for PngSink {
+    fn show(&mut self, image: &image::RgbImage, _mode: RefreshMode) -> result::TTDashResult<()> {
+        image.save_with_format(&self.path, image::ImageFormat::Png)?;
+        return Ok(());
+    }
+}
+
+// Appends each frame to an animated GIF, a second apiece, to watch how the
+// screen changes over a run.
+pub struct GifSink {
+    encoder: image::codecs::gif::GifEncoder<std::fs::File>,
+}
+
+impl GifSink {
+    pub fn new(path: &str) -> result::TTDashResult<GifSink> {
+        let mut encoder = image::codecs::gif::GifEncoder::new(std::fs::File::create(path)?);
+        encoder.set_repeat(image::codecs::gif::Repeat::Infinite)?;
+        return Ok(GifSink{encoder: encoder});
+    }
+}
+
+impl Screen for GifSink {
+    fn show(&mut self, image: &image::RgbImage, _mode: RefreshMode) -> result::TTDashResult<()> {
+        let rgba = image::DynamicImage::ImageRgb8(image.clone()).into_rgba8();
+        self.encoder.encode_frame(image::Frame::from_parts(
+            rgba, 0, 0, image::Delay::from_numer_denom_ms(1000, 1)))?;
+        return Ok(());
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RefreshMode {
+    Full,
+    Partial,
+}
+
+// What the controller's up to, which decides how much waking it up for the
+// next refresh takes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PanelState {
+    // Not set up since starting, or since something went wrong: needs a
+    // reset and init.
+    Uninitialized,
+    // Set up for refreshes in this mode and powered on.
+    Awake(RefreshMode),
+    // As set up by Awake, but powered off between refreshes.
+    PoweredOff(RefreshMode),
+    // In deep sleep, which only a reset and init wake it from.
+    Asleep,
+}
+
+// The panel and what's on it. Partial refreshes only update what changed,
+// when the panel supports them; otherwise it gets a full refresh, which also
+// clears ghosting. The panel is powered off after every refresh.
+pub struct Display<E: Epd> {
+    panel: Box<dyn Panel>,
+    epd: E,
+    state: PanelState,
+    last_frame: Option<image::RgbImage>,
+    // Consecutive failed refreshes.
+    failures: u32,
+}
+
+// Failed refreshes in a row before resetting the panel.
+const MAX_FAILURES : u32 = 3;
+
+impl<E: Epd> Display<E> {
+    pub fn new(panel: Box<dyn Panel>, epd: E) -> Display<E> {
+        return Display{
+            panel: panel,
+            epd: epd,
+            state: PanelState::Uninitialized,
+            last_frame: None,
+            failures: 0,
+        };
+    }
+
+    // Gets the panel ready for a refresh in `mode`, powering it back on if
+    // that's all it takes.
+    fn wake(&mut self, mode: RefreshMode) -> result::TTDashResult<()> {
+        match self.state {
+            PanelState::Awake(awake) if awake == mode => {},
+            PanelState::PoweredOff(powered_off) if powered_off == mode => self.panel.power_on(&mut self.epd)?,
+            _ => match mode {
+                RefreshMode::Full => self.panel.init(&mut self.epd)?,
+                RefreshMode::Partial => self.panel.init_partial(&mut self.epd)?,
+            },
+        }
+        self.state = PanelState::Awake(mode);
+        return Ok(());
+    }
+
+    fn power_off(&mut self) -> result::TTDashResult<()> {
+        if let PanelState::Awake(mode) = self.state {
+            self.panel.power_off(&mut self.epd)?;
+            self.state = PanelState::PoweredOff(mode);
+        }
+        return Ok(());
+    }
+
+    fn refresh(&mut self, image: &image::RgbImage, mode: RefreshMode) -> result::TTDashResult<()> {
+        let panel = self.panel.as_ref();
+        match self.last_frame {
+            Some(ref last_frame) if panel.supports_partial_refresh() && mode == RefreshMode::Partial => {
+                let rects = changed_windows(last_frame, image);
+                if rects.is_empty() {
+                    debug!("Nothing changed on screen, not refreshing.");
+                    return Ok(());
+                }
+                info!("Partial refresh of {:?}", rects);
+
+                let crop = |imgbuf: &image::RgbImage, rect: &layout::Rect| {
+                    return panel.pack(&image::imageops::crop_imm(
+                        imgbuf, rect.x as u32, rect.y as u32, rect.width as u32, rect.height as u32).to_image());
+                };
+                let windows: Vec<Window> = rects.iter().map(|rect| Window{
+                    rect: *rect,
+                    old: crop(last_frame, rect),
+                    new: crop(image, rect),
+                }).collect();
+
+                self.wake(RefreshMode::Partial)?;
+                self.panel.refresh_partial(&mut self.epd, &windows)?;
+            },
+            _ => {
+                let data = panel.pack(image);
+
+                self.wake(RefreshMode::Full)?;
+                self.panel.refresh(&mut self.epd, &data)?;
+            },
+        }
+
+        self.last_frame = Some(image.clone());
+        return self.power_off();
+    }
+}
+
+impl<E: Epd> Screen for Display<E> {
+    fn supports_partial_refresh(&self) -> bool {
+        return self.panel.supports_partial_refresh();
+    }
+
+    // After a failure there's no knowing what's on the panel, so the next
+    // refresh is a full one, and after a few in a row the panel is reset.
+    fn show(&mut self, image: &image::RgbImage, mode: RefreshMode) -> result::TTDashResult<()> {
+        let panel = self.panel.as_ref();
+        if image.width() != panel.width() || image.height() != panel.height() {
+            return Err(result::make_error(&format!(
+                "{}x{} image doesn't fit the {} panel ({}x{})",
+                image.width(), image.height(), panel.name(), panel.width(), panel.height())));
+        }
+
+        let err = match self.refresh(image, mode) {
+            Ok(_) => {
+                self.failures = 0;
+                return Ok(());
+            },
+            Err(err) => err,
+        };
+
+        self.failures += 1;
+        self.last_frame = None;
+        self.state = PanelState::Uninitialized;
+        if self.failures >= MAX_FAILURES {
+            warn!("{} failed refreshes in a row, resetting the panel.", self.failures);
+            match self.panel.init(&mut self.epd) {
+                Ok(_) => {
+                    self.failures = 0;
+                    self.state = PanelState::Awake(RefreshMode::Full);
+                },
+                Err(reset_err) => error!("Error resetting the panel: {}", reset_err),
+            }
+        }
+        return Err(err);
+    }
+
+    // Waking up takes a reset, after which the controller's RAM can't be
+    // trusted for a partial refresh.
+    fn sleep(&mut self) -> result::TTDashResult<()> {
+        self.last_frame = None;
+        match self.state {
+            PanelState::Uninitialized | PanelState::Asleep => return Ok(()),
+            _ => {},
+        }
+        self.power_off()?;
+        self.panel.sleep(&mut self.epd)?;
+        self.state = PanelState::Asleep;
+        return Ok(());
+    }
+}
+
+// Bands of rows that differ between two frames, each as wide as the changes
+// within it. Bands closer than a few rows are merged, since every window
+// costs a refresh cycle, and edges are rounded out to whole bytes of a
+// one-bit-per-pixel framebuffer.
+pub fn changed_windows(old: &image::RgbImage, new: &image::RgbImage) -> Vec<layout::Rect> {
+    let merge_rows = 8;
+    let align = 8;
+
+    let mut bands: Vec<(u32, u32, u32, u32)> = vec![];  // (left, right, top, bottom), inclusive
+    for y in 0..new.height() {
+        let changed: Vec<u32> = (0..new.width())
+            .filter(|x| old.get_pixel(*x, y) != new.get_pixel(*x, y))
+            .collect();
+        let (left, right) = match (changed.first(), changed.last()) {
+            (Some(left), Some(right)) => (*left, *right),
+            _ => continue,
+        };
+
+        match bands.last_mut() {
+            Some(band) if y - band.3 <= merge_rows => {
+                *band = (std::cmp::min(band.0, left), std::cmp::max(band.1, right), band.2, y);
+            },
+            _ => bands.push((left, right, y, y)),
+        }
+    }
+
+    return bands.iter().map(|(left, right, top, bottom)| {
+        let left = left / align * align;
+        let right = std::cmp::min(new.width(), (right / align + 1) * align);
+        return layout::Rect::new(left as i32, *top as i32, (right - left) as i32, (bottom - top + 1) as i32);
+    }).collect();
+}
+
+// One bit per pixel, most significant bit first, each row padded to a whole
+// byte. `black` is the bit value the controller wants for a black pixel.
+fn pack_1bpp(imgbuf: &image::RgbImage, black: bool) -> Vec<u8> {
+    return pack_plane(imgbuf, is_dark, black);
+}
+
+// Like `pack_1bpp`, for whichever pixels `ink` picks, which get `ink_bit`.
+fn pack_plane<F: Fn(&image::Rgb<u8>) -> bool>(imgbuf: &image::RgbImage, ink: F, ink_bit: bool) -> Vec<u8> {
+    let row_bytes = (imgbuf.width() as usize + 7) / 8;
+    let mut data = vec![if ink_bit { 0x00 } else { 0xFF }; row_bytes * imgbuf.height() as usize];
+
+    for (x, y, pixel) in imgbuf.enumerate_pixels() {
+        if !ink(pixel) {
+            continue;
+        }
+        data[y as usize * row_bytes + x as usize / 8] ^= 0x80 >> (x % 8);
+    }
+    return data;
+}
+
+// The black plane then the accent plane; accent pixels aren't also black.
+fn pack_three_color(imgbuf: &image::RgbImage, accent: image::Rgb<u8>, black: bool, accent_bit: bool) -> Vec<u8> {
+    let mut data = pack_plane(imgbuf, |p| *p != accent && is_dark(p), black);
+    data.extend(pack_plane(imgbuf, |p| *p == accent, accent_bit));
+    return data;
+}
+
+// Deep sleep for the UC8159, UC8179 and IL0398.
+fn deep_sleep(epd: &mut dyn Epd) -> result::TTDashResult<()> {
+    epd.send_command(DEEP_SLEEP)?;
+    epd.send_data(0xA5)?;      // check code
     return Ok(());
 }
 
-fn send_command(dc_pin: &mut OutputPin, spi: &mut Spi, command: u8) {
-    dc_pin.set_low();
-    let v = vec![command];
-    let bytes = spi.write(&v).expect("spi.write");
-    assert_eq!(bytes, 1);
+fn is_dark(pixel: &image::Rgb<u8>) -> bool {
+    return luma(pixel) < 128;
 }
 
-fn send_data(dc_pin: &mut OutputPin, spi: &mut Spi, data: u8) {
-    dc_pin.set_high();
-    let v = vec![data];
-    let bytes = spi.write(&v).expect("spi.write");
-    assert_eq!(bytes, 1);
+fn luma(pixel: &image::Rgb<u8>) -> u8 {
+    use image::Pixel;
+    return pixel.to_luma()[0];
 }
 
-fn wait_until_idle(busy_pin: &InputPin) {
-    loop {
-        if busy_pin.read() == Level::Low {
-            return;
+// 7.5" v1, 640x384, with two levels of gray. Two pixels per byte, four bits
+// each, of which the panel only uses the low two.
+pub struct Epd7in5 {}
+
+impl Panel for Epd7in5 {
+    fn name(&self) -> &'static str {
+        return "7in5";
+    }
+
+    fn width(&self) -> u32 {
+        return 640;
+    }
+
+    fn height(&self) -> u32 {
+        return 384;
+    }
+
+    fn palette(&self) -> &'static [u8] {
+        return &FOUR_GRAYS;
+    }
+
+    fn init(&self, epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        epd.reset()?;
+
+        epd.send_command(POWER_SETTING)?;
+        epd.send_data(0x37)?;
+        epd.send_data(0x00)?;
+
+        epd.send_command(PANEL_SETTING)?;
+        epd.send_data(0xCF)?;
+        epd.send_data(0x08)?;
+
+        epd.send_command(BOOSTER_SOFT_START)?;
+        epd.send_data(0xc7)?;
+        epd.send_data(0xcc)?;
+        epd.send_data(0x28)?;
+
+        epd.send_command(POWER_ON)?;
+        epd.wait_until(Level::Low, None)?;
+
+        epd.send_command(PLL_CONTROL)?;
+        epd.send_data(0x3c)?;
+
+        epd.send_command(TEMPERATURE_CALIBRATION)?;
+        epd.send_data(0x00)?;
+
+        epd.send_command(VCOM_AND_DATA_INTERVAL_SETTING)?;
+        epd.send_data(0x77)?;
+
+        epd.send_command(TCON_SETTING)?;
+        epd.send_data(0x22)?;
+
+        epd.send_command(TCON_RESOLUTION)?;
+        epd.send_data(0x02)?;     //source 640
+        epd.send_data(0x80)?;
+        epd.send_data(0x01)?;     //gate 384
+        epd.send_data(0x80)?;
+
+        epd.send_command(VCM_DC_SETTING)?;
+        epd.send_data(0x1E)?;      //decide by LUT file;
+
+        epd.send_command(0xe5)?;           //FLASH MODE;
+        epd.send_data(0x03)?;
+        return Ok(());
+    }
+
+    fn pack(&self, imgbuf: &image::RgbImage) -> Vec<u8> {
+        let level = |color: u8| -> u8 {
+            if color < 64 {
+                return 0x0;
+            } else if color <= 128 {
+                return 0x1;
+            } else if color <= 192 {
+                return 0x2;
+            }
+            return 0x3;
+        };
+
+        return imgbuf.rows()
+            .flat_map(|row| row.collect::<Vec<_>>()
+                      .chunks(2)
+                      .map(|pair| (level(luma(pair[0])) << 4) | pair.get(1).map(|p| level(luma(p))).unwrap_or(0))
+                      .collect::<Vec<u8>>())
+            .collect();
+    }
+
+    fn refresh(&self, epd: &mut dyn Epd, data: &[u8]) -> result::TTDashResult<()> {
+        epd.send_command(DATA_START_TRANSMISSION)?;
+        epd.send_all_data(data)?;
+
+        epd.send_command(DISPLAY_REFRESH)?;
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        epd.wait_until(Level::Low, None)?;
+        return Ok(());
+    }
+
+    fn power_on(&self, epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        epd.send_command(POWER_ON)?;
+        return epd.wait_until(Level::Low, None);
+    }
+
+    fn power_off(&self, epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        epd.send_command(POWER_OFF)?;
+        return epd.wait_until(Level::Low, None);
+    }
+
+    fn sleep(&self, epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        return deep_sleep(epd);
+    }
+}
+
+// 7.5" v2, 800x480. One bit per pixel, 1 is black. The busy pin is low
+// while busy, and only updates after a status read.
+pub struct Epd7in5V2 {}
+
+impl Panel for Epd7in5V2 {
+    fn name(&self) -> &'static str {
+        return "7in5_v2";
+    }
+
+    fn width(&self) -> u32 {
+        return 800;
+    }
+
+    fn height(&self) -> u32 {
+        return 480;
+    }
+
+    fn init(&self, epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        epd.reset()?;
+
+        epd.send_command(BOOSTER_SOFT_START)?;
+        epd.send_all_data(&[0x17, 0x17, 0x28, 0x17])?;
+
+        epd.send_command(POWER_SETTING)?;
+        epd.send_all_data(&[0x07, 0x07, 0x3f, 0x3f])?;
+
+        epd.send_command(POWER_ON)?;
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        epd.wait_until(Level::High, Some(GET_STATUS))?;
+
+        epd.send_command(PANEL_SETTING)?;
+        epd.send_data(0x1F)?;      // KW mode, LUT from OTP
+
+        epd.send_command(TCON_RESOLUTION)?;
+        epd.send_all_data(&[0x03, 0x20, 0x01, 0xE0])?;     // source 800, gate 480
+
+        epd.send_command(DUAL_SPI)?;
+        epd.send_data(0x00)?;
+
+        epd.send_command(VCOM_AND_DATA_INTERVAL_SETTING)?;
+        epd.send_all_data(&[0x10, 0x07])?;
+
+        epd.send_command(TCON_SETTING)?;
+        epd.send_data(0x22)?;
+        return Ok(());
+    }
+
+    fn pack(&self, imgbuf: &image::RgbImage) -> Vec<u8> {
+        return pack_1bpp(imgbuf, true);
+    }
+
+    fn refresh(&self, epd: &mut dyn Epd, data: &[u8]) -> result::TTDashResult<()> {
+        epd.send_command(DATA_START_TRANSMISSION_2)?;
+        epd.send_all_data(data)?;
+
+        epd.send_command(DISPLAY_REFRESH)?;
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        epd.wait_until(Level::High, Some(GET_STATUS))?;
+        return Ok(());
+    }
+
+    fn power_on(&self, epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        epd.send_command(POWER_ON)?;
+        return epd.wait_until(Level::High, Some(GET_STATUS));
+    }
+
+    fn power_off(&self, epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        epd.send_command(POWER_OFF)?;
+        return epd.wait_until(Level::High, Some(GET_STATUS));
+    }
+
+    fn sleep(&self, epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        epd.send_command(VCOM_AND_DATA_INTERVAL_SETTING)?;
+        epd.send_data(0xF7)?;      // floating border while asleep
+        return deep_sleep(epd);
+    }
+
+    fn supports_partial_refresh(&self) -> bool {
+        return true;
+    }
+
+    fn init_partial(&self, epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        self.init(epd)?;
+
+        epd.send_command(CASCADE_SETTING)?;
+        epd.send_data(0x02)?;      // use the forced temperature
+        epd.send_command(FORCE_TEMPERATURE)?;
+        epd.send_data(0x6E)?;      // which selects the fast waveform
+
+        epd.send_command(VCOM_AND_DATA_INTERVAL_SETTING)?;
+        epd.send_all_data(&[0xA9, 0x07])?;     // floating border
+        return Ok(());
+    }
+
+    // The controller compares old and new data to decide which pixels to
+    // drive, one window per refresh.
+    fn refresh_partial(&self, epd: &mut dyn Epd, windows: &[Window]) -> result::TTDashResult<()> {
+        epd.send_command(PARTIAL_IN)?;
+        for window in windows {
+            let rect = &window.rect;
+            let (x_end, y_end) = (rect.right() - 1, rect.bottom() - 1);
+            epd.send_command(PARTIAL_WINDOW)?;
+            epd.send_all_data(&[
+                (rect.x >> 8) as u8, rect.x as u8, (x_end >> 8) as u8, x_end as u8,
+                (rect.y >> 8) as u8, rect.y as u8, (y_end >> 8) as u8, y_end as u8,
+                0x01])?;   // only scan the gates inside the window
+
+            epd.send_command(DATA_START_TRANSMISSION)?;
+            epd.send_all_data(&window.old)?;
+            epd.send_command(DATA_START_TRANSMISSION_2)?;
+            epd.send_all_data(&window.new)?;
+
+            epd.send_command(DISPLAY_REFRESH)?;
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            epd.wait_until(Level::High, Some(GET_STATUS))?;
         }
+        return Ok(());
+    }
+}
+
+// 4.2", 400x300. One bit per pixel, 1 is white.
+pub struct Epd4in2 {}
+
+impl Panel for Epd4in2 {
+    fn name(&self) -> &'static str {
+        return "4in2";
+    }
+
+    fn width(&self) -> u32 {
+        return 400;
+    }
+
+    fn height(&self) -> u32 {
+        return 300;
+    }
+
+    fn init(&self, epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        epd.reset()?;
+
+        epd.send_command(POWER_SETTING)?;
+        epd.send_all_data(&[0x03, 0x00, 0x2b, 0x2b])?;
+
+        epd.send_command(BOOSTER_SOFT_START)?;
+        epd.send_all_data(&[0x17, 0x17, 0x17])?;
+
+        epd.send_command(POWER_ON)?;
+        epd.wait_until(Level::High, None)?;
+
+        epd.send_command(PANEL_SETTING)?;
+        epd.send_data(0x1F)?;      // KW mode, LUT from OTP
+
+        epd.send_command(PLL_CONTROL)?;
+        epd.send_data(0x3c)?;
+
+        epd.send_command(TCON_RESOLUTION)?;
+        epd.send_all_data(&[0x01, 0x90, 0x01, 0x2C])?;     // source 400, gate 300
+
+        epd.send_command(VCM_DC_SETTING)?;
+        epd.send_data(0x28)?;
+
+        epd.send_command(VCOM_AND_DATA_INTERVAL_SETTING)?;
+        epd.send_data(0x97)?;
+        return Ok(());
+    }
+
+    fn pack(&self, imgbuf: &image::RgbImage) -> Vec<u8> {
+        return pack_1bpp(imgbuf, false);
+    }
+
+    fn refresh(&self, epd: &mut dyn Epd, data: &[u8]) -> result::TTDashResult<()> {
+        // The old frame; all white so every pixel gets driven.
+        epd.send_command(DATA_START_TRANSMISSION)?;
+        epd.send_all_data(&vec![0xFF; data.len()])?;
+
+        epd.send_command(DATA_START_TRANSMISSION_2)?;
+        epd.send_all_data(data)?;
+
+        epd.send_command(DISPLAY_REFRESH)?;
         std::thread::sleep(std::time::Duration::from_millis(100));
+        epd.wait_until(Level::High, None)?;
+        return Ok(());
+    }
+
+    fn power_on(&self, epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        epd.send_command(POWER_ON)?;
+        return epd.wait_until(Level::High, None);
+    }
+
+    fn power_off(&self, epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        epd.send_command(POWER_OFF)?;
+        return epd.wait_until(Level::High, None);
+    }
+
+    fn sleep(&self, epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        epd.send_command(VCOM_AND_DATA_INTERVAL_SETTING)?;
+        epd.send_data(0x17)?;      // floating border while asleep
+        return deep_sleep(epd);
     }
 }
 
-fn init_display(gpio: &mut Gpio, spi: &mut Spi, dc_pin: &mut OutputPin, busy_pin: &InputPin) {
-    let mut rst_pin = gpio.get(RST_PIN).expect("get rst pin").into_output();
+// 2.9" v2, 128x296 (portrait). One bit per pixel, 1 is white.
+pub struct Epd2in9V2 {}
 
-    rst_pin.set_low();
-    std::thread::sleep(std::time::Duration::from_millis(200));
-    rst_pin.set_high();
-    std::thread::sleep(std::time::Duration::from_millis(200));
+impl Panel for Epd2in9V2 {
+    fn name(&self) -> &'static str {
+        return "2in9_v2";
+    }
 
-    send_command(dc_pin, spi, POWER_SETTING);
-    send_data(dc_pin, spi, 0x37);
-    send_data(dc_pin, spi, 0x00);
+    fn width(&self) -> u32 {
+        return 128;
+    }
 
-    send_command(dc_pin, spi, PANEL_SETTING);
-    send_data(dc_pin, spi, 0xCF);
-    send_data(dc_pin, spi, 0x08);
+    fn height(&self) -> u32 {
+        return 296;
+    }
 
-    send_command(dc_pin, spi, BOOSTER_SOFT_START);
-    send_data(dc_pin, spi, 0xc7);
-    send_data(dc_pin, spi, 0xcc);
-    send_data(dc_pin, spi, 0x28);
+    fn init(&self, epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        epd.reset()?;
+        epd.wait_until(Level::Low, None)?;
 
-    send_command(dc_pin, spi, POWER_ON);
-    wait_until_idle(&busy_pin);
+        epd.send_command(SW_RESET)?;
+        epd.wait_until(Level::Low, None)?;
 
-    send_command(dc_pin, spi, PLL_CONTROL);
-    send_data(dc_pin, spi, 0x3c);
+        epd.send_command(DRIVER_OUTPUT_CONTROL)?;
+        epd.send_all_data(&[0x27, 0x01, 0x00])?;     // 296 gates
 
-    send_command(dc_pin, spi, TEMPERATURE_CALIBRATION);
-    send_data(dc_pin, spi, 0x00);
+        epd.send_command(DATA_ENTRY_MODE)?;
+        epd.send_data(0x03)?;      // x then y increasing
 
-    send_command(dc_pin, spi, VCOM_AND_DATA_INTERVAL_SETTING);
-    send_data(dc_pin, spi, 0x77);
+        epd.send_command(SET_RAM_X_ADDRESS_START_END)?;
+        epd.send_all_data(&[0x00, 0x0F])?;     // bytes 0..=15
 
-    send_command(dc_pin, spi, TCON_SETTING);
-    send_data(dc_pin, spi, 0x22);
+        epd.send_command(SET_RAM_Y_ADDRESS_START_END)?;
+        epd.send_all_data(&[0x00, 0x00, 0x27, 0x01])?;     // rows 0..=295
 
-    send_command(dc_pin, spi, TCON_RESOLUTION);
-    send_data(dc_pin, spi, 0x02);     //source 640
-    send_data(dc_pin, spi, 0x80);
-    send_data(dc_pin, spi, 0x01);     //gate 384
-    send_data(dc_pin, spi, 0x80);
+        epd.send_command(BORDER_WAVEFORM_CONTROL)?;
+        epd.send_data(0x05)?;
 
-    send_command(dc_pin, spi, VCM_DC_SETTING);
-    send_data(dc_pin, spi, 0x1E);      //decide by LUT file;
+        epd.send_command(DISPLAY_UPDATE_CONTROL_1)?;
+        epd.send_all_data(&[0x00, 0x80])?;
 
-    send_command(dc_pin, spi, 0xe5);           //FLASH MODE;
-    send_data(dc_pin, spi, 0x03);
+        epd.send_command(TEMPERATURE_SENSOR_CONTROL)?;
+        epd.send_data(0x80)?;      // internal sensor
 
-    // Draw all black?
-    send_command(dc_pin, spi, DATA_START_TRANSMISSION);
+        epd.send_command(SET_RAM_X_ADDRESS_COUNTER)?;
+        epd.send_data(0x00)?;
+        epd.send_command(SET_RAM_Y_ADDRESS_COUNTER)?;
+        epd.send_all_data(&[0x00, 0x00])?;
+        epd.wait_until(Level::Low, None)?;
+        return Ok(());
+    }
+
+    fn pack(&self, imgbuf: &image::RgbImage) -> Vec<u8> {
+        return pack_1bpp(imgbuf, false);
+    }
+
+    fn refresh(&self, epd: &mut dyn Epd, data: &[u8]) -> result::TTDashResult<()> {
+        epd.send_command(WRITE_RAM_BW)?;
+        epd.send_all_data(data)?;
+        // The previous frame, for partial refreshes to compare against.
+        self.set_ram_window(epd, &layout::Rect::new(0, 0, self.width() as i32, self.height() as i32))?;
+        epd.send_command(WRITE_RAM_RED)?;
+        epd.send_all_data(data)?;
+
+        epd.send_command(DISPLAY_UPDATE_CONTROL_2)?;
+        epd.send_data(0xF7)?;      // load temperature and the OTP LUT, then display
+        epd.send_command(MASTER_ACTIVATION)?;
+        epd.wait_until(Level::Low, None)?;
+        return Ok(());
+    }
+
+    fn sleep(&self, epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        epd.send_command(DEEP_SLEEP_MODE)?;
+        epd.send_data(0x01)?;      // mode 1 keeps RAM, but waking still needs a reset
+        return Ok(());
+    }
+
+    fn supports_partial_refresh(&self) -> bool {
+        return true;
+    }
+
+    fn init_partial(&self, epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        epd.reset()?;
+        epd.wait_until(Level::Low, None)?;
+
+        epd.send_command(BORDER_WAVEFORM_CONTROL)?;
+        epd.send_data(0x80)?;      // leave the border alone
+
+        epd.send_command(DATA_ENTRY_MODE)?;
+        epd.send_data(0x03)?;
+        return Ok(());
+    }
+
+    // Writes every window into RAM, then updates only the pixels that differ
+    // between the old and new frames in one go.
+    fn refresh_partial(&self, epd: &mut dyn Epd, windows: &[Window]) -> result::TTDashResult<()> {
+        for window in windows {
+            self.set_ram_window(epd, &window.rect)?;
+            epd.send_command(WRITE_RAM_RED)?;
+            epd.send_all_data(&window.old)?;
+
+            self.set_ram_window(epd, &window.rect)?;
+            epd.send_command(WRITE_RAM_BW)?;
+            epd.send_all_data(&window.new)?;
+        }
+
+        epd.send_command(DISPLAY_UPDATE_CONTROL_2)?;
+        epd.send_data(0xFF)?;      // as for a full refresh, but with the partial (mode 2) waveform
+        epd.send_command(MASTER_ACTIVATION)?;
+        epd.wait_until(Level::Low, None)?;
+        return Ok(());
+    }
 }
 
-fn display_image(dc_pin: &mut OutputPin, busy_pin: &InputPin, spi: &mut Spi, imgbuf: &image::ImageBuffer<image::Luma<u8>, Vec<u8>>) {
-    let mut pixel_in_progress: u8 = 0;
+impl Epd2in9V2 {
+    // Points the RAM address window and counters at `rect`, whose x edges are
+    // on byte boundaries.
+    fn set_ram_window(&self, epd: &mut dyn Epd, rect: &layout::Rect) -> result::TTDashResult<()> {
+        let (x_end, y_end) = (rect.right() - 1, rect.bottom() - 1);
+        epd.send_command(SET_RAM_X_ADDRESS_START_END)?;
+        epd.send_all_data(&[(rect.x / 8) as u8, (x_end / 8) as u8])?;
+        epd.send_command(SET_RAM_Y_ADDRESS_START_END)?;
+        epd.send_all_data(&[rect.y as u8, (rect.y >> 8) as u8, y_end as u8, (y_end >> 8) as u8])?;
 
-    use image::Pixel;
+        epd.send_command(SET_RAM_X_ADDRESS_COUNTER)?;
+        epd.send_data((rect.x / 8) as u8)?;
+        epd.send_command(SET_RAM_Y_ADDRESS_COUNTER)?;
+        epd.send_all_data(&[rect.y as u8, (rect.y >> 8) as u8])?;
+        return Ok(());
+    }
+}
 
-    for y in 0..imgbuf.height() {
-        for x in 0..imgbuf.width() {
-            let color = imgbuf.get_pixel(x as u32, y as u32).to_luma()[0];
-            if x % 2 == 0 {
-                if color < 64 {
-                    pixel_in_progress = 0x00;
-                } else if color <= 128 {
-                    pixel_in_progress = 0x10;
-                } else if color <= 192 {
-                    pixel_in_progress = 0x20;
-                } else {
-                    pixel_in_progress = 0x30;
-                }
-            } else {
-                if color < 64 {
-                    pixel_in_progress |= 0x00;
-                } else if color <= 128 {
-                    pixel_in_progress |= 0x01;
-                } else if color <= 192 {
-                    pixel_in_progress |= 0x02;
-                } else {
-                    pixel_in_progress |= 0x03;
-                }
+// 7.5" B v2, 800x480, black, white and red. The black plane is one bit per
+// pixel with 1 for white, the red plane 1 for red. Busy as for the 7.5" v2.
+pub struct Epd7in5BV2 {}
+
+impl Panel for Epd7in5BV2 {
+    fn name(&self) -> &'static str {
+        return "7in5b_v2";
+    }
+
+    fn width(&self) -> u32 {
+        return 800;
+    }
+
+    fn height(&self) -> u32 {
+        return 480;
+    }
+
+    fn accent(&self) -> Option<image::Rgb<u8>> {
+        return Some(RED);
+    }
+
+    fn init(&self, epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        epd.reset()?;
+
+        epd.send_command(POWER_SETTING)?;
+        epd.send_all_data(&[0x07, 0x07, 0x3f, 0x3f])?;
+
+        epd.send_command(BOOSTER_SOFT_START)?;
+        epd.send_all_data(&[0x17, 0x17, 0x28, 0x17])?;
+
+        epd.send_command(POWER_ON)?;
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        epd.wait_until(Level::High, Some(GET_STATUS))?;
+
+        epd.send_command(PANEL_SETTING)?;
+        epd.send_data(0x0F)?;      // KWR mode, LUT from OTP
+
+        epd.send_command(TCON_RESOLUTION)?;
+        epd.send_all_data(&[0x03, 0x20, 0x01, 0xE0])?;     // source 800, gate 480
+
+        epd.send_command(DUAL_SPI)?;
+        epd.send_data(0x00)?;
+
+        epd.send_command(VCOM_AND_DATA_INTERVAL_SETTING)?;
+        epd.send_all_data(&[0x11, 0x07])?;
+
+        epd.send_command(TCON_SETTING)?;
+        epd.send_data(0x22)?;
+
+        epd.send_command(GATE_SOURCE_START)?;
+        epd.send_all_data(&[0x00, 0x00, 0x00, 0x00])?;
+        return Ok(());
+    }
+
+    fn pack(&self, imgbuf: &image::RgbImage) -> Vec<u8> {
+        return pack_three_color(imgbuf, RED, false, true);
+    }
+
+    fn refresh(&self, epd: &mut dyn Epd, data: &[u8]) -> result::TTDashResult<()> {
+        let (black, red) = data.split_at(data.len() / 2);
+        epd.send_command(DATA_START_TRANSMISSION)?;
+        epd.send_all_data(black)?;
+        epd.send_command(DATA_START_TRANSMISSION_2)?;
+        epd.send_all_data(red)?;
+
+        epd.send_command(DISPLAY_REFRESH)?;
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        epd.wait_until(Level::High, Some(GET_STATUS))?;
+        return Ok(());
+    }
+
+    fn power_on(&self, epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        epd.send_command(POWER_ON)?;
+        return epd.wait_until(Level::High, Some(GET_STATUS));
+    }
+
+    fn power_off(&self, epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        epd.send_command(POWER_OFF)?;
+        return epd.wait_until(Level::High, Some(GET_STATUS));
+    }
+
+    fn sleep(&self, epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        return deep_sleep(epd);
+    }
+}
+
+// 4.2" B and C, 400x300, black, white and red or yellow respectively. Both
+// planes are one bit per pixel, with 0 for black or the accent.
+pub struct Epd4in2Bc {
+    name: &'static str,
+    accent: image::Rgb<u8>,
+}
 
-                send_data(dc_pin, spi, pixel_in_progress);
+impl Panel for Epd4in2Bc {
+    fn name(&self) -> &'static str {
+        return self.name;
+    }
+
+    fn width(&self) -> u32 {
+        return 400;
+    }
+
+    fn height(&self) -> u32 {
+        return 300;
+    }
+
+    fn accent(&self) -> Option<image::Rgb<u8>> {
+        return Some(self.accent);
+    }
+
+    fn init(&self, epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        epd.reset()?;
+
+        epd.send_command(BOOSTER_SOFT_START)?;
+        epd.send_all_data(&[0x17, 0x17, 0x17])?;
+
+        epd.send_command(POWER_ON)?;
+        epd.wait_until(Level::High, None)?;
+
+        epd.send_command(PANEL_SETTING)?;
+        epd.send_data(0x0F)?;      // KWR mode, LUT from OTP
+        return Ok(());
+    }
+
+    fn pack(&self, imgbuf: &image::RgbImage) -> Vec<u8> {
+        return pack_three_color(imgbuf, self.accent, false, false);
+    }
+
+    fn refresh(&self, epd: &mut dyn Epd, data: &[u8]) -> result::TTDashResult<()> {
+        let (black, accent) = data.split_at(data.len() / 2);
+        epd.send_command(DATA_START_TRANSMISSION)?;
+        epd.send_all_data(black)?;
+        epd.send_command(DATA_START_TRANSMISSION_2)?;
+        epd.send_all_data(accent)?;
+
+        epd.send_command(DISPLAY_REFRESH)?;
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        epd.wait_until(Level::High, None)?;
+        return Ok(());
+    }
+
+    fn power_on(&self, epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        epd.send_command(POWER_ON)?;
+        return epd.wait_until(Level::High, None);
+    }
+
+    fn power_off(&self, epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        epd.send_command(POWER_OFF)?;
+        return epd.wait_until(Level::High, None);
+    }
+
+    fn sleep(&self, epd: &mut dyn Epd) -> result::TTDashResult<()> {
+        return deep_sleep(epd);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Op {
+        Reset,
+        Command(u8),
+        // Consecutive data bytes are collected into one Data.
+        Data(Vec<u8>),
+        Wait(Level),
+    }
+
+    // Records everything sent instead of sending it. The panel is never busy,
+    // unless it's stuck, when it never stops being.
+    struct RecordingEpd {
+        ops: Vec<Op>,
+        stuck: bool,
+    }
+
+    impl RecordingEpd {
+        fn new() -> RecordingEpd {
+            return RecordingEpd{ops: vec![], stuck: false};
+        }
+    }
+
+    impl Epd for RecordingEpd {
+        fn reset(&mut self) -> result::TTDashResult<()> {
+            self.ops.push(Op::Reset);
+            return Ok(());
+        }
+
+        fn send_command(&mut self, command: u8) -> result::TTDashResult<()> {
+            self.ops.push(Op::Command(command));
+            return Ok(());
+        }
+
+        fn send_data(&mut self, data: u8) -> result::TTDashResult<()> {
+            if let Some(Op::Data(bytes)) = self.ops.last_mut() {
+                bytes.push(data);
+                return Ok(());
+            }
+            self.ops.push(Op::Data(vec![data]));
+            return Ok(());
+        }
+
+        fn wait_until(&mut self, idle: Level, _poll: Option<u8>) -> result::TTDashResult<()> {
+            self.ops.push(Op::Wait(idle));
+            if self.stuck {
+                return Err(result::TTDashError::PanelNotResponding(BUSY_TIMEOUT));
+            }
+            return Ok(());
+        }
+    }
+
+    fn recording_display(panel: Box<dyn Panel>) -> Display<RecordingEpd> {
+        return Display::new(panel, RecordingEpd::new());
+    }
+
+    #[test]
+    fn full_refresh_7in5_golden() {
+        let mut display = recording_display(Box::new(Epd7in5{}));
+        display.show(&test_image(640, 384), RefreshMode::Full).unwrap();
+
+        let mut frame = vec![0x33; 640 * 384 / 2];
+        frame[0] = 0x00;
+        frame[1] = 0x03;
+        assert_eq!(display.epd.ops, vec![
+            Op::Reset,
+            Op::Command(POWER_SETTING), Op::Data(vec![0x37, 0x00]),
+            Op::Command(PANEL_SETTING), Op::Data(vec![0xCF, 0x08]),
+            Op::Command(BOOSTER_SOFT_START), Op::Data(vec![0xc7, 0xcc, 0x28]),
+            Op::Command(POWER_ON), Op::Wait(Level::Low),
+            Op::Command(PLL_CONTROL), Op::Data(vec![0x3c]),
+            Op::Command(TEMPERATURE_CALIBRATION), Op::Data(vec![0x00]),
+            Op::Command(VCOM_AND_DATA_INTERVAL_SETTING), Op::Data(vec![0x77]),
+            Op::Command(TCON_SETTING), Op::Data(vec![0x22]),
+            Op::Command(TCON_RESOLUTION), Op::Data(vec![0x02, 0x80, 0x01, 0x80]),
+            Op::Command(VCM_DC_SETTING), Op::Data(vec![0x1E]),
+            Op::Command(0xe5), Op::Data(vec![0x03]),
+            Op::Command(DATA_START_TRANSMISSION), Op::Data(frame),
+            Op::Command(DISPLAY_REFRESH), Op::Wait(Level::Low),
+            Op::Command(POWER_OFF), Op::Wait(Level::Low),
+        ]);
+        assert_eq!(PanelState::PoweredOff(RefreshMode::Full), display.state);
+
+        // The v1 panel can't do partial refreshes, so every frame is a full
+        // one, but it only needs powering back on for it.
+        display.epd.ops.clear();
+        let mut changed = test_image(640, 384);
+        changed.put_pixel(10, 20, image::Rgb([0, 0, 0]));
+        display.show(&changed, RefreshMode::Partial).unwrap();
+        assert_eq!(&display.epd.ops[0..2], &[Op::Command(POWER_ON), Op::Wait(Level::Low)]);
+        assert!(!display.epd.ops.contains(&Op::Reset));
+        assert_eq!(&display.epd.ops[display.epd.ops.len() - 2..], &[Op::Command(POWER_OFF), Op::Wait(Level::Low)]);
+    }
+
+    #[test]
+    fn partial_refresh_7in5_v2_power() {
+        let mut display = recording_display(Box::new(Epd7in5V2{}));
+        let base = test_image(800, 480);
+        display.show(&base, RefreshMode::Full).unwrap();
+
+        // The first partial refresh sets the panel up for them, and the next
+        // only powers it back on.
+        for minute in 1..=2 {
+            let mut changed = base.clone();
+            changed.put_pixel(minute as u32, 20, image::Rgb([0, 0, 0]));
+            display.epd.ops.clear();
+            display.show(&changed, RefreshMode::Partial).unwrap();
+            assert_eq!(&display.epd.ops[display.epd.ops.len() - 2..], &[Op::Command(POWER_OFF), Op::Wait(Level::High)]);
+            assert_eq!(PanelState::PoweredOff(RefreshMode::Partial), display.state);
+        }
+        assert_eq!(&display.epd.ops[0..2], &[Op::Command(POWER_ON), Op::Wait(Level::High)]);
+        assert!(!display.epd.ops.contains(&Op::Reset));
+    }
+
+    #[test]
+    fn partial_refresh_2in9_golden() {
+        let mut display = recording_display(Box::new(Epd2in9V2{}));
+        let base = test_image(128, 296);
+        display.show(&base, RefreshMode::Full).unwrap();
+        assert!(display.epd.ops.contains(&Op::Command(SW_RESET)));
+
+        // Nothing changed, nothing sent.
+        display.epd.ops.clear();
+        display.show(&base, RefreshMode::Partial).unwrap();
+        assert_eq!(display.epd.ops, vec![]);
+
+        let mut changed = base.clone();
+        changed.put_pixel(10, 20, image::Rgb([0, 0, 0]));
+        display.show(&changed, RefreshMode::Partial).unwrap();
+        let set_window = vec![
+            Op::Command(SET_RAM_X_ADDRESS_START_END), Op::Data(vec![1, 1]),
+            Op::Command(SET_RAM_Y_ADDRESS_START_END), Op::Data(vec![20, 0, 20, 0]),
+            Op::Command(SET_RAM_X_ADDRESS_COUNTER), Op::Data(vec![1]),
+            Op::Command(SET_RAM_Y_ADDRESS_COUNTER), Op::Data(vec![20, 0]),
+        ];
+        let mut expected = vec![
+            Op::Reset, Op::Wait(Level::Low),
+            Op::Command(BORDER_WAVEFORM_CONTROL), Op::Data(vec![0x80]),
+            Op::Command(DATA_ENTRY_MODE), Op::Data(vec![0x03]),
+        ];
+        expected.extend(set_window.clone());
+        expected.extend(vec![Op::Command(WRITE_RAM_RED), Op::Data(vec![0xFF])]);
+        expected.extend(set_window);
+        expected.extend(vec![
+            Op::Command(WRITE_RAM_BW), Op::Data(vec![0xDF]),
+            Op::Command(DISPLAY_UPDATE_CONTROL_2), Op::Data(vec![0xFF]),
+            Op::Command(MASTER_ACTIVATION), Op::Wait(Level::Low),
+        ]);
+        assert_eq!(display.epd.ops, expected);
+
+        // A full refresh clears any ghosting, even if nothing changed.
+        display.epd.ops.clear();
+        display.show(&base, RefreshMode::Full).unwrap();
+        assert!(display.epd.ops.contains(&Op::Command(SW_RESET)));
+    }
+
+    #[test]
+    fn partial_refresh_needs_last_frame() {
+        let mut display = recording_display(Box::new(Epd2in9V2{}));
+        assert!(display.supports_partial_refresh());
+        display.show(&test_image(128, 296), RefreshMode::Partial).unwrap();
+        assert!(display.epd.ops.contains(&Op::Command(SW_RESET)));
+        assert!(display.epd.ops.contains(&Op::Data(vec![0xF7])));
+    }
+
+    #[test]
+    fn stuck_panel() {
+        let mut display = recording_display(Box::new(Epd2in9V2{}));
+        let base = test_image(128, 296);
+        display.show(&base, RefreshMode::Full).unwrap();
+
+        display.epd.stuck = true;
+        display.epd.ops.clear();
+        for minute in 1..=3 {
+            let mut changed = base.clone();
+            changed.put_pixel(minute as u32, 100, image::Rgb([0, 0, 0]));
+            match display.show(&changed, RefreshMode::Partial) {
+                Err(result::TTDashError::PanelNotResponding(_)) => {},
+                other => panic!("Expected PanelNotResponding, got {:?}", other),
             }
         }
+        // Each refresh reset the panel before getting stuck, and then the
+        // third failure tried resetting it again.
+        assert_eq!(4, display.epd.ops.iter().filter(|op| **op == Op::Reset).count());
+
+        // Once it's back, the frame is redrawn in full.
+        display.epd.stuck = false;
+        display.epd.ops.clear();
+        display.show(&base, RefreshMode::Partial).unwrap();
+        assert!(display.epd.ops.contains(&Op::Command(SW_RESET)));
+        assert_eq!(0, display.failures);
     }
 
-    send_command(dc_pin, spi, DISPLAY_REFRESH);
-    std::thread::sleep(std::time::Duration::from_millis(100));
-    wait_until_idle(&busy_pin);
+    #[test]
+    fn sleep() {
+        let mut display = recording_display(Box::new(Epd4in2{}));
+        display.show(&test_image(400, 300), RefreshMode::Full).unwrap();
+        display.epd.ops.clear();
+        display.sleep().unwrap();
+        assert_eq!(display.epd.ops, vec![
+            Op::Command(VCOM_AND_DATA_INTERVAL_SETTING), Op::Data(vec![0x17]),
+            Op::Command(DEEP_SLEEP), Op::Data(vec![0xA5]),
+        ]);
+        assert_eq!(PanelState::Asleep, display.state);
+
+        // Already asleep.
+        display.epd.ops.clear();
+        display.sleep().unwrap();
+        assert_eq!(display.epd.ops, vec![]);
+
+        // Waking up from sleep takes a full refresh, even where a partial one
+        // would otherwise do.
+        let mut display = recording_display(Box::new(Epd2in9V2{}));
+        display.show(&test_image(128, 296), RefreshMode::Full).unwrap();
+        display.sleep().unwrap();
+        assert_eq!(&display.epd.ops[display.epd.ops.len() - 2..], &[Op::Command(DEEP_SLEEP_MODE), Op::Data(vec![0x01])]);
+        display.epd.ops.clear();
+        let mut changed = test_image(128, 296);
+        changed.put_pixel(10, 20, image::Rgb([0, 0, 0]));
+        display.show(&changed, RefreshMode::Partial).unwrap();
+        assert!(display.epd.ops.contains(&Op::Command(SW_RESET)));
+    }
+
+    #[test]
+    fn wrong_size_image() {
+        let mut display = recording_display(Box::new(Epd4in2{}));
+        assert!(display.show(&test_image(640, 384), RefreshMode::Full).is_err());
+        assert_eq!(display.epd.ops, vec![]);
+    }
+
+    #[test]
+    fn backends() {
+        assert_eq!(Backend::Spi, "spi".parse().unwrap());
+        assert_eq!(Backend::Png("/tmp/ttdash.png".to_string()), "png:/tmp/ttdash.png".parse().unwrap());
+        assert_eq!(Backend::Gif("out.gif".to_string()), "gif:out.gif".parse().unwrap());
+        assert!("png:".parse::<Backend>().is_err());
+        assert!("hdmi".parse::<Backend>().is_err());
+    }
+
+    #[test]
+    fn image_sinks() {
+        let dir = std::env::temp_dir().join(format!("ttdash-display-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let png = dir.join("frame.png").to_str().unwrap().to_string();
+        let gif = dir.join("frames.gif").to_str().unwrap().to_string();
+
+        for backend in vec![Backend::Png(png.clone()), Backend::Gif(gif.clone())] {
+            let mut screen = open_screen(&backend, Box::new(Epd4in2{}), dither::Dither::None).unwrap();
+            screen.show(&test_image(400, 300), RefreshMode::Full).unwrap();
+            screen.show(&test_image(400, 300), RefreshMode::Partial).unwrap();
+        }
+
+        assert_eq!(image::open(&png).unwrap().to_rgb8(), test_image(400, 300));
+        assert!(std::fs::metadata(&gif).unwrap().len() > 0);
+
+        // Sinks show the image the way a black and white panel would.
+        let mut screen = open_screen(&Backend::Png(png.clone()), Box::new(Epd4in2{}), dither::Dither::Ordered).unwrap();
+        screen.show(&image::RgbImage::from_pixel(400, 300, image::Rgb([100; 3])), RefreshMode::Full).unwrap();
+        let shown = image::open(&png).unwrap().to_luma8();
+        assert!(shown.pixels().all(|p| p[0] == 0 || p[0] == 255));
+        assert!(shown.pixels().any(|p| p[0] == 0) && shown.pixels().any(|p| p[0] == 255));
+
+        // And in color for three color panels.
+        let mut imgbuf = test_image(400, 300);
+        imgbuf.put_pixel(10, 10, image::Rgb([255, 0, 0]));
+        let mut screen = open_screen(&Backend::Png(png.clone()), Model::Epd4in2B.panel(), dither::Dither::None).unwrap();
+        screen.show(&imgbuf, RefreshMode::Full).unwrap();
+        let shown = image::open(&png).unwrap().to_rgb8();
+        assert_eq!(RED, *shown.get_pixel(10, 10));
+        assert_eq!(image::Rgb([0, 0, 0]), *shown.get_pixel(0, 0));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Black in the top left, white elsewhere.
+    fn test_image(width: u32, height: u32) -> image::RgbImage {
+        return image::RgbImage::from_fn(width, height, |x, y| {
+            if x < 3 && y == 0 {
+                return image::Rgb([0, 0, 0]);
+            }
+            return image::Rgb([255, 255, 255]);
+        });
+    }
+
+    #[test]
+    fn models() {
+        for (name, width, height) in vec![("7in5", 640, 384), ("7in5_v2", 800, 480), ("4in2", 400, 300), ("2in9_v2", 128, 296),
+                                          ("7in5b_v2", 800, 480), ("4in2b", 400, 300), ("4in2c", 400, 300)] {
+            let panel = name.parse::<Model>().unwrap().panel();
+            assert_eq!(panel.name(), name);
+            assert_eq!((panel.width(), panel.height()), (width, height));
+        }
+        assert_eq!("7IN5_V2".parse::<Model>().unwrap(), Model::Epd7in5V2);
+        assert!("13in3".parse::<Model>().is_err());
+        assert_eq!(None, Model::Epd7in5V2.panel().accent());
+        assert_eq!(Some(YELLOW), Model::Epd4in2C.panel().accent());
+    }
+
+    #[test]
+    fn full_refresh_4in2b_golden() {
+        let mut display = recording_display(Model::Epd4in2B.panel());
+        let mut imgbuf = test_image(400, 300);
+        imgbuf.put_pixel(8, 0, RED);
+        imgbuf.put_pixel(9, 0, RED);
+        display.show(&imgbuf, RefreshMode::Full).unwrap();
+
+        let mut black = vec![0xFF; 400 * 300 / 8];
+        black[0] = 0x1F;
+        let mut red = vec![0xFF; 400 * 300 / 8];
+        red[1] = 0x3F;
+        assert_eq!(display.epd.ops, vec![
+            Op::Reset,
+            Op::Command(BOOSTER_SOFT_START), Op::Data(vec![0x17, 0x17, 0x17]),
+            Op::Command(POWER_ON), Op::Wait(Level::High),
+            Op::Command(PANEL_SETTING), Op::Data(vec![0x0F]),
+            Op::Command(DATA_START_TRANSMISSION), Op::Data(black),
+            Op::Command(DATA_START_TRANSMISSION_2), Op::Data(red),
+            Op::Command(DISPLAY_REFRESH), Op::Wait(Level::High),
+            Op::Command(POWER_OFF), Op::Wait(Level::High),
+        ]);
+    }
+
+    #[test]
+    fn pack_three_color_panels() {
+        let mut imgbuf = test_image(800, 480);
+        imgbuf.put_pixel(2, 0, RED);
+        imgbuf.put_pixel(4, 0, RED);
+        let data = Epd7in5BV2{}.pack(&imgbuf);
+        assert_eq!(data.len(), 2 * 800 * 480 / 8);
+        // Black is 0 and red is 1; the red pixel at (2, 0) isn't also black.
+        assert_eq!(&data[0..2], &[0x3F, 0xFF]);
+        assert_eq!(&data[800 * 480 / 8..][0..2], &[0x28, 0x00]);
+    }
+
+    #[test]
+    fn pack_7in5() {
+        let mut imgbuf = test_image(640, 384);
+        imgbuf.put_pixel(3, 0, image::Rgb([100; 3]));
+        let data = Epd7in5{}.pack(&imgbuf);
+        assert_eq!(data.len(), 640 * 384 / 2);
+        assert_eq!(&data[0..3], &[0x00, 0x01, 0x33]);
+        assert_eq!(data[320], 0x33);
+    }
+
+    #[test]
+    fn pack_1bpp_panels() {
+        let data = Epd7in5V2{}.pack(&test_image(800, 480));
+        assert_eq!(data.len(), 800 * 480 / 8);
+        assert_eq!(&data[0..2], &[0xE0, 0x00]);
+        assert_eq!(data[100], 0x00);
+
+        let data = Epd4in2{}.pack(&test_image(400, 300));
+        assert_eq!(data.len(), 400 * 300 / 8);
+        assert_eq!(&data[0..2], &[0x1F, 0xFF]);
+        assert_eq!(data[50], 0xFF);
+
+        let data = Epd2in9V2{}.pack(&test_image(128, 296));
+        assert_eq!(data.len(), 16 * 296);
+        assert_eq!(&data[0..2], &[0x1F, 0xFF]);
+    }
+
+    #[test]
+    fn changed_windows() {
+        let old = test_image(64, 100);
+        assert!(super::changed_windows(&old, &old).is_empty());
+
+        let mut new = old.clone();
+        // Two changes a few rows apart share a window...
+        new.put_pixel(10, 20, image::Rgb([0, 0, 0]));
+        new.put_pixel(30, 25, image::Rgb([0, 0, 0]));
+        // ...but one further down gets its own.
+        new.put_pixel(63, 60, image::Rgb([0, 0, 0]));
+        new.put_pixel(60, 61, image::Rgb([0, 0, 0]));
+
+        assert_eq!(vec![layout::Rect::new(8, 20, 24, 6), layout::Rect::new(56, 60, 8, 2)],
+                   super::changed_windows(&old, &new));
+    }
+
+    #[test]
+    fn pack_7in5_levels() {
+        let mut imgbuf = test_image(640, 384);
+        for (x, color) in vec![0, 63, 64, 128, 129, 192, 193, 255].iter().enumerate() {
+            imgbuf.put_pixel(x as u32, 0, image::Rgb([*color; 3]));
+        }
+        assert_eq!(&Epd7in5{}.pack(&imgbuf)[0..4], &[0x00, 0x11, 0x22, 0x33]);
+    }
+
+    #[test]
+    fn spidev_max_transfer() {
+        assert_eq!(4096, super::max_transfer(None));
+        assert_eq!(65536, super::max_transfer(Some("65536\n")));
+        assert_eq!(4096, super::max_transfer(Some("0\n")));
+        assert_eq!(4096, super::max_transfer(Some("lots")));
+    }
+
+    #[test]
+    fn pack_pads_rows() {
+        let data = pack_1bpp(&test_image(10, 2), false);
+        assert_eq!(data, vec![0x1F, 0xFF, 0xFF, 0xFF]);
+    }
 }
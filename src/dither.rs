@@ -0,0 +1,228 @@
+// Reduces a grayscale image to the handful of gray levels a panel can show.
+// Without dithering each pixel just becomes the nearest level, which keeps
+// text and lines crisp; dithering trades that for smooth gradients in things
+// like photos, maps and anti-aliased charts.
+extern crate image;
+extern crate std;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dither {
+    None,
+    FloydSteinberg,
+    Ordered,
+}
+
+impl std::str::FromStr for Dither {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Dither, String> {
+        return match s.to_lowercase().as_ref() {
+            "none" => Ok(Dither::None),
+            "floyd-steinberg" => Ok(Dither::FloydSteinberg),
+            "ordered" => Ok(Dither::Ordered),
+            _ => Err(format!("Unknown dither '{}', expected none, floyd-steinberg or ordered", s)),
+        };
+    }
+}
+
+// 4x4 Bayer matrix; each entry is a threshold in sixteenths.
+const BAYER_4X4 : [[u8; 4]; 4] = [
+    [0, 8, 2, 10],
+    [12, 4, 14, 6],
+    [3, 11, 1, 9],
+    [15, 7, 13, 5],
+];
+
+// Every pixel of the result is one of `palette`, which is sorted and
+// non-empty.
+pub fn quantize(imgbuf: &image::GrayImage, palette: &[u8], dither: Dither) -> image::GrayImage {
+    return match dither {
+        Dither::None => image::GrayImage::from_fn(imgbuf.width(), imgbuf.height(), |x, y| {
+            image::Luma([nearest(palette, imgbuf.get_pixel(x, y)[0] as f32)])
+        }),
+        Dither::FloydSteinberg => floyd_steinberg(imgbuf, palette),
+        Dither::Ordered => image::GrayImage::from_fn(imgbuf.width(), imgbuf.height(), |x, y| {
+            let value = imgbuf.get_pixel(x, y)[0];
+            let (darker, lighter) = bracket(palette, value);
+            if darker == lighter {
+                return image::Luma([darker]);
+            }
+            let fraction = (value - darker) as f32 / (lighter - darker) as f32;
+            let threshold = (BAYER_4X4[(y % 4) as usize][(x % 4) as usize] as f32 + 0.5) / 16.0;
+            return image::Luma([if fraction > threshold { lighter } else { darker }]);
+        }),
+    };
+}
+
+// Like `quantize`, for panels that may also have an accent color: pixels
+// nearer `accent` than to any of `palette` become it, and the rest are
+// reduced to grays.
+pub fn quantize_rgb(imgbuf: &image::RgbImage, palette: &[u8], accent: Option<image::Rgb<u8>>, dither: Dither) -> image::RgbImage {
+    use image::Pixel;
+
+    let is_accent: Vec<bool> = imgbuf.pixels().map(|p| match accent {
+        Some(accent) => palette.iter().all(|gray| distance(p, &accent) < distance(p, &image::Rgb([*gray; 3]))),
+        None => false,
+    }).collect();
+    let width = imgbuf.width();
+
+    // Accent pixels count as white so their error doesn't darken what's around them.
+    let grays = image::GrayImage::from_fn(imgbuf.width(), imgbuf.height(), |x, y| {
+        if is_accent[(y * width + x) as usize] {
+            return image::Luma([255]);
+        }
+        return imgbuf.get_pixel(x, y).to_luma();
+    });
+    let grays = quantize(&grays, palette, dither);
+
+    return image::RgbImage::from_fn(imgbuf.width(), imgbuf.height(), |x, y| {
+        if is_accent[(y * width + x) as usize] {
+            return accent.unwrap();
+        }
+        return grays.get_pixel(x, y).to_rgb();
+    });
+}
+
+fn distance(a: &image::Rgb<u8>, b: &image::Rgb<u8>) -> i32 {
+    return a.0.iter().zip(b.0.iter()).map(|(a, b)| (*a as i32 - *b as i32).pow(2)).sum();
+}
+
+fn nearest(palette: &[u8], value: f32) -> u8 {
+    return *palette.iter()
+        .min_by(|a, b| (**a as f32 - value).abs().partial_cmp(&(**b as f32 - value).abs()).unwrap())
+        .expect("empty palette");
+}
+
+// The palette entries either side of `value`, which are the same if it's on
+// or beyond the ends of the palette.
+fn bracket(palette: &[u8], value: u8) -> (u8, u8) {
+    let darker = palette.iter().rev().find(|p| **p <= value).unwrap_or(&palette[0]);
+    let lighter = palette.iter().find(|p| **p >= value).unwrap_or(&palette[palette.len() - 1]);
+    return (*darker, *lighter);
+}
+
+// Pushes each pixel's rounding error onto the neighbours not yet visited:
+// 7/16 right, then 3/16, 5/16 and 1/16 along the row below.
+fn floyd_steinberg(imgbuf: &image::GrayImage, palette: &[u8]) -> image::GrayImage {
+    let (width, height) = (imgbuf.width() as usize, imgbuf.height() as usize);
+    let mut values: Vec<f32> = imgbuf.pixels().map(|p| p[0] as f32).collect();
+    let mut out = image::GrayImage::new(imgbuf.width(), imgbuf.height());
+
+    for y in 0..height {
+        for x in 0..width {
+            let value = values[y * width + x];
+            let chosen = nearest(palette, value);
+            out.put_pixel(x as u32, y as u32, image::Luma([chosen]));
+
+            let error = value - chosen as f32;
+            let mut spread = |dx: isize, dy: usize, weight: f32| {
+                let nx = x as isize + dx;
+                if nx >= 0 && (nx as usize) < width && y + dy < height {
+                    values[(y + dy) * width + nx as usize] += error * weight;
+                }
+            };
+            spread(1, 0, 7.0 / 16.0);
+            spread(-1, 1, 3.0 / 16.0);
+            spread(0, 1, 5.0 / 16.0);
+            spread(1, 1, 1.0 / 16.0);
+        }
+    }
+    return out;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::quantize;
+    use super::Dither;
+
+    const FOUR_GRAYS : [u8; 4] = [0, 128, 192, 255];
+    const BLACK_AND_WHITE : [u8; 2] = [0, 255];
+
+    fn flat(value: u8) -> image::GrayImage {
+        return image::GrayImage::from_pixel(16, 16, image::Luma([value]));
+    }
+
+    fn gradient() -> image::GrayImage {
+        return image::GrayImage::from_fn(256, 8, |x, _| image::Luma([x as u8]));
+    }
+
+    fn black_fraction(imgbuf: &image::GrayImage) -> f32 {
+        return imgbuf.pixels().filter(|p| p[0] == 0).count() as f32 / (imgbuf.width() * imgbuf.height()) as f32;
+    }
+
+    #[test]
+    fn only_palette_colors() {
+        for dither in vec![Dither::None, Dither::FloydSteinberg, Dither::Ordered] {
+            for palette in vec![&FOUR_GRAYS[..], &BLACK_AND_WHITE[..]] {
+                let out = quantize(&gradient(), palette, dither);
+                assert!(out.pixels().all(|p| palette.contains(&p[0])), "{:?} {:?}", dither, palette);
+            }
+        }
+    }
+
+    #[test]
+    fn palette_colors_are_kept() {
+        // Solid fills the dashboard draws with shouldn't turn into noise.
+        for dither in vec![Dither::None, Dither::FloydSteinberg, Dither::Ordered] {
+            for value in FOUR_GRAYS.iter() {
+                assert_eq!(flat(*value), quantize(&flat(*value), &FOUR_GRAYS, dither), "{:?} {}", dither, value);
+            }
+        }
+    }
+
+    #[test]
+    fn no_dither_picks_nearest() {
+        let out = quantize(&gradient(), &FOUR_GRAYS, Dither::None);
+        assert_eq!(0, out.get_pixel(63, 0)[0]);
+        assert_eq!(128, out.get_pixel(65, 0)[0]);
+        assert_eq!(192, out.get_pixel(200, 0)[0]);
+        assert_eq!(255, out.get_pixel(240, 0)[0]);
+
+        // No mid gray on a black and white panel.
+        assert_eq!(1.0, black_fraction(&quantize(&flat(100), &BLACK_AND_WHITE, Dither::None)));
+        assert_eq!(0.0, black_fraction(&quantize(&flat(150), &BLACK_AND_WHITE, Dither::None)));
+    }
+
+    #[test]
+    fn dithering_keeps_average_brightness() {
+        for (value, expected_black) in vec![(64, 0.75), (128, 0.5), (191, 0.25)] {
+            let ordered = quantize(&flat(value), &BLACK_AND_WHITE, Dither::Ordered);
+            assert_eq!(expected_black, black_fraction(&ordered), "{}", value);
+
+            let diffused = quantize(&flat(value), &BLACK_AND_WHITE, Dither::FloydSteinberg);
+            assert!((black_fraction(&diffused) - expected_black).abs() < 0.05, "{} {}", value, black_fraction(&diffused));
+        }
+
+        // Between two grays, only those two are used.
+        let out = quantize(&flat(160), &FOUR_GRAYS, Dither::Ordered);
+        assert_eq!(128, out.pixels().map(|p| p[0]).filter(|v| *v == 128).count());
+        assert!(out.pixels().all(|p| p[0] == 128 || p[0] == 192));
+    }
+
+    #[test]
+    fn accent() {
+        let red = image::Rgb([255, 0, 0]);
+        let mut imgbuf = image::RgbImage::from_pixel(16, 16, image::Rgb([255, 255, 255]));
+        imgbuf.put_pixel(0, 0, red);
+        imgbuf.put_pixel(1, 0, image::Rgb([220, 40, 30]));
+        imgbuf.put_pixel(2, 0, image::Rgb([0, 0, 0]));
+        imgbuf.put_pixel(3, 0, image::Rgb([255, 200, 200]));
+
+        let out = super::quantize_rgb(&imgbuf, &BLACK_AND_WHITE, Some(red), Dither::FloydSteinberg);
+        assert_eq!(vec![red, red, image::Rgb([0, 0, 0]), image::Rgb([255, 255, 255])],
+                   (0..4).map(|x| *out.get_pixel(x, 0)).collect::<Vec<_>>());
+        assert_eq!(2, out.pixels().filter(|p| **p == red).count());
+
+        // Without an accent channel, red is just dark.
+        let out = super::quantize_rgb(&imgbuf, &FOUR_GRAYS, None, Dither::None);
+        assert_eq!(image::Rgb([0, 0, 0]), *out.get_pixel(0, 0));
+        assert!(out.pixels().all(|p| p[0] == p[1] && p[1] == p[2]));
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Ok(Dither::FloydSteinberg), "Floyd-Steinberg".parse());
+        assert_eq!(Ok(Dither::Ordered), "ordered".parse());
+        assert!("atkinson".parse::<Dither>().is_err());
+    }
+}
@@ -5,7 +5,10 @@ extern crate imageproc;
 extern crate rusttype;
 extern crate std;
 
+use crate::aqi;
 use crate::bustime;
+use crate::indoor;
+use crate::layout;
 use crate::purpleair;
 use crate::result;
 use crate::subway;
@@ -16,155 +19,314 @@ pub struct Styles<'a> {
     pub font_bold: rusttype::Font<'a>,
     pub font_black: rusttype::Font<'a>,
 
-    pub color_black: image::Luma<u8>,
-    pub color_light_gray: image::Luma<u8>,
-    pub color_dark_gray: image::Luma<u8>,
-    pub color_white: image::Luma<u8>,
+    pub color_black: image::Rgb<u8>,
+    pub color_light_gray: image::Rgb<u8>,
+    pub color_dark_gray: image::Rgb<u8>,
+    pub color_white: image::Rgb<u8>,
+    // For things that need attention: service alerts, thunderstorms and
+    // unhealthy air. Black on panels without a red or yellow channel.
+    pub color_accent: image::Rgb<u8>,
 }
 
-const EPD_WIDTH: usize = 640;
-const EPD_HEIGHT: usize = 384;
-
-pub fn generate_image(data: &subway::ProcessedData,
+pub fn generate_image(layout: &layout::Layout,
+                      data: &subway::ProcessedData,
                       weather_display: Option<&weather::WeatherDisplay>,
                       air_quality: Option<&purpleair::AirQuality>,
+                      indoor_air_quality: Option<&indoor::IndoorAirQuality>,
                       bus_time: Option<&bustime::BusTimeDisplayData>,
                       version: Option<String>,
-                      styles: &Styles) -> result::TTDashResult<image::GrayImage> {
-    let mut imgbuf = image::GrayImage::new(EPD_WIDTH as u32, EPD_HEIGHT as u32);
+                      timezone: &chrono_tz::Tz,
+                      styles: &Styles) -> result::TTDashResult<image::RgbImage> {
+    let mut imgbuf = image::RgbImage::new(layout.width, layout.height);
+    imageproc::drawing::draw_filled_rect_mut(
+        &mut imgbuf, imageproc::rect::Rect::at(0, 0).of_size(layout.width, layout.height), styles.color_white);
 
-    draw_subway_arrivals(&mut imgbuf, styles, data, bus_time);
+    let now = chrono::Utc::now().timestamp();
+    for region in layout.regions() {
+        match region.section {
+            layout::Section::Title => draw_title(&mut imgbuf, styles, &region),
+            layout::Section::Divider => draw_divider(&mut imgbuf, styles, &region),
+            layout::Section::AirQuality => draw_air_quality(&mut imgbuf, styles, &region, air_quality, indoor_air_quality)?,
+            layout::Section::BigCountdown => draw_big_countdown(&mut imgbuf, styles, &region, data),
+            layout::Section::UpcomingTrains => draw_upcoming_trains(&mut imgbuf, styles, &region, now, data, timezone),
+            layout::Section::OutboundTrains => draw_outbound_trains(&mut imgbuf, styles, &region, now, data),
+            layout::Section::Bus => {
+                if let Some(bus_time) = bus_time {
+                    draw_bus(&mut imgbuf, styles, &region, bus_time);
+                }
+            },
+            layout::Section::Alerts => draw_alerts(&mut imgbuf, styles, &region, data),
+            layout::Section::CurrentWeather => {
+                if let Some(weather_display) = weather_display {
+                    draw_current_weather(&mut imgbuf, styles, &region, weather_display)?;
+                }
+            },
+            layout::Section::DailyForecast => {
+                if let Some(weather_display) = weather_display {
+                    draw_daily_forecast(&mut imgbuf, styles, &region, now, weather_display)?;
+                }
+            },
+            layout::Section::HourlyForecast => {
+                if let Some(weather_display) = weather_display {
+                    draw_hourly_forecast(&mut imgbuf, styles, &region, now, weather_display);
+                }
+            },
+            layout::Section::Version => draw_version(
+                &mut imgbuf, styles, &region, version.as_ref().map(String::as_ref).unwrap_or("UNKNOWN VERSION")),
+        }
+    }
 
-    if weather_display.is_some() {
-        draw_weather(&mut imgbuf, styles, weather_display.unwrap())?;
+    return Ok(imgbuf);
+}
+
+// What stays on screen through quiet hours: the forecast for the day they
+// end, since there's nothing to say about trains overnight.
+pub fn generate_night_image(layout: &layout::Layout,
+                            weather_display: Option<&weather::WeatherDisplay>,
+                            wake_date: chrono::NaiveDate,
+                            styles: &Styles) -> result::TTDashResult<image::RgbImage> {
+    let mut imgbuf = image::RgbImage::new(layout.width, layout.height);
+    imageproc::drawing::draw_filled_rect_mut(
+        &mut imgbuf, imageproc::rect::Rect::at(0, 0).of_size(layout.width, layout.height), styles.color_white);
+
+    let frame = layout::Rect::new(0, 0, layout.width as i32, layout.height as i32)
+        .inset((20.0 * layout.scale) as i32);
+    let (title_rect, rest) = frame.split_top(frame.height * 25 / 100);
+    let (forecast_rect, details_rect) = rest.split_top(rest.height * 60 / 100);
+
+    layout::draw_text(&mut imgbuf, styles.color_black, &title_rect, layout::HAlign::Left, layout::VAlign::Center,
+                      60.0 * layout.scale, &styles.font_bold, &wake_date.format("%A, %B %-d").to_string());
+
+    let day = weather_display.and_then(
+        |weather_display| weather_display.days.iter().find(|(date, _)| date.naive_local() == wake_date)).map(|(_, day)| day);
+    let day = match day {
+        Some(day) => day,
+        None => {
+            layout::draw_text(&mut imgbuf, styles.color_black, &forecast_rect, layout::HAlign::Left, layout::VAlign::Center,
+                              40.0 * layout.scale, &styles.font, "No forecast");
+            return Ok(imgbuf);
+        },
+    };
+
+    // Condition icon, then the high and low.
+    let icon_size = std::cmp::min(forecast_rect.height, forecast_rect.width / 3) * 80 / 100;
+    let (icon_rect, temps_rect) = forecast_rect.split_left(forecast_rect.width / 3);
+    if let Some(ref condition) = day.condition {
+        draw_condition_icon(
+            condition,
+            icon_rect.x + (icon_rect.width - icon_size) / 2, icon_rect.y + (icon_rect.height - icon_size) / 2, icon_size,
+            &mut imgbuf, styles)?;
     }
+    layout::draw_text(&mut imgbuf, styles.color_black, &temps_rect, layout::HAlign::Center, layout::VAlign::Center,
+                      140.0 * layout.scale, &styles.font_black, &format!("{:.0}° / {:.0}°", day.max_t, day.min_t));
 
-    if air_quality.is_some() {
-        draw_air_quality(&mut imgbuf, styles, air_quality.unwrap())?;
+    let rows = details_rect.rows(2);
+    if let Some(ref short_forecast) = day.short_forecast {
+        layout::draw_text(&mut imgbuf, styles.color_black, &rows[0], layout::HAlign::Left, layout::VAlign::Center,
+                          40.0 * layout.scale, &styles.font, short_forecast);
     }
 
-    draw_version(&mut imgbuf, styles, version.unwrap_or("UNKNOWN VERSION".to_string()).as_ref());
+    let mut details = vec![];
+    if let Some(sunrise) = day.sunrise {
+        details.push(format!("Sunrise {}", sunrise.format("%-I:%M")));
+    }
+    if !day.precip_by_hour.is_empty() {
+        details.push(format!("Precip {:.0}%", day.precip_by_hour.values().cloned().fold(0.0, f32::max)));
+    }
+    layout::draw_text(&mut imgbuf, styles.color_black, &rows[1], layout::HAlign::Left, layout::VAlign::Center,
+                      40.0 * layout.scale, &styles.font, &details.join("   "));
 
     return Ok(imgbuf);
 }
 
-fn draw_version(imgbuf: &mut image::GrayImage, styles: &Styles, version: &str) {
-    imageproc::drawing::draw_text_mut(imgbuf, styles.color_black, 570, 5, scale(15.0), &styles.font, version);
+fn draw_version(imgbuf: &mut image::RgbImage, styles: &Styles, region: &layout::Region, version: &str) {
+    layout::draw_text(imgbuf, styles.color_black, &region.rect,
+                      region.halign.unwrap_or(layout::HAlign::Right), region.valign.unwrap_or(layout::VAlign::Center),
+                      15.0 * region.scale, &styles.font, version);
 }
 
+fn draw_title(imgbuf: &mut image::RgbImage, styles: &Styles, region: &layout::Region) {
+    layout::draw_text(imgbuf, styles.color_black, &region.rect,
+                      region.halign.unwrap_or(layout::HAlign::Left), region.valign.unwrap_or(layout::VAlign::Center),
+                      40.0 * region.scale, &styles.font, "Manhattan");
+}
 
-fn draw_subway_arrivals(imgbuf: &mut image::GrayImage, styles: &Styles, data: &subway::ProcessedData, bus_time_data: Option<&bustime::BusTimeDisplayData>) {
-    let now = chrono::Utc::now().timestamp();
-
-    imageproc::drawing::draw_filled_rect_mut(imgbuf, imageproc::rect::Rect::at(0,0).of_size(EPD_WIDTH as u32, EPD_HEIGHT as u32), styles.color_white);
+// A horizontal rule through the middle of the region, inset from its ends.
+fn draw_divider(imgbuf: &mut image::RgbImage, styles: &Styles, region: &layout::Region) {
+    let inset = region.scaled(10) as f32;
+    let rect = &region.rect;
+    let y = (rect.y + rect.height / 2) as f32;
+    imageproc::drawing::draw_line_segment_mut(
+        imgbuf, (rect.x as f32 + inset, y), (rect.right() as f32 - inset, y), styles.color_black);
+}
 
-//    imageproc::drawing::draw_text_mut(imgbuf, styles.color_black, 10, 0, scale(50.0), &styles.font_bold, &data.station_name);
-    imageproc::drawing::draw_text_mut(imgbuf, styles.color_black, 10, 0, scale(40.0), &styles.font, "Manhattan");
+fn draw_big_countdown(imgbuf: &mut image::RgbImage, styles: &Styles, region: &layout::Region, data: &subway::ProcessedData) {
+    let big_text = match data.big_countdown {
+        Some(ref big_text) => big_text,
+        None => return,
+    };
 
-    imageproc::drawing::draw_line_segment_mut(imgbuf, (10.0, 45.0), (EPD_HEIGHT as f32 - 10.0, 45.0), styles.color_black);
+    let big_line = data.big_countdown_line.clone().unwrap_or("R".to_string());
+    layout::draw_text(imgbuf, line_color(styles, data, &big_line), &region.rect,
+                      region.halign.unwrap_or(layout::HAlign::Center), region.valign.unwrap_or(layout::VAlign::Center),
+                      250.0 * region.scale, &styles.font_black, big_text);
+
+    if big_line != "R" {
+        draw_subway_line_emblem(
+            imgbuf, &big_line,
+            (region.rect.x + region.scaled(30)) as u32, (region.rect.y + region.scaled(30)) as u32,
+            region.scaled(20) as u32, styles);
+    }
+}
 
+// One row per train: minutes to go, arrival time and, for trains other than
+// the R, the line.
+fn draw_upcoming_trains(imgbuf: &mut image::RgbImage, styles: &Styles, region: &layout::Region, now: i64, data: &subway::ProcessedData, timezone: &chrono_tz::Tz) {
     use chrono::TimeZone;
 
-    let big_line = data.big_countdown_line.clone().unwrap_or("R".to_string());
-    match data.big_countdown {
-        Some(ref big_text) => {
-            let x;
-            if big_text.len() == 1 {
-                x = 70;
-            } else {
-                x = 10;
-            }
-            imageproc::drawing::draw_text_mut(imgbuf, styles.color_black, x, 15, scale(250.0), &styles.font_black, big_text);
-            if big_line != "R" {
-                draw_subway_line_emblem(imgbuf, &big_line, 30, 75, 20, styles);
-            }
-        },
-        _ => {},
-    }
+    let rows = 4;
+    let countdown_width = region.scaled(56);
+    let gap = region.scaled(10);
+    let emblem_width = region.scaled(26);
 
-    let mut y = 50;
-    let y_step = 40;
-    for (ref ts, ref line) in data.upcoming_trains.iter().take(4) {
+    for (row, (ref ts, ref line)) in region.rect.rows(rows).iter().zip(data.upcoming_trains.iter().take(rows as usize)) {
         let countdown = countdown_summary(now, *ts);
-        let arrival = chrono_tz::US::Eastern.timestamp(*ts, 0);
+        let arrival = timezone.timestamp(*ts, 0);
         let arrival_formatted = arrival.format("%-I:%M").to_string();
 
-        imageproc::drawing::draw_text_mut(imgbuf, styles.color_black, 219, y, scale(50.0), &styles.font_bold, &countdown);
-        imageproc::drawing::draw_text_mut(imgbuf, styles.color_black, 284, y, scale(50.0), &styles.font, &arrival_formatted);
+        let (countdown_rect, rest) = row.split_left(countdown_width);
+        let (_, rest) = rest.split_left(gap);
+        let (time_rect, emblem_rect) = rest.split_left(rest.width - emblem_width);
+
+        layout::draw_text(imgbuf, line_color(styles, data, line), &countdown_rect, layout::HAlign::Right, layout::VAlign::Center,
+                          50.0 * region.scale, &styles.font_bold, &countdown);
+        layout::draw_text(imgbuf, styles.color_black, &time_rect, layout::HAlign::Left, layout::VAlign::Center,
+                          50.0 * region.scale, &styles.font, &arrival_formatted);
 
         if line != "R" {
-            draw_subway_line_emblem(imgbuf, line, 375, (y + 25) as u32, 12, styles);
+            draw_subway_line_emblem(
+                imgbuf, line,
+                (emblem_rect.x + emblem_rect.width / 2) as u32, (emblem_rect.y + emblem_rect.height / 2) as u32,
+                region.scaled(12) as u32, styles);
         }
+    }
+}
 
-        y = y + y_step;
+// Countdowns for trains on lines with service alerts stand out.
+fn line_color(styles: &Styles, data: &subway::ProcessedData, line: &str) -> image::Rgb<u8> {
+    if data.alerted_lines.iter().any(|alerted| alerted == line) {
+        return styles.color_accent;
     }
+    return styles.color_black;
+}
 
+fn draw_outbound_trains(imgbuf: &mut image::RgbImage, styles: &Styles, region: &layout::Region, now: i64, data: &subway::ProcessedData) {
+    let outbound_text: String = if data.upcoming_outbound_trains.is_empty() {
+        "NO TRAINS".to_string()
+    } else {
+        data.upcoming_outbound_trains.iter()
+            .take(4)
+        //                .filter(|(_, line)| line == "R")
+            .map(|(ts, line)|
+                 if line == "R" {
+                     countdown_summary(now, *ts)
+                 } else {
+                     format!("{} ({})", countdown_summary(now, *ts), line)
+                 })
+            .collect::<Vec<String>>()
+            .join(", ")
+    };
+
+    draw_arrivals_row(imgbuf, styles, region, &region.rect, Direction::Down, "R:", &outbound_text);
+}
 
-    imageproc::drawing::draw_line_segment_mut(imgbuf, (10.0, 230.0), (EPD_HEIGHT as f32 - 10.0, 230.0), styles.color_black);
+fn draw_bus(imgbuf: &mut image::RgbImage, styles: &Styles, region: &layout::Region, bus_time_data: &bustime::BusTimeDisplayData) {
+    let rows = region.rect.rows(2);
 
-    {
-        let section_y = 240;
-        imageproc::drawing::draw_polygon_mut(imgbuf, &[
-            imageproc::point::Point::new(20, section_y + 32),
-            imageproc::point::Point::new(28, section_y + 12),
-            imageproc::point::Point::new(12, section_y + 12),
-        ], styles.color_black);
-        imageproc::drawing::draw_text_mut(imgbuf, styles.color_black, 32, section_y, scale(50.0), &styles.font, "R: ");
+    let uptown_text = bus_time_data.uptown_waits.iter().take(3).map(|w| format!("{}", w)).collect::<Vec<String>>().join(", ");
+    draw_arrivals_row(imgbuf, styles, region, &rows[0], Direction::Up, "B63:", &uptown_text);
 
-        let outbound_text: String = if data.upcoming_outbound_trains.is_empty() {
-            "NO TRAINS".to_string()
-        } else {
-            data.upcoming_outbound_trains.iter()
-                .take(4)
-            //                .filter(|(_, line)| line == "R")
-                .map(|(ts, line)|
-                     if line == "R" {
-                         countdown_summary(now, *ts)
-                     } else {
-                         format!("{} ({})", countdown_summary(now, *ts), line)
-                     })
-                .collect::<Vec<String>>()
-                .join(", ")
-        };
+    let downtown_text = bus_time_data.downtown_waits.iter().take(3).map(|w| format!("{}", w)).collect::<Vec<String>>().join(", ");
+    draw_arrivals_row(imgbuf, styles, region, &rows[1], Direction::Down, "B63:", &downtown_text);
+}
 
-        imageproc::drawing::draw_text_mut(imgbuf, styles.color_black, 100, section_y, scale(50.0), &styles.font_bold, &outbound_text);
+// Each service alert: the lines it affects, then what's going on, wrapped to
+// the width of the region. Alerts that don't fit are left off.
+fn draw_alerts(imgbuf: &mut image::RgbImage, styles: &Styles, region: &layout::Region, data: &subway::ProcessedData) {
+    if data.alerts.is_empty() {
+        layout::draw_text(imgbuf, styles.color_black, &region.rect,
+                          region.halign.unwrap_or(layout::HAlign::Center), region.valign.unwrap_or(layout::VAlign::Center),
+                          40.0 * region.scale, &styles.font, "Good service");
+        return;
     }
 
+    let text_size = 30.0 * region.scale;
+    let line_height = region.scaled(36);
+    let bar_width = region.scaled(4);
+    let indent = region.scaled(12);
+    let radius = region.scaled(14);
+    let alert_gap = region.scaled(16);
+
+    let mut y = region.rect.y;
+    for alert in &data.alerts {
+        let text_width = region.rect.width - indent;
+        let lines = layout::wrap_text(&styles.font, text_size, &alert.summary, text_width);
+        let lines_that_fit = std::cmp::min(lines.len() as i32, (region.rect.bottom() - y) / line_height - 1);
+        if lines_that_fit < 1 {
+            break;
+        }
 
-    // Top:    [290, 340), triangle at 302 -> 322
-    // Bottom: [340, 390), triangle at 356 -> 376
-    match bus_time_data {
-        None => {},
-        Some(bus_time_data) => {
-            {
-                // Uptown B63
-                let section_y = 290;
-                imageproc::drawing::draw_text_mut(imgbuf, styles.color_black, 32, section_y, scale(50.0), &styles.font, "B63: ");
-                imageproc::drawing::draw_polygon_mut(imgbuf, &[
-                    imageproc::point::Point::new(20, section_y + 16),
-                    imageproc::point::Point::new(28, section_y + 36),
-                    imageproc::point::Point::new(12, section_y + 36),
-                ], styles.color_black);
-                let uptown_text = bus_time_data.uptown_waits.iter().take(3).map(|w| format!("{}", w)).collect::<Vec<String>>().join(", ");
-                imageproc::drawing::draw_text_mut(imgbuf, styles.color_black, 120, section_y, scale(50.0), &styles.font_bold, &uptown_text);
-            }
-
-            {
-                // Downtown B63
-                let section_y = 340;
-                imageproc::drawing::draw_polygon_mut(imgbuf, &[
-                    imageproc::point::Point::new(20, section_y + 32),
-                    imageproc::point::Point::new(28, section_y + 12),
-                    imageproc::point::Point::new(12, section_y + 12),
-                ], styles.color_black);
-                imageproc::drawing::draw_text_mut(imgbuf, styles.color_black, 32, section_y, scale(50.0), &styles.font, "B63: ");
-                let downtown_text = bus_time_data.downtown_waits.iter().take(3).map(|w| format!("{}", w)).collect::<Vec<String>>().join(", ");
-                imageproc::drawing::draw_text_mut(imgbuf, styles.color_black, 120, section_y, scale(50.0), &styles.font_bold, &downtown_text);
-            }
+        // An accent bar down the side of each alert, with the lines across the
+        // top and the summary below.
+        let height = (lines_that_fit + 1) * line_height;
+        imageproc::drawing::draw_filled_rect_mut(
+            imgbuf, layout::Rect::new(region.rect.x, y, bar_width, height).to_imageproc(), styles.color_accent);
+        for (i, line) in alert.lines.iter().enumerate() {
+            draw_subway_line_emblem(
+                imgbuf, line,
+                (region.rect.x + indent + radius + i as i32 * (2 * radius + region.scaled(6))) as u32,
+                (y + line_height / 2) as u32,
+                radius as u32, styles);
+        }
+        for (i, text) in lines.iter().take(lines_that_fit as usize).enumerate() {
+            let rect = layout::Rect::new(region.rect.x + indent, y + (i as i32 + 1) * line_height, text_width, line_height);
+            layout::draw_text(imgbuf, styles.color_black, &rect, layout::HAlign::Left, layout::VAlign::Center,
+                              text_size, &styles.font, text);
         }
+        y += height + alert_gap;
     }
 }
 
+enum Direction {
+    Up,
+    Down,
+}
+
+// [arrow] label: waits
+fn draw_arrivals_row(imgbuf: &mut image::RgbImage, styles: &Styles, region: &layout::Region, rect: &layout::Rect, direction: Direction, label: &str, text: &str) {
+    let arrow_width = region.scaled(32);
+    let label_gap = region.scaled(16);
+
+    let (arrow_rect, rest) = rect.split_left(arrow_width);
+    let center_x = arrow_rect.x + region.scaled(20);
+    let center_y = arrow_rect.y + arrow_rect.height / 2;
+    let (tip_y, base_y) = match direction {
+        Direction::Up => (center_y - region.scaled(10), center_y + region.scaled(10)),
+        Direction::Down => (center_y + region.scaled(10), center_y - region.scaled(10)),
+    };
+    imageproc::drawing::draw_polygon_mut(imgbuf, &[
+        imageproc::point::Point::new(center_x, tip_y),
+        imageproc::point::Point::new(center_x + region.scaled(8), base_y),
+        imageproc::point::Point::new(center_x - region.scaled(8), base_y),
+    ], styles.color_black);
+
+    let label_ink = layout::draw_text(imgbuf, styles.color_black, &rest, layout::HAlign::Left, layout::VAlign::Center,
+                                      50.0 * region.scale, &styles.font, label);
+    let (_, text_rect) = rest.split_left(label_ink.right() + label_gap - rest.x);
+    layout::draw_text(imgbuf, styles.color_black, &text_rect, layout::HAlign::Left, layout::VAlign::Center,
+                      50.0 * region.scale, &styles.font_bold, text);
+}
+
 pub fn countdown_summary(now_ts: i64, arrival_ts: i64) -> String {
     let wait_seconds = arrival_ts - now_ts;
 
@@ -174,7 +336,7 @@ pub fn countdown_summary(now_ts: i64, arrival_ts: i64) -> String {
     return format!("{}", wait_seconds / 60);
 }
 
-fn draw_raindrop(x: i32, y: i32, height: i32, imgbuf: &mut image::GrayImage, styles: &Styles) -> result::TTDashResult<()> {
+fn draw_raindrop(x: i32, y: i32, height: i32, imgbuf: &mut image::RgbImage, styles: &Styles) -> result::TTDashResult<()> {
     let x = x as f32;
     let y = y as f32;
     let height = height as f32;
@@ -200,93 +362,344 @@ fn draw_raindrop(x: i32, y: i32, height: i32, imgbuf: &mut image::GrayImage, sty
     return Ok(());
 }
 
-fn draw_daily_forecast(left_x: i32, top_y: i32, imgbuf: &mut image::GrayImage, styles: &Styles, weather_display: &weather::WeatherDisplay) -> result::TTDashResult<()> {
+fn draw_cloud(x: i32, y: i32, size: i32, imgbuf: &mut image::RgbImage, styles: &Styles) {
+    let radius = size / 4;
+    imageproc::drawing::draw_filled_circle_mut(imgbuf, (x + radius, y + 2 * radius), radius, styles.color_dark_gray);
+    imageproc::drawing::draw_filled_circle_mut(imgbuf, (x + 2 * radius, y + radius + 1), radius + 1, styles.color_dark_gray);
+    imageproc::drawing::draw_filled_circle_mut(imgbuf, (x + 3 * radius, y + 2 * radius), radius, styles.color_dark_gray);
+    imageproc::drawing::draw_filled_rect_mut(
+        imgbuf,
+        imageproc::rect::Rect::at(x + radius, y + 2 * radius).of_size((2 * radius) as u32, (radius + 1) as u32),
+        styles.color_dark_gray);
+}
+
+// Draws a size x size icon with its top left corner at (x, y).
+fn draw_condition_icon(condition: &weather::Condition, x: i32, y: i32, size: i32, imgbuf: &mut image::RgbImage, styles: &Styles) -> result::TTDashResult<()> {
+    let fx = x as f32;
+    let fy = y as f32;
+    let fsize = size as f32;
+
+    match *condition {
+        weather::Condition::Sunny => {
+            let center = (x + size / 2, y + size / 2);
+            imageproc::drawing::draw_filled_circle_mut(imgbuf, center, size / 4, styles.color_black);
+            for i in 0..8 {
+                let angle = i as f32 * std::f32::consts::PI / 4.0;
+                let (inner, outer) = (fsize * 0.35, fsize * 0.5);
+                imageproc::drawing::draw_line_segment_mut(
+                    imgbuf,
+                    (center.0 as f32 + inner * angle.cos(), center.1 as f32 + inner * angle.sin()),
+                    (center.0 as f32 + outer * angle.cos(), center.1 as f32 + outer * angle.sin()),
+                    styles.color_black);
+            }
+        },
+        weather::Condition::Cloudy => {
+            draw_cloud(x, y + size / 4, size, imgbuf, styles);
+        },
+        weather::Condition::Rain => {
+            draw_cloud(x, y, size, imgbuf, styles);
+            for i in 1..4 {
+                let drop_x = fx + fsize * i as f32 / 4.0;
+                imageproc::drawing::draw_line_segment_mut(
+                    imgbuf,
+                    (drop_x, fy + fsize * 0.8),
+                    (drop_x - 2.0, fy + fsize),
+                    styles.color_black);
+            }
+        },
+        weather::Condition::Snow => {
+            draw_cloud(x, y, size, imgbuf, styles);
+            for i in 1..4 {
+                imageproc::drawing::draw_filled_circle_mut(
+                    imgbuf, (x + size * i / 4, y + size - 2 * (i % 2)), 1, styles.color_black);
+            }
+        },
+        weather::Condition::Thunder => {
+            draw_cloud(x, y, size, imgbuf, styles);
+            let mut bolt = vec![
+                imageproc::point::Point::new(x + size / 2, y + size / 2),
+                imageproc::point::Point::new(x + size / 4, y + 3 * size / 4),
+                imageproc::point::Point::new(x + size / 2, y + 3 * size / 4),
+                imageproc::point::Point::new(x + 3 * size / 8, y + size),
+                imageproc::point::Point::new(x + 3 * size / 4, y + 5 * size / 8),
+                imageproc::point::Point::new(x + size / 2, y + 5 * size / 8),
+            ];
+            // Small icons round the last point onto the first, which
+            // draw_polygon_mut won't take.
+            if bolt.first() == bolt.last() {
+                bolt.pop();
+            }
+            imageproc::drawing::draw_polygon_mut(imgbuf, &bolt, styles.color_accent);
+        },
+        weather::Condition::Fog => {
+            for i in 1..4 {
+                let line_y = fy + fsize * i as f32 / 4.0;
+                let indent = if i % 2 == 0 { 0.0 } else { fsize / 8.0 };
+                imageproc::drawing::draw_line_segment_mut(
+                    imgbuf, (fx + indent, line_y), (fx + fsize - indent, line_y), styles.color_black);
+            }
+        },
+    }
+
+    return Ok(());
+}
+
+// Four days across the region: day labels and highs on top, then a chart of
+// hourly precipitation chances that fills the rest of the height.
+fn draw_daily_forecast(imgbuf: &mut image::RgbImage, styles: &Styles, region: &layout::Region, now: i64, weather_display: &weather::WeatherDisplay) -> result::TTDashResult<()> {
     use chrono::Datelike;
 
-    let precip_bar_max_height = 50;
+    let days = 4;
+    let day_gap = region.scaled(5);
+    // The chart has a raindrop to its left, and stays clear of the region's
+    // bottom edge.
+    let (header_rect, rest) = region.rect.split_top(region.scaled(75));
+    let (rest, _bottom_margin) = rest.split_top(rest.height - region.scaled(19));
+    let (raindrop_rect, chart_rect) = rest.split_left(region.scaled(10));
+
+    let precip_bar_max_height = std::cmp::max(10, chart_rect.height);
 
-    let hour_width: u32 = 2;
-    let day_width: u32 = 24 * hour_width + 5;
+    let hour_width: u32 = std::cmp::max(1, (chart_rect.width - days * day_gap) / (days * 24)) as u32;
+    let day_width: u32 = 24 * hour_width + day_gap as u32;
 
     let day_labels = vec!["S", "M", "T", "W", "R", "F", "S"];
     let first_entry = weather_display.days.iter().nth(0).ok_or(
         result::make_error("missing first entry"))?;
     let first_date = first_entry.0;
 
-    let left_offset = 10; // Space for raindrop
-
-    for (date, info) in weather_display.days.iter().take(4) {
+    for (date, info) in weather_display.days.iter().take(days as usize) {
         let day_count = date.num_days_from_ce() - first_date.num_days_from_ce();
         let day_label = day_labels.get(date.weekday().num_days_from_sunday() as usize).unwrap_or(&"?").to_string();
 
-        // Day Label (SMTWRFS)
-        imageproc::drawing::draw_text_mut(
-            imgbuf, styles.color_black,
-            /* x= */ (left_x + left_offset) as i32 + day_count as i32 * day_width as i32 + (8 * hour_width) as i32,
-            /* y= */ (top_y) as i32,
-            scale(40.0), &styles.font_bold, &day_label);
+        // Day label (SMTWRFS) and condition icon on top, the high below,
+        // each shrinking to fit the day's column.
+        let day_rect = layout::Rect::new(
+            chart_rect.x + day_count * day_width as i32, header_rect.y, day_width as i32 - day_gap, header_rect.height);
+        let (label_row, high_rect) = day_rect.split_top(region.scaled(30));
+        let (label_rect, icon_rect) = label_row.split_left(18 * hour_width as i32);
+
+        layout::draw_text(imgbuf, styles.color_black, &label_rect, layout::HAlign::Center, layout::VAlign::Center,
+                          40.0 * region.scale, &styles.font_bold, &day_label);
+
+        let icon_size = std::cmp::min(region.scaled(16), icon_rect.width);
+        match info.condition {
+            Some(ref condition) => draw_condition_icon(
+                condition,
+                /* x= */ icon_rect.x,
+                /* y= */ icon_rect.y + (icon_rect.height - icon_size) / 2,
+                /* size= */ icon_size,
+                imgbuf, styles)?,
+            None => {},
+        }
 
-        // High temperature
-        imageproc::drawing::draw_text_mut(
-            imgbuf, styles.color_black,
-            /* x = */ (left_x + left_offset + day_count * day_width as i32 + (4 * hour_width as i32)) as i32,
-            /* y = */ (top_y + 30) as i32,
-            scale(45.0), &styles.font, &format!("{:.0}", info.max_t));
+        layout::draw_text(imgbuf, styles.color_black, &high_rect, layout::HAlign::Center, layout::VAlign::Center,
+                          45.0 * region.scale, &styles.font, &format!("{:.0}", info.max_t));
 
         // Precip bars
-        let precip_bar_top = top_y + 75;
-        let precip_bar_width = (4 * day_width) as i32;
+        let precip_bar_top = chart_rect.y;
+        let precip_bar_width = days * day_width as i32;
 
-        draw_raindrop(left_x, top_y + 90, 25, imgbuf, styles)?;
+        let raindrop_height = std::cmp::min(region.scaled(25), raindrop_rect.height);
+        draw_raindrop(raindrop_rect.x, raindrop_rect.y + (raindrop_rect.height - raindrop_height) / 2, raindrop_height, imgbuf, styles)?;
         imageproc::drawing::draw_line_segment_mut(
             imgbuf,
-            ((left_x + left_offset) as f32, precip_bar_top as f32),
-            ((left_x + left_offset + precip_bar_width) as f32, precip_bar_top as f32),
+            (chart_rect.x as f32, precip_bar_top as f32),
+            ((chart_rect.x + precip_bar_width) as f32, precip_bar_top as f32),
             styles.color_black);
         imageproc::drawing::draw_line_segment_mut(
             imgbuf,
-            ((left_x + left_offset) as f32, (precip_bar_top + precip_bar_max_height) as f32),
-            ((left_x + left_offset + precip_bar_width) as f32, (precip_bar_top + precip_bar_max_height) as f32),
+            (chart_rect.x as f32, (precip_bar_top + precip_bar_max_height) as f32),
+            ((chart_rect.x + precip_bar_width) as f32, (precip_bar_top + precip_bar_max_height) as f32),
             styles.color_black);
 
+        let column_x = chart_rect.x + day_count * day_width as i32;
+        let hours_in_day = weather::hours_in_day(date.naive_local(), date.timezone());
+        let hour_rect = |hour: i32, y: i32, height: u32| {
+            let x = column_x + hour_offset(hour as f32, hours_in_day, hour_width);
+            let next_x = column_x + hour_offset((hour + 1) as f32, hours_in_day, hour_width);
+            return imageproc::rect::Rect::at(x, y).of_size(std::cmp::max(1, next_x - x) as u32, height);
+        };
+
+        // Shade hours between sunset and sunrise
+        match (info.sunrise, info.sunset) {
+            (Some(sunrise), Some(sunset)) => {
+                let sunrise_hour = weather::hours_since_midnight(&sunrise);
+                let sunset_hour = weather::hours_since_midnight(&sunset);
+                for hour in 0..hours_in_day {
+                    if (hour + 1) as f32 > sunrise_hour && (hour as f32) < sunset_hour {
+                        continue;
+                    }
+                    imageproc::drawing::draw_filled_rect_mut(
+                        imgbuf,
+                        hour_rect(hour, precip_bar_top + 1, (precip_bar_max_height - 1) as u32),
+                        styles.color_light_gray);
+                }
+            },
+            _ => {},
+        }
+
         for (hour, precip_prob) in &info.precip_by_hour {
             let bar_height = std::cmp::max(1, (precip_bar_max_height as f32 * (*precip_prob / 100.0)) as u32);
 
             imageproc::drawing::draw_filled_rect_mut(
                 imgbuf,
-                imageproc::rect::Rect::at(
-                    /* x= */ left_x + left_offset + day_count as i32 * day_width as i32 + *hour as i32 * hour_width as i32,
-                    /* y= */ precip_bar_top + precip_bar_max_height - bar_height as i32)
-                    .of_size(hour_width, bar_height),
+                hour_rect(*hour as i32, precip_bar_top + precip_bar_max_height - bar_height as i32, bar_height),
                 styles.color_black);
         }
     }
 
+    draw_temperature_sparkline(
+        chart_rect.x, chart_rect.y, precip_bar_max_height,
+        hour_width as i32, day_width as i32, region, now, imgbuf, styles, weather_display)?;
+
     return Ok(());
 }
 
-fn draw_weather(imgbuf: &mut image::GrayImage, styles: &Styles, weather_display: &weather::WeatherDisplay) -> result::TTDashResult<()> {
-    let left_x: i32 = 400;
-    let top_y: i32 = 00;
+// How far into a day's column of 24 hour widths `hour` (since midnight)
+// falls. The 23 or 25 hours of DST transition days are squeezed or
+// stretched to fill the column exactly.
+fn hour_offset(hour: f32, hours_in_day: i32, hour_width: u32) -> i32 {
+    return (hour * (24 * hour_width) as f32 / hours_in_day as f32).round() as i32;
+}
+
+// Draws the next 48 hours of temperatures as a line over the precip chart,
+// sharing its hour axis, with the min and max points labelled.
+fn draw_temperature_sparkline(left_x: i32, top_y: i32, height: i32, hour_width: i32, day_width: i32, region: &layout::Region, now: i64, imgbuf: &mut image::RgbImage, styles: &Styles, weather_display: &weather::WeatherDisplay) -> result::TTDashResult<()> {
+    use chrono::Datelike;
+
+    let vertical_padding = region.scaled(4);
+    let hours_to_draw = 48;
+
+    let temps: Vec<(&chrono::DateTime<chrono_tz::Tz>, &f32)> =
+        weather_display.upcoming_temps(now).take(hours_to_draw).collect();
+    let first_date = weather_display.days.keys().nth(0).ok_or(
+        result::make_error("missing first entry"))?;
+
+    let min_t = temps.iter().map(|(_, t)| **t).fold(std::f32::INFINITY, f32::min);
+    let max_t = temps.iter().map(|(_, t)| **t).fold(std::f32::NEG_INFINITY, f32::max);
+    let range_t = if max_t > min_t { max_t - min_t } else { 1.0 };
+
+    let points: Vec<(f32, f32)> = temps.iter().map(|(time, t)| {
+        let day_count = time.date_naive().num_days_from_ce() - first_date.num_days_from_ce();
+        let hours_in_day = weather::hours_in_day(time.date_naive(), time.timezone());
+        let x = left_x + day_count * day_width +
+            hour_offset(weather::hours_since_midnight(time) + 0.5, hours_in_day, hour_width as u32);
+        let y = (top_y + height - vertical_padding) as f32 -
+            ((**t - min_t) / range_t) * (height - 2 * vertical_padding) as f32;
+        return (x as f32, y);
+    }).collect();
+
+    for segment in points.windows(2) {
+        imageproc::drawing::draw_line_segment_mut(
+            imgbuf, segment[0], segment[1], styles.color_dark_gray);
+    }
+
+    let min_index = temps.iter().position(|(_, t)| **t == min_t);
+    let max_index = temps.iter().position(|(_, t)| **t == max_t);
+    for (index, label_y_offset) in vec![(max_index, -region.scaled(16)), (min_index, 0)] {
+        if let Some(index) = index {
+            let (x, y) = points[index];
+            imageproc::drawing::draw_filled_circle_mut(
+                imgbuf, (x as i32, y as i32), 2, styles.color_black);
+            imageproc::drawing::draw_text_mut(
+                imgbuf, styles.color_black,
+                /* x= */ x as i32 + region.scaled(3), /* y= */ y as i32 + label_y_offset,
+                scale(16.0 * region.scale), &styles.font_bold, &format!("{:.0}", temps[index].1));
+        }
+    }
 
-    draw_daily_forecast(left_x, top_y + 240, imgbuf, styles, weather_display)?;
+    return Ok(());
+}
+
+// The next day hour by hour, starting with the current one: temperatures as
+// a line, labelled every few hours, over bars for the chance of rain.
+fn draw_hourly_forecast(imgbuf: &mut image::RgbImage, styles: &Styles, region: &layout::Region, now: i64, weather_display: &weather::WeatherDisplay) {
+    let hours = 24;
+    let label_every = 3;
+
+    let temps: Vec<(&chrono::DateTime<chrono_tz::Tz>, f32)> =
+        weather_display.upcoming_temps(now).take(hours).map(|(time, t)| (time, *t)).collect();
+    if temps.is_empty() {
+        return;
+    }
+
+    let (chart_rect, hour_labels_rect) = region.rect.split_top(region.rect.height - region.scaled(24));
+    let (temp_labels_rect, line_rect) = chart_rect.split_top(region.scaled(24));
+    let hour_width = chart_rect.width as f32 / hours as f32;
+    let chart_bottom = chart_rect.bottom() as f32;
+
+    let min_t = temps.iter().map(|(_, t)| *t).fold(std::f32::INFINITY, f32::min);
+    let max_t = temps.iter().map(|(_, t)| *t).fold(std::f32::NEG_INFINITY, f32::max);
+    let range_t = if max_t > min_t { max_t - min_t } else { 1.0 };
+    let line_padding = region.scaled(4) as f32;
+
+    let points: Vec<(f32, f32)> = temps.iter().enumerate().map(|(hour, (_, t))| {
+        let x = chart_rect.x as f32 + (hour as f32 + 0.5) * hour_width;
+        let y = chart_bottom - line_padding - (t - min_t) / range_t * (line_rect.height as f32 - 2.0 * line_padding);
+        return (x, y);
+    }).collect();
+
+    for (hour, (time, _)) in temps.iter().enumerate() {
+        let precip = weather_display.days.iter()
+            .find(|(date, _)| date.naive_local() == time.date_naive())
+            .and_then(|(_, day)| day.precip_by_hour.get(&(weather::hours_since_midnight(time) as u32)));
+        if let Some(precip) = precip {
+            let bar_height = (line_rect.height as f32 * precip / 100.0).round() as i32;
+            if bar_height > 0 {
+                imageproc::drawing::draw_filled_rect_mut(
+                    imgbuf,
+                    layout::Rect::new(
+                        (chart_rect.x as f32 + hour as f32 * hour_width) as i32, chart_rect.bottom() - bar_height,
+                        std::cmp::max(1, hour_width as i32 - 1), bar_height).to_imageproc(),
+                    styles.color_light_gray);
+            }
+        }
+    }
+
+    imageproc::drawing::draw_line_segment_mut(
+        imgbuf, (chart_rect.x as f32, chart_bottom), (chart_rect.right() as f32, chart_bottom), styles.color_black);
+    for segment in points.windows(2) {
+        imageproc::drawing::draw_line_segment_mut(imgbuf, segment[0], segment[1], styles.color_black);
+    }
+
+    for (hour, ((time, t), (x, y))) in temps.iter().zip(points.iter()).enumerate().step_by(label_every) {
+        imageproc::drawing::draw_filled_circle_mut(imgbuf, (*x as i32, *y as i32), region.scaled(3), styles.color_black);
+
+        let label_width = (label_every as f32 * hour_width) as i32;
+        let label_x = *x as i32 - label_width / 2;
+        let temp_rect = layout::Rect::new(label_x, temp_labels_rect.y, label_width, (*y as i32) - temp_labels_rect.y - region.scaled(4));
+        layout::draw_text(imgbuf, styles.color_black, &temp_rect, layout::HAlign::Center, layout::VAlign::Bottom,
+                          22.0 * region.scale, &styles.font_bold, &format!("{:.0}°", t));
+
+        let hour_rect = layout::Rect::new(label_x, hour_labels_rect.y, label_width, hour_labels_rect.height);
+        let hour_label = if hour == 0 { "Now".to_string() } else { time.format("%-I%P").to_string() };
+        layout::draw_text(imgbuf, styles.color_black, &hour_rect, layout::HAlign::Center, layout::VAlign::Center,
+                          20.0 * region.scale, &styles.font, &hour_label);
+    }
+}
 
+// Current temperature, today's low and high, then dew point and sun times.
+fn draw_current_weather(imgbuf: &mut image::RgbImage, styles: &Styles, region: &layout::Region, weather_display: &weather::WeatherDisplay) -> result::TTDashResult<()> {
     let first_entry = weather_display.days.iter().nth(0).ok_or(
         result::make_error("missing first entry"))?;
     let first_info = first_entry.1;
 
-    imageproc::drawing::draw_text_mut(
-        imgbuf, styles.color_black,
-        /* x= */ (left_x + 40) as i32, /* y= */ top_y as i32,
-        scale(140.0),
-        &styles.font_black, &format!("{:.0}°", weather_display.current_t));
+    let (current_rect, rest) = region.rect.split_top(region.rect.height * 45 / 100);
+    let (min_max_rect, details_rect) = rest.split_top(region.rect.height * 32 / 100);
 
-    imageproc::drawing::draw_text_mut(
-        imgbuf, styles.color_black,
-        /* x= */ left_x as i32,
-        /* y= */ (top_y + 110) as i32,
-        scale(80.0), &styles.font_bold,
-        &format!("{}° / {}°", first_info.min_t, first_info.max_t));
+    layout::draw_text(imgbuf, styles.color_black, &current_rect,
+                      region.halign.unwrap_or(layout::HAlign::Center), layout::VAlign::Center,
+                      140.0 * region.scale, &styles.font_black, &format!("{:.0}°", weather_display.current_t));
+
+    layout::draw_text(imgbuf, styles.color_black, &min_max_rect,
+                      region.halign.unwrap_or(layout::HAlign::Center), layout::VAlign::Center,
+                      80.0 * region.scale, &styles.font_bold, &format!("{}° / {}°", first_info.min_t, first_info.max_t));
+
+    let left_x = details_rect.x;
+    let dp_box_width = region.scaled(20);
+    let dp_box_height = region.scaled(15);
+    let dp_box_gap = region.scaled(6);
+    let top_y = details_rect.y + (details_rect.height - dp_box_height) / 2;
 
     // Dew point ranges:
     // [0]  < 55: Pleasant
@@ -295,15 +708,14 @@ fn draw_weather(imgbuf: &mut image::GrayImage, styles: &Styles, weather_display:
     // [3] 66-70: Uncomfortable
     // [4] 71-75: Oppressive
     // [5]  > 76: Miserable
-    let dew_point = first_info.max_dew_point as i32;
-    let dew_point_bucket =
-        std::cmp::min(5, std::cmp::max(0, (dew_point - 50) / 5));
+    // Unknown dew point draws all boxes empty.
+    let dew_point_bucket = match first_info.max_dew_point {
+        Some(dew_point) => std::cmp::min(5, std::cmp::max(0, (dew_point as i32 - 50) / 5)),
+        None => 0,
+    };
 
-    let dp_box_width = 35;
-    let dp_box_height = 15;
-    let dp_box_gap = 10;
     for i in 0..5 as i32 {
-        let rect = imageproc::rect::Rect::at(left_x + i * (dp_box_width + dp_box_gap), top_y + 200).of_size(dp_box_width as u32, dp_box_height as u32);
+        let rect = imageproc::rect::Rect::at(left_x + i * (dp_box_width + dp_box_gap), top_y).of_size(dp_box_width as u32, dp_box_height as u32);
         if i < dew_point_bucket {
             imageproc::drawing::draw_filled_rect_mut(imgbuf, rect, styles.color_black);
         } else {
@@ -311,28 +723,179 @@ fn draw_weather(imgbuf: &mut image::GrayImage, styles: &Styles, weather_display:
         }
     }
 
+    draw_sun_times(left_x + 5 * (dp_box_width + dp_box_gap), top_y - region.scaled(6), region, imgbuf, styles, first_info);
+
     return Ok(());
 }
 
-fn draw_air_quality(imgbuf: &mut image::GrayImage, styles: &Styles, air_quality: &purpleair::AirQuality) -> result::TTDashResult<()> {
-    let left_x: i32 = 250;
-    let top_y: i32 = 0;
+fn draw_sun_times(left_x: i32, top_y: i32, region: &layout::Region, imgbuf: &mut image::RgbImage, styles: &Styles, day: &weather::WeatherDisplayDay) {
+    let column_width = region.scaled(58);
+    let columns = vec![
+        // Sunrise: up arrow
+        (day.sunrise, [(8, 4), (14, 14), (2, 14)]),
+        // Sunset: down arrow
+        (day.sunset, [(8, 14), (14, 4), (2, 4)]),
+    ];
+
+    for (i, (time, arrow)) in columns.iter().enumerate() {
+        let x = left_x + i as i32 * column_width;
+        imageproc::drawing::draw_polygon_mut(
+            imgbuf,
+            &arrow.iter().map(|(dx, dy)| imageproc::point::Point::new(x + region.scaled(*dx), top_y + region.scaled(*dy))).collect::<Vec<_>>(),
+            styles.color_black);
+        let text = time.map(|t| t.format("%-I:%M").to_string()).unwrap_or("--".to_string());
+        imageproc::drawing::draw_text_mut(
+            imgbuf, styles.color_black,
+            /* x= */ x + region.scaled(16), /* y= */ top_y,
+            scale(22.0 * region.scale), &styles.font, &text);
+    }
+}
+
+// The category bar and trend are for outdoor air when there is any; pm2.5
+// goes in/out like the AQI. Outdoor readings are followed by how many sensors
+// they're averaged from.
+fn draw_air_quality(imgbuf: &mut image::RgbImage, styles: &Styles, region: &layout::Region, air_quality: Option<&purpleair::AirQuality>, indoor_air_quality: Option<&indoor::IndoorAirQuality>) -> result::TTDashResult<()> {
+    let (title, pm25, category) = match (indoor_air_quality, air_quality) {
+        (Some(indoor), Some(outdoor)) => (
+            format!("AQI {}/{} in/out ({})", indoor.aqi.value, outdoor.aqi.value, outdoor.sensor_count),
+            format!("{:.1}/{:.1}", indoor.pm25_ugm3, outdoor.smoothed_pm25_ugm3()),
+            outdoor.aqi.category),
+        (None, Some(outdoor)) => (
+            format!("AQI {} {} ({})", outdoor.aqi.value, outdoor.aqi.category.label(), outdoor.sensor_count),
+            format!("{:.1}", outdoor.smoothed_pm25_ugm3()),
+            outdoor.aqi.category),
+        (Some(indoor), None) => (
+            format!("AQI {} in", indoor.aqi.value), format!("{:.1}", indoor.pm25_ugm3), indoor.aqi.category),
+        (None, None) => return Ok(()),
+    };
+
+    let (arrow_rect, rest) = region.rect.split_left(region.scaled(18));
+    let (title_rect, bar_rect) = rest.split_top(rest.height * 55 / 100);
+
+    let color = if category >= aqi::Category::Unhealthy { styles.color_accent } else { styles.color_black };
+    layout::draw_text(imgbuf, color, &title_rect, layout::HAlign::Left, layout::VAlign::Center,
+                      24.0 * region.scale, &styles.font_black, &title);
+
+    // One box per EPA category, filled up to and including the current one.
+    let box_width = region.scaled(16);
+    let box_height = region.scaled(10);
+    let box_gap = region.scaled(3);
+    let box_y = bar_rect.y + (bar_rect.height - box_height) / 2;
+    for (i, box_category) in aqi::Category::ALL.iter().enumerate() {
+        let rect = imageproc::rect::Rect::at(bar_rect.x + i as i32 * (box_width + box_gap), box_y).of_size(box_width as u32, box_height as u32);
+        if *box_category <= category {
+            imageproc::drawing::draw_filled_rect_mut(imgbuf, rect, color);
+        } else {
+            imageproc::drawing::draw_hollow_rect_mut(imgbuf, rect, styles.color_black);
+        }
+    }
+
+    let (_, pm_rect) = bar_rect.split_left(aqi::Category::ALL.len() as i32 * (box_width + box_gap) + region.scaled(4));
+    layout::draw_text(imgbuf, styles.color_black, &pm_rect, layout::HAlign::Left, layout::VAlign::Center,
+                      16.0 * region.scale, &styles.font, &pm25);
 
-    imageproc::drawing::draw_text_mut(
-        imgbuf, styles.color_black,
-        /* x= */ left_x as i32, /* y= */ top_y as i32,
-        scale(40.0),
-        &styles.font_black, &format!("pm={:.1}", air_quality.raw_pm25_ugm3));
+    if let Some(air_quality) = air_quality {
+        draw_trend_arrow(arrow_rect.x, title_rect.y + title_rect.height / 2 - region.scaled(6), region, imgbuf, styles, air_quality.trend);
+    }
 
     return Ok(());
 }
 
+fn draw_trend_arrow(left_x: i32, top_y: i32, region: &layout::Region, imgbuf: &mut image::RgbImage, styles: &Styles, trend: purpleair::Trend) {
+    let arrow = match trend {
+        purpleair::Trend::Rising => [(7, 0), (14, 12), (0, 12)],
+        purpleair::Trend::Falling => [(7, 12), (14, 0), (0, 0)],
+        purpleair::Trend::Steady => return,
+    };
+    imageproc::drawing::draw_polygon_mut(
+        imgbuf,
+        &arrow.iter().map(|(dx, dy)| imageproc::point::Point::new(left_x + region.scaled(*dx), top_y + region.scaled(*dy))).collect::<Vec<_>>(),
+        styles.color_black);
+}
+
 fn scale(s: f32) -> rusttype::Scale {
     return rusttype::Scale{x: s, y: s};
 }
 
-fn draw_subway_line_emblem(imgbuf: &mut image::GrayImage, letter: &str, x: u32, y: u32, radius: u32, styles: &Styles) {
+fn draw_subway_line_emblem(imgbuf: &mut image::RgbImage, letter: &str, x: u32, y: u32, radius: u32, styles: &Styles) {
     imageproc::drawing::draw_filled_circle_mut(imgbuf, (x as i32, y as i32), (radius + 2)as i32, styles.color_white);
     imageproc::drawing::draw_filled_circle_mut(imgbuf, (x as i32, y as i32), radius as i32, styles.color_black);
     imageproc::drawing::draw_text_mut(imgbuf, styles.color_white, (x - (radius / 2) + 2) as i32, (y - radius) as i32, scale((radius * 2) as f32), &styles.font_bold, letter);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::layout;
+    use super::weather;
+
+    fn styles() -> super::Styles<'static> {
+        let font = || rusttype::Font::try_from_bytes(include_bytes!("/usr/share/fonts/truetype/roboto/unhinted/RobotoCondensed-Regular.ttf")).unwrap();
+        let bold = || rusttype::Font::try_from_bytes(include_bytes!("/usr/share/fonts/truetype/roboto/unhinted/RobotoCondensed-Bold.ttf")).unwrap();
+        return super::Styles{
+            font: font(),
+            font_bold: bold(),
+            font_black: bold(),
+            color_black: image::Rgb([0, 0, 0]),
+            color_light_gray: image::Rgb([192, 192, 192]),
+            color_dark_gray: image::Rgb([128, 128, 128]),
+            color_white: image::Rgb([255, 255, 255]),
+            color_accent: image::Rgb([255, 0, 0]),
+        };
+    }
+
+    #[test]
+    fn daily_forecast_fall_back_stays_in_its_column() {
+        use chrono::TimeZone;
+        let tz = chrono_tz::US::Eastern;
+        let at = |ts: i64| tz.timestamp_opt(ts, 0).unwrap();
+
+        // Midnight EDT, 2019-11-03, which has 25 hours. Rain all day.
+        let midnight = 1572753600;
+        let mut hourly_temps = std::collections::BTreeMap::new();
+        let mut precip_by_hour = std::collections::BTreeMap::new();
+        for hour in 0..25 {
+            let t = if hour <= 12 { 50 + hour } else { 62 - (hour - 12) / 2 };
+            hourly_temps.insert(at(midnight + hour as i64 * 3600), t as f32);
+            precip_by_hour.insert(hour as u32, 100.0);
+        }
+        let mut days = std::collections::BTreeMap::new();
+        days.insert(at(midnight).date(), weather::WeatherDisplayDay{
+            min_t: 50.0,
+            max_t: 62.0,
+            max_dew_point: None,
+            condition: None,
+            short_forecast: None,
+            sunrise: Some(at(1572780600)),  // 06:30 EST
+            sunset: Some(at(1572817800)),   // 16:50 EST
+            precip_by_hour: precip_by_hour,
+        });
+        let weather_display = weather::WeatherDisplay{
+            overall_min_t: 50.0,
+            overall_max_t: 62.0,
+            current_t: 50.0,
+            hourly_temps: hourly_temps,
+            days: days,
+        };
+
+        // The chart is x 10..414 and y 75..181, so each of the four days
+        // gets 24 hours 4 pixels wide, then a 5 pixel gap.
+        let region = layout::Region{
+            section: layout::Section::DailyForecast,
+            rect: layout::Rect::new(0, 0, 414, 200),
+            halign: None,
+            valign: None,
+            scale: 1.0,
+        };
+        let mut imgbuf = image::RgbImage::from_pixel(414, 200, image::Rgb([255, 255, 255]));
+        super::draw_daily_forecast(&mut imgbuf, &styles(), &region, midnight, &weather_display).unwrap();
+
+        // The last hour is still drawn, at the end of the day's column.
+        assert_eq!(image::Rgb([0, 0, 0]), *imgbuf.get_pixel(105, 180));
+        // Nothing between the chart's top and bottom lines past the column.
+        for x in 106..414 {
+            for y in 76..181 {
+                assert_eq!(image::Rgb([255, 255, 255]), *imgbuf.get_pixel(x, y), "drawn at {}, {}", x, y);
+            }
+        }
+    }
+}
@@ -0,0 +1,278 @@
+// Indoor air quality, either from a particulate sensor wired to the Pi's
+// serial port or from a PurpleAir sensor's local /json endpoint.
+extern crate reqwest;
+extern crate rppal;
+extern crate serde;
+extern crate serde_json;
+extern crate std;
+
+use crate::aqi;
+use crate::purpleair;
+use crate::result;
+
+// Both sensors talk 9600 8N1 and send a frame about once a second.
+const BAUD_RATE: u32 = 9600;
+const SERIAL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+
+const PMS5003_FRAME_LENGTH: usize = 32;
+const SDS011_FRAME_LENGTH: usize = 10;
+
+#[derive(Debug)]
+pub struct IndoorAirQuality {
+    pub pm25_ugm3: f32,
+    pub aqi: aqi::Aqi,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SensorKind {
+    Pms5003,
+    Sds011,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Serial(SensorKind, String),
+    // URL of a local PurpleAir sensor's /json endpoint.
+    Http(String),
+}
+
+// "pms5003:/dev/ttyAMA0", "sds011:/dev/ttyUSB0" or "http://10.0.0.5/json".
+impl std::str::FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Source, String> {
+        if s.starts_with("http://") || s.starts_with("https://") {
+            return Ok(Source::Http(s.to_string()));
+        }
+        let mut parts = s.splitn(2, ':');
+        let kind = match parts.next().unwrap_or("").to_lowercase().as_ref() {
+            "pms5003" => SensorKind::Pms5003,
+            "sds011" => SensorKind::Sds011,
+            _ => return Err(format!("Unknown indoor air quality source '{}'", s)),
+        };
+        return match parts.next() {
+            Some(path) if !path.is_empty() => Ok(Source::Serial(kind, path.to_string())),
+            _ => Err(format!("Missing serial device in '{}'", s)),
+        };
+    }
+}
+
+pub fn get_indoor_air_quality(source: &Source) -> result::TTDashResult<IndoorAirQuality> {
+    let pm25_ugm3 = match source {
+        Source::Serial(kind, path) => read_serial(*kind, path)?,
+        Source::Http(url) => get_local_sensor_pm25(url, real_fetch_json_fn)?,
+    };
+    return Ok(IndoorAirQuality{
+        pm25_ugm3: pm25_ugm3,
+        aqi: aqi::from_pm25(pm25_ugm3),
+    });
+}
+
+fn read_serial(kind: SensorKind, path: &str) -> result::TTDashResult<f32> {
+    let mut uart = rppal::uart::Uart::with_path(path, BAUD_RATE, rppal::uart::Parity::None, 8, 1)?;
+    uart.set_read_mode(0, std::time::Duration::from_millis(200))?;
+    return read_stream(kind, |buffer| Ok(uart.read(buffer)?));
+}
+
+// Reads until the stream has a complete, valid frame, and returns the latest
+// reading. Bytes from before the frame header are skipped, so it's fine to
+// start reading halfway through a frame.
+fn read_stream<F>(kind: SensorKind, mut read_fn: F) -> result::TTDashResult<f32>
+where F: FnMut(&mut [u8]) -> result::TTDashResult<usize> {
+    let start = std::time::Instant::now();
+    let mut stream = vec![];
+    let mut buffer = [0u8; 64];
+    while start.elapsed() < SERIAL_TIMEOUT {
+        let count = read_fn(&mut buffer)?;
+        stream.extend_from_slice(&buffer[..count]);
+        if let Some(pm25_ugm3) = parse_stream(kind, &stream) {
+            return Ok(pm25_ugm3);
+        }
+    }
+    return Err(result::make_error(&format!("No valid {:?} frame after {:?}", kind, SERIAL_TIMEOUT)));
+}
+
+// Returns pm2.5 from the last valid frame in `stream`.
+pub fn parse_stream(kind: SensorKind, stream: &[u8]) -> Option<f32> {
+    let (frame_length, parse_fn): (usize, fn(&[u8]) -> Option<f32>) = match kind {
+        SensorKind::Pms5003 => (PMS5003_FRAME_LENGTH, parse_pms5003_frame),
+        SensorKind::Sds011 => (SDS011_FRAME_LENGTH, parse_sds011_frame),
+    };
+    if stream.len() < frame_length {
+        return None;
+    }
+    return (0..=stream.len() - frame_length).rev()
+        .filter_map(|i| parse_fn(&stream[i..i + frame_length]))
+        .nth(0);
+}
+
+// 0x42 0x4d, frame length (28), 13 big-endian u16 data words and a u16 sum of
+// all preceding bytes. The fifth word is pm2.5 under atmospheric conditions.
+// https://www.aqmd.gov/docs/default-source/aq-spec/resources-page/plantower-pms5003-manual_v2-3.pdf
+fn parse_pms5003_frame(frame: &[u8]) -> Option<f32> {
+    let word = |i: usize| (frame[i] as u16) << 8 | frame[i + 1] as u16;
+
+    if frame[0] != 0x42 || frame[1] != 0x4d || word(2) != 28 {
+        return None;
+    }
+    let checksum = frame[..30].iter().map(|b| *b as u16).fold(0u16, |a, b| a.wrapping_add(b));
+    if checksum != word(30) {
+        return None;
+    }
+    return Some(word(4 + 2 * 4) as f32);
+}
+
+// 0xaa 0xc0, little-endian pm2.5 and pm10 in tenths of ug/m3, two ID bytes,
+// an 8 bit sum of the data bytes and 0xab.
+// https://cdn-reichelt.de/documents/datenblatt/X200/SDS011-DATASHEET.pdf
+fn parse_sds011_frame(frame: &[u8]) -> Option<f32> {
+    if frame[0] != 0xaa || frame[1] != 0xc0 || frame[9] != 0xab {
+        return None;
+    }
+    let checksum = frame[2..8].iter().fold(0u8, |a, b| a.wrapping_add(*b));
+    if checksum != frame[8] {
+        return None;
+    }
+    return Some(((frame[3] as u16) << 8 | frame[2] as u16) as f32 / 10.0);
+}
+
+fn get_local_sensor_pm25(url: &str, fetch_json_fn: fn(&str) -> result::TTDashResult<String>) -> result::TTDashResult<f32> {
+    let raw_json = fetch_json_fn(url)?;
+    let response: LocalSensorResponse = serde_json::from_str(&raw_json)?;
+
+    purpleair::validate_channels(Some(response.pm25_atm_ugm3), response.pm25_atm_b_ugm3)?;
+    return Ok(match response.pm25_atm_b_ugm3 {
+        Some(b) => (response.pm25_atm_ugm3 + b) / 2.0,
+        None => response.pm25_atm_ugm3,
+    });
+}
+
+fn real_fetch_json_fn(url: &str) -> result::TTDashResult<String> {
+    use std::io::Read;
+
+    let client = reqwest::blocking::Client::new();
+    let mut response = client.get(url).send()?.error_for_status()?;
+    let mut response_body = String::new();
+    response.read_to_string(&mut response_body)?;
+    return Ok(response_body);
+}
+
+// Single-channel sensors don't report the _b fields.
+#[derive(Serialize, Deserialize)]
+struct LocalSensorResponse {
+    #[serde(rename = "pm2_5_atm")]
+    pm25_atm_ugm3: f32,
+
+    #[serde(default, rename = "pm2_5_atm_b")]
+    pm25_atm_b_ugm3: Option<f32>,
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate hex;
+
+    use super::parse_stream;
+    use super::result;
+    use super::SensorKind;
+    use super::Source;
+
+    // Captured frames reading pm2.5 12 and 15.
+    const PMS5003_FRAMES: [&str; 2] = [
+        "424d001c0005000d000f0005000c000f038401040032000600020001000001b3",
+        "424d001c0006001000130006000f001303b60118003c00070002000100000214",
+    ];
+    // Captured frames reading pm2.5 12.3 and 15.0.
+    const SDS011_FRAMES: [&str; 2] = [
+        "aac07b00c90012348aab",
+        "aac09600e0001234bcab",
+    ];
+
+    fn capture(parts: &[&str]) -> Vec<u8> {
+        return hex::decode(parts.concat()).expect("bad hex");
+    }
+
+    #[test]
+    fn parse_pms5003() {
+        assert_eq!(Some(12.0), parse_stream(SensorKind::Pms5003, &capture(&[PMS5003_FRAMES[0]])));
+        // Latest frame wins, and a partial frame at the start is skipped.
+        assert_eq!(Some(15.0), parse_stream(
+            SensorKind::Pms5003, &capture(&["0013001c", PMS5003_FRAMES[0], PMS5003_FRAMES[1], "424d001c00"])));
+        // Bad checksum
+        assert_eq!(None, parse_stream(
+            SensorKind::Pms5003, &capture(&[&PMS5003_FRAMES[0].replace("01b3", "01b4")])));
+        assert_eq!(None, parse_stream(SensorKind::Pms5003, &capture(&["424d001c"])));
+        assert_eq!(None, parse_stream(SensorKind::Sds011, &capture(&[PMS5003_FRAMES[0]])));
+    }
+
+    #[test]
+    fn parse_sds011() {
+        assert_eq!(Some(12.3), parse_stream(SensorKind::Sds011, &capture(&[SDS011_FRAMES[0]])));
+        assert_eq!(Some(15.0), parse_stream(
+            SensorKind::Sds011, &capture(&["c90012", SDS011_FRAMES[0], SDS011_FRAMES[1], "aac0"])));
+        assert_eq!(None, parse_stream(
+            SensorKind::Sds011, &capture(&[&SDS011_FRAMES[0].replace("8aab", "8bab")])));
+    }
+
+    #[test]
+    fn read_stream_in_chunks() {
+        let stream = capture(&["4d001c", PMS5003_FRAMES[0], PMS5003_FRAMES[1]]);
+        let mut position = 0;
+        let pm25 = super::read_stream(SensorKind::Pms5003, |buffer| {
+            // A few bytes at a time, like a slow serial port.
+            let count = std::cmp::min(5, stream.len() - position);
+            buffer[..count].copy_from_slice(&stream[position..position + count]);
+            position += count;
+            return Ok(count);
+        }).expect("read_stream failed");
+
+        // Returns as soon as the first frame is complete.
+        assert_eq!(12.0, pm25);
+        assert!(position < stream.len());
+    }
+
+    #[test]
+    fn read_serial_from_pty() {
+        extern crate nix;
+
+        let master = nix::pty::posix_openpt(nix::fcntl::OFlag::O_RDWR).expect("posix_openpt");
+        nix::pty::grantpt(&master).expect("grantpt");
+        nix::pty::unlockpt(&master).expect("unlockpt");
+        let path = nix::pty::ptsname_r(&master).expect("ptsname");
+
+        let frames = capture(&["0100", SDS011_FRAMES[0], SDS011_FRAMES[1]]);
+        let writer = std::thread::spawn(move || {
+            use std::os::unix::io::AsRawFd;
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            nix::unistd::write(master.as_raw_fd(), &frames).expect("write");
+            // Keep the master open until the reader is done.
+            std::thread::sleep(std::time::Duration::from_millis(500));
+        });
+
+        assert_eq!(15.0, super::read_serial(SensorKind::Sds011, &path).expect("read_serial failed"));
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn local_sensor() {
+        let fake_fetch_fn = |url: &str| -> result::TTDashResult<String> {
+            assert_eq!("http://10.0.0.5/json", url);
+            return Ok(std::fs::read_to_string("testdata/purpleair_local.json").expect("error reading purpleair_local.json"));
+        };
+        let pm25 = super::get_local_sensor_pm25("http://10.0.0.5/json", fake_fetch_fn).expect("local sensor failed");
+        assert!((pm25 - (3.12 + 2.86) / 2.0).abs() < 0.001, "{}", pm25);
+
+        let disagreeing_fetch_fn = |_url: &str| -> result::TTDashResult<String> {
+            return Ok(r#"{"pm2_5_atm": 2.0, "pm2_5_atm_b": 40.0}"#.to_string());
+        };
+        assert!(super::get_local_sensor_pm25("http://10.0.0.5/json", disagreeing_fetch_fn).is_err());
+    }
+
+    #[test]
+    fn parse_source() {
+        assert_eq!(Ok(Source::Serial(SensorKind::Pms5003, "/dev/ttyAMA0".to_string())), "pms5003:/dev/ttyAMA0".parse());
+        assert_eq!(Ok(Source::Serial(SensorKind::Sds011, "/dev/ttyUSB0".to_string())), "SDS011:/dev/ttyUSB0".parse());
+        assert_eq!(Ok(Source::Http("http://10.0.0.5/json".to_string())), "http://10.0.0.5/json".parse());
+        assert!("pms5003:".parse::<Source>().is_err());
+        assert!("bme280:/dev/i2c-1".parse::<Source>().is_err());
+    }
+}
@@ -0,0 +1,570 @@
+// Where things go on the screen. A layout is a tree of rows and columns,
+// loaded from JSON, whose leaves are sections that drawing.rs knows how to
+// draw. Each section only sees the rectangle it was given, so moving things
+// around or targeting a different panel size doesn't need code changes.
+extern crate image;
+extern crate imageproc;
+extern crate rusttype;
+extern crate serde;
+extern crate serde_json;
+extern crate std;
+
+use anyhow::Context;
+use crate::result;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Rect {
+    pub fn new(x: i32, y: i32, width: i32, height: i32) -> Rect {
+        return Rect{x: x, y: y, width: std::cmp::max(0, width), height: std::cmp::max(0, height)};
+    }
+
+    pub fn right(&self) -> i32 {
+        return self.x + self.width;
+    }
+
+    pub fn bottom(&self) -> i32 {
+        return self.y + self.height;
+    }
+
+    pub fn inset(&self, padding: i32) -> Rect {
+        return Rect::new(self.x + padding, self.y + padding, self.width - 2 * padding, self.height - 2 * padding);
+    }
+
+    // (the leftmost `width` pixels, the rest)
+    pub fn split_left(&self, width: i32) -> (Rect, Rect) {
+        let width = std::cmp::min(std::cmp::max(0, width), self.width);
+        return (Rect::new(self.x, self.y, width, self.height),
+                Rect::new(self.x + width, self.y, self.width - width, self.height));
+    }
+
+    // (the top `height` pixels, the rest)
+    pub fn split_top(&self, height: i32) -> (Rect, Rect) {
+        let height = std::cmp::min(std::cmp::max(0, height), self.height);
+        return (Rect::new(self.x, self.y, self.width, height),
+                Rect::new(self.x, self.y + height, self.width, self.height - height));
+    }
+
+    // `count` rows of (nearly) equal height.
+    pub fn rows(&self, count: i32) -> Vec<Rect> {
+        return (0..count).map(|i| {
+            let top = self.y + self.height * i / count;
+            let bottom = self.y + self.height * (i + 1) / count;
+            return Rect::new(self.x, top, self.width, bottom - top);
+        }).collect();
+    }
+
+    pub fn to_imageproc(&self) -> imageproc::rect::Rect {
+        return imageproc::rect::Rect::at(self.x, self.y)
+            .of_size(std::cmp::max(1, self.width) as u32, std::cmp::max(1, self.height) as u32);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HAlign {
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VAlign {
+    Top,
+    Center,
+    Bottom,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Section {
+    Title,
+    Divider,
+    AirQuality,
+    BigCountdown,
+    UpcomingTrains,
+    OutboundTrains,
+    Bus,
+    Alerts,
+    CurrentWeather,
+    DailyForecast,
+    HourlyForecast,
+    Version,
+}
+
+// One node of the tree: a section, a row or a column. A node with none of
+// those is empty space.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Node {
+    // Fixed size along the parent's axis. Nodes without one share what's
+    // left over in proportion to their weight.
+    #[serde(default)]
+    pub size: Option<i32>,
+    #[serde(default = "default_weight")]
+    pub weight: f32,
+    #[serde(default)]
+    pub padding: i32,
+    // How sections place their main content; each section has its own default.
+    #[serde(default)]
+    pub halign: Option<HAlign>,
+    #[serde(default)]
+    pub valign: Option<VAlign>,
+
+    #[serde(default)]
+    pub section: Option<Section>,
+    #[serde(default)]
+    pub row: Option<Vec<Node>>,
+    #[serde(default)]
+    pub column: Option<Vec<Node>>,
+}
+
+fn default_weight() -> f32 {
+    return 1.0;
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Layout {
+    pub width: u32,
+    pub height: u32,
+    // Multiplies the fixed sizes sections draw at, like font sizes and icons,
+    // so a layout for a smaller panel can shrink everything at once.
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+    pub root: Node,
+}
+
+fn default_scale() -> f32 {
+    return 1.0;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub section: Section,
+    pub rect: Rect,
+    pub halign: Option<HAlign>,
+    pub valign: Option<VAlign>,
+    pub scale: f32,
+}
+
+impl Region {
+    // A size in pixels at this region's scale.
+    pub fn scaled(&self, size: i32) -> i32 {
+        return (size as f32 * self.scale).round() as i32;
+    }
+}
+
+impl Layout {
+    // The built in layout for drawing at this size, which is the panel's
+    // size turned by any rotation; rotated panels get their own layouts.
+    pub fn builtin(width: u32, height: u32) -> result::TTDashResult<Layout> {
+        let json = match (width, height) {
+            (640, 384) => include_str!("../layouts/640x384.json"),
+            (800, 480) => include_str!("../layouts/800x480.json"),
+            (400, 300) => include_str!("../layouts/400x300.json"),
+            (128, 296) => include_str!("../layouts/128x296.json"),
+            (384, 640) => include_str!("../layouts/384x640.json"),
+            (480, 800) => include_str!("../layouts/480x800.json"),
+            (300, 400) => include_str!("../layouts/300x400.json"),
+            (296, 128) => include_str!("../layouts/296x128.json"),
+            _ => return Err(result::make_error(&format!(
+                "No built in layout for {}x{}, pass one with --layout", width, height))),
+        };
+        return Ok(Layout::from_json(json).expect("built in layout is invalid"));
+    }
+
+    pub fn from_file<P: AsRef<std::path::Path>>(path: P) -> result::TTDashResult<Layout> {
+        let debug_path = path.as_ref().to_str().map(|x| x.to_string());
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading layout from '{:?}'", debug_path))?;
+        return Layout::from_json(&json);
+    }
+
+    pub fn from_json(json: &str) -> result::TTDashResult<Layout> {
+        let layout: Layout = serde_json::from_str(json)
+            .context("while parsing layout")?;
+        if !(layout.scale > 0.0) {
+            return Err(result::make_error(&format!("Layout scale must be positive, not {}", layout.scale)));
+        }
+        validate(&layout.root)?;
+        return Ok(layout);
+    }
+
+    // Every section in the layout, and where to draw it.
+    pub fn regions(&self) -> Vec<Region> {
+        let mut regions = vec![];
+        place(&self.root, Rect::new(0, 0, self.width as i32, self.height as i32), self.scale, &mut regions);
+        return regions;
+    }
+}
+
+// How far to turn the drawn image, clockwise, to suit how the panel is
+// mounted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rotation {
+    Rotate0,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl std::str::FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Rotation, String> {
+        return match s {
+            "0" => Ok(Rotation::Rotate0),
+            "90" => Ok(Rotation::Rotate90),
+            "180" => Ok(Rotation::Rotate180),
+            "270" => Ok(Rotation::Rotate270),
+            _ => Err(format!("Unknown rotation '{}', expected 0, 90, 180 or 270", s)),
+        };
+    }
+}
+
+impl Rotation {
+    // The size to draw at for a width x height panel.
+    pub fn drawing_size(&self, width: u32, height: u32) -> (u32, u32) {
+        return match self {
+            Rotation::Rotate0 | Rotation::Rotate180 => (width, height),
+            Rotation::Rotate90 | Rotation::Rotate270 => (height, width),
+        };
+    }
+}
+
+// The drawn image as it should go to the panel: mirrored left to right if
+// `mirror`, then rotated.
+pub fn orient(imgbuf: &image::RgbImage, rotation: Rotation, mirror: bool) -> image::RgbImage {
+    let mirrored;
+    let imgbuf = if mirror {
+        mirrored = image::imageops::flip_horizontal(imgbuf);
+        &mirrored
+    } else {
+        imgbuf
+    };
+
+    return match rotation {
+        Rotation::Rotate0 => imgbuf.clone(),
+        Rotation::Rotate90 => image::imageops::rotate90(imgbuf),
+        Rotation::Rotate180 => image::imageops::rotate180(imgbuf),
+        Rotation::Rotate270 => image::imageops::rotate270(imgbuf),
+    };
+}
+
+fn validate(node: &Node) -> result::TTDashResult<()> {
+    let kinds = vec![node.section.is_some(), node.row.is_some(), node.column.is_some()];
+    if kinds.iter().filter(|k| **k).count() > 1 {
+        return Err(result::make_error(&format!(
+            "Layout node can only be one of section, row or column: {:?}", node)));
+    }
+    for child in node.row.iter().chain(node.column.iter()).flatten() {
+        validate(child)?;
+    }
+    return Ok(());
+}
+
+fn place(node: &Node, rect: Rect, scale: f32, regions: &mut Vec<Region>) {
+    let rect = rect.inset(node.padding);
+    if let Some(section) = node.section {
+        regions.push(Region{
+            section: section,
+            rect: rect,
+            halign: node.halign,
+            valign: node.valign,
+            scale: scale,
+        });
+    } else if let Some(children) = &node.row {
+        let mut x = rect.x;
+        for (child, width) in children.iter().zip(split(children, rect.width)) {
+            place(child, Rect::new(x, rect.y, width, rect.height), scale, regions);
+            x += width;
+        }
+    } else if let Some(children) = &node.column {
+        let mut y = rect.y;
+        for (child, height) in children.iter().zip(split(children, rect.height)) {
+            place(child, Rect::new(rect.x, y, rect.width, height), scale, regions);
+            y += height;
+        }
+    }
+}
+
+// Sizes along the parent's axis: fixed sizes first, then whatever's left is
+// shared by weight. Rounding leftovers go to the last weighted child so the
+// children always fill the parent exactly.
+fn split(children: &[Node], total: i32) -> Vec<i32> {
+    let fixed: i32 = children.iter().filter_map(|c| c.size).sum();
+    let remaining = std::cmp::max(0, total - fixed);
+    let total_weight: f32 = children.iter().filter(|c| c.size.is_none()).map(|c| c.weight).sum();
+
+    let mut sizes: Vec<i32> = children.iter().map(|c| match c.size {
+        Some(size) => size,
+        None if total_weight > 0.0 => (remaining as f32 * c.weight / total_weight) as i32,
+        None => 0,
+    }).collect();
+
+    if let Some(last_weighted) = children.iter().rposition(|c| c.size.is_none()) {
+        let used: i32 = sizes.iter().sum();
+        sizes[last_weighted] += std::cmp::max(0, total - used);
+    }
+    return sizes;
+}
+
+fn scale(s: f32) -> rusttype::Scale {
+    return rusttype::Scale{x: s, y: s};
+}
+
+// Where the ink of `text` lands, relative to the (x, y) passed to
+// imageproc's draw_text_mut. Horizontally that's the text's own glyphs;
+// vertically it's the extent of a digit, so texts of the same size line up
+// on their baseline whatever their descenders.
+pub fn text_bounds(font: &rusttype::Font, size: f32, text: &str) -> Option<Rect> {
+    let glyph_bounds = |text: &str| {
+        let v_metrics = font.v_metrics(scale(size));
+        return font.layout(text, scale(size), rusttype::point(0.0, v_metrics.ascent))
+            .filter_map(|g| g.pixel_bounding_box())
+            .fold(None, |bounds: Option<rusttype::Rect<i32>>, bb| Some(match bounds {
+                None => bb,
+                Some(b) => rusttype::Rect{
+                    min: rusttype::point(std::cmp::min(b.min.x, bb.min.x), std::cmp::min(b.min.y, bb.min.y)),
+                    max: rusttype::point(std::cmp::max(b.max.x, bb.max.x), std::cmp::max(b.max.y, bb.max.y)),
+                },
+            }));
+    };
+
+    let horizontal = glyph_bounds(text)?;
+    let vertical = glyph_bounds("0")?;
+    return Some(Rect::new(
+        horizontal.min.x, vertical.min.y,
+        horizontal.max.x - horizontal.min.x, vertical.max.y - vertical.min.y));
+}
+
+// The largest size up to `max_size` at which `text` fits in `rect`.
+pub fn fit_size(font: &rusttype::Font, max_size: f32, text: &str, rect: &Rect) -> f32 {
+    let mut size = max_size;
+    // Glyph metrics don't scale perfectly linearly, so shrink until it fits.
+    for _ in 0..5 {
+        match text_bounds(font, size, text) {
+            Some(bounds) if bounds.width > rect.width || bounds.height > rect.height => {
+                let ratio = f32::min(rect.width as f32 / bounds.width as f32,
+                                     rect.height as f32 / bounds.height as f32);
+                size = (size * ratio).floor();
+            },
+            _ => break,
+        }
+    }
+    return f32::max(1.0, size);
+}
+
+// `text` broken between words into lines that fit `width` at `size`. A word
+// too wide for any line gets one to itself.
+pub fn wrap_text(font: &rusttype::Font, size: f32, text: &str, width: i32) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    for word in text.split_whitespace() {
+        if let Some(line) = lines.last_mut() {
+            let longer = format!("{} {}", line, word);
+            if text_bounds(font, size, &longer).map(|bounds| bounds.width <= width).unwrap_or(true) {
+                *line = longer;
+                continue;
+            }
+        }
+        lines.push(word.to_string());
+    }
+    return lines;
+}
+
+// Draws `text` aligned within `rect`, shrinking it if it doesn't fit at
+// `max_size`. Returns where the ink ended up, to position things next to it.
+pub fn draw_text(imgbuf: &mut image::RgbImage, color: image::Rgb<u8>, rect: &Rect, halign: HAlign, valign: VAlign, max_size: f32, font: &rusttype::Font, text: &str) -> Rect {
+    let size = fit_size(font, max_size, text, rect);
+    let bounds = match text_bounds(font, size, text) {
+        Some(bounds) => bounds,
+        None => return Rect::new(rect.x, rect.y, 0, 0),
+    };
+
+    let ink_x = match halign {
+        HAlign::Left => rect.x,
+        HAlign::Center => rect.x + (rect.width - bounds.width) / 2,
+        HAlign::Right => rect.right() - bounds.width,
+    };
+    let ink_y = match valign {
+        VAlign::Top => rect.y,
+        VAlign::Center => rect.y + (rect.height - bounds.height) / 2,
+        VAlign::Bottom => rect.bottom() - bounds.height,
+    };
+
+    imageproc::drawing::draw_text_mut(
+        imgbuf, color, ink_x - bounds.x, ink_y - bounds.y, scale(size), font, text);
+    return Rect::new(ink_x, ink_y, bounds.width, bounds.height);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HAlign;
+    use super::Layout;
+    use super::Rect;
+    use super::Rotation;
+    use super::Section;
+    use super::VAlign;
+
+    fn font() -> rusttype::Font<'static> {
+        return rusttype::Font::try_from_bytes(include_bytes!("/usr/share/fonts/truetype/roboto/unhinted/RobotoCondensed-Regular.ttf")).unwrap();
+    }
+
+    fn find(layout: &Layout, section: Section) -> Rect {
+        return layout.regions().iter().find(|r| r.section == section).expect("missing section").rect;
+    }
+
+    #[test]
+    fn sizes_and_weights() {
+        let layout = Layout::from_json(r#"{
+            "width": 100, "height": 50,
+            "root": {"row": [
+                {"size": 10},
+                {"section": "title", "weight": 1},
+                {"section": "version", "weight": 2, "padding": 2},
+                {"size": 21, "column": [
+                    {"section": "bus", "size": 20},
+                    {"section": "divider"}
+                ]}
+            ]}
+        }"#).expect("parse");
+
+        assert_eq!(Rect::new(10, 0, 23, 50), find(&layout, Section::Title));
+        assert_eq!(Rect::new(35, 2, 42, 46), find(&layout, Section::Version));
+        assert_eq!(Rect::new(79, 0, 21, 20), find(&layout, Section::Bus));
+        assert_eq!(Rect::new(79, 20, 21, 30), find(&layout, Section::Divider));
+    }
+
+    #[test]
+    fn overfull_rows_squeeze_weighted_children() {
+        let layout = Layout::from_json(r#"{
+            "width": 100, "height": 50,
+            "root": {"row": [{"size": 120, "section": "title"}, {"section": "bus"}]}
+        }"#).expect("parse");
+
+        assert_eq!(0, find(&layout, Section::Bus).width);
+    }
+
+    #[test]
+    fn builtin_layouts() {
+        let layout = Layout::builtin(640, 384).expect("640x384");
+        assert_eq!(Rect::new(209, 46, 175, 184), find(&layout, Section::UpcomingTrains));
+        assert_eq!(Rect::new(400, 240, 240, 144), find(&layout, Section::DailyForecast));
+        assert_eq!(1.0, layout.scale);
+
+        let layout = Layout::builtin(400, 300).expect("400x300");
+        assert!(layout.regions().iter().all(|r| r.scale == layout.scale));
+        assert_eq!(30, layout.regions()[0].scaled(50));
+
+        for (width, height) in vec![(640, 384), (800, 480), (400, 300), (128, 296),
+                                    (384, 640), (480, 800), (300, 400), (296, 128)] {
+            let layout = Layout::builtin(width, height).expect("builtin");
+            assert_eq!((width, height), (layout.width, layout.height));
+            for region in layout.regions() {
+                assert!(region.rect.x >= 0 && region.rect.right() <= width as i32, "{:?}", region);
+                assert!(region.rect.y >= 0 && region.rect.bottom() <= height as i32, "{:?}", region);
+                assert!(region.rect.width > 0 && region.rect.height > 0, "{:?}", region);
+            }
+        }
+
+        assert!(Layout::builtin(1, 1).is_err());
+    }
+
+    #[test]
+    fn invalid_layouts() {
+        // Unknown section
+        assert!(Layout::from_json(r#"{"width": 1, "height": 1, "root": {"section": "clock"}}"#).is_err());
+        // Misspelled key
+        assert!(Layout::from_json(r#"{"width": 1, "height": 1, "root": {"sectoin": "bus"}}"#).is_err());
+        // Two kinds of node at once
+        assert!(Layout::from_json(r#"{"width": 1, "height": 1, "root": {"section": "bus", "row": []}}"#).is_err());
+        assert!(Layout::from_json(r#"{"width": 1, "height": 1, "root": {"row": [{"section": "bus", "column": []}]}}"#).is_err());
+        // Nothing to draw at
+        assert!(Layout::from_json(r#"{"width": 1, "height": 1, "scale": 0, "root": {"section": "bus"}}"#).is_err());
+    }
+
+    #[test]
+    fn rect_helpers() {
+        let rect = Rect::new(10, 20, 100, 50);
+        assert_eq!((Rect::new(10, 20, 30, 50), Rect::new(40, 20, 70, 50)), rect.split_left(30));
+        assert_eq!((Rect::new(10, 20, 100, 50), Rect::new(110, 20, 0, 50)), rect.split_left(300));
+        assert_eq!((Rect::new(10, 20, 100, 5), Rect::new(10, 25, 100, 45)), rect.split_top(5));
+        assert_eq!(vec![Rect::new(10, 20, 100, 16), Rect::new(10, 36, 100, 17), Rect::new(10, 53, 100, 17)], rect.rows(3));
+        assert_eq!(Rect::new(12, 22, 96, 46), rect.inset(2));
+    }
+
+    #[test]
+    fn text_alignment() {
+        let font = font();
+        let rect = Rect::new(20, 10, 200, 60);
+
+        let mut imgbuf = image::RgbImage::new(300, 100);
+        let left = super::draw_text(&mut imgbuf, image::Rgb([0u8; 3]), &rect, HAlign::Left, VAlign::Top, 40.0, &font, "12:34");
+        assert_eq!((20, 10), (left.x, left.y));
+
+        let right = super::draw_text(&mut imgbuf, image::Rgb([0u8; 3]), &rect, HAlign::Right, VAlign::Bottom, 40.0, &font, "12:34");
+        assert_eq!((220, 70), (right.right(), right.bottom()));
+        assert_eq!((left.width, left.height), (right.width, right.height));
+
+        let center = super::draw_text(&mut imgbuf, image::Rgb([0u8; 3]), &rect, HAlign::Center, VAlign::Center, 40.0, &font, "12:34");
+        assert!((center.x - rect.x - (rect.right() - center.right())).abs() <= 1);
+        assert!((center.y - rect.y - (rect.bottom() - center.bottom())).abs() <= 1);
+    }
+
+    #[test]
+    fn text_shrinks_to_fit() {
+        let font = font();
+        let wide = super::text_bounds(&font, 50.0, "NO TRAINS").unwrap();
+        let rect = Rect::new(0, 0, wide.width / 2, 100);
+
+        let size = super::fit_size(&font, 50.0, "NO TRAINS", &rect);
+        assert!(size < 50.0 && size > 20.0, "{}", size);
+        assert!(super::text_bounds(&font, size, "NO TRAINS").unwrap().width <= rect.width);
+
+        // Fits already
+        assert_eq!(50.0, super::fit_size(&font, 50.0, "NO TRAINS", &Rect::new(0, 0, 1000, 1000)));
+    }
+
+    #[test]
+    fn wrap_text() {
+        let font = font();
+        let text = "Delays on the R   while we fix a signal";
+        assert_eq!(vec!["Delays on the R while we fix a signal"], super::wrap_text(&font, 20.0, text, 1000));
+
+        let width = super::text_bounds(&font, 20.0, "Delays on the R").unwrap().width;
+        let lines = super::wrap_text(&font, 20.0, text, width);
+        assert_eq!(vec!["Delays on the R", "while we fix a", "signal"], lines);
+        assert_eq!(vec!["Delays", "on"], super::wrap_text(&font, 20.0, "Delays on", 1));
+        assert!(super::wrap_text(&font, 20.0, "", 100).is_empty());
+    }
+
+    #[test]
+    fn rotation() {
+        assert_eq!(Ok(Rotation::Rotate270), "270".parse());
+        assert!("45".parse::<Rotation>().is_err());
+        assert_eq!((480, 800), Rotation::Rotate90.drawing_size(800, 480));
+        assert_eq!((800, 480), Rotation::Rotate180.drawing_size(800, 480));
+
+        // A 3x2 image with its top left pixel marked.
+        let mut imgbuf = image::RgbImage::from_pixel(3, 2, image::Rgb([255, 255, 255]));
+        imgbuf.put_pixel(0, 0, image::Rgb([0, 0, 0]));
+        let marked = |imgbuf: &image::RgbImage| {
+            return (imgbuf.width(), imgbuf.height(), imgbuf.enumerate_pixels().find(|(_, _, p)| p[0] == 0).map(|(x, y, _)| (x, y)));
+        };
+
+        assert_eq!((3, 2, Some((0, 0))), marked(&super::orient(&imgbuf, Rotation::Rotate0, false)));
+        assert_eq!((2, 3, Some((1, 0))), marked(&super::orient(&imgbuf, Rotation::Rotate90, false)));
+        assert_eq!((3, 2, Some((2, 1))), marked(&super::orient(&imgbuf, Rotation::Rotate180, false)));
+        assert_eq!((2, 3, Some((0, 2))), marked(&super::orient(&imgbuf, Rotation::Rotate270, false)));
+        assert_eq!((3, 2, Some((2, 0))), marked(&super::orient(&imgbuf, Rotation::Rotate0, true)));
+        assert_eq!((2, 3, Some((1, 2))), marked(&super::orient(&imgbuf, Rotation::Rotate90, true)));
+    }
+}
@@ -17,16 +17,24 @@ extern crate reqwest;
 extern crate rppal;
 extern crate rusttype;
 #[macro_use] extern crate serde_derive;
-#[macro_use] extern crate serde_with;
 #[macro_use] extern crate time;
 extern crate tiny_http;
 
+mod aqi;
 mod bustime;
+mod buttons;
 mod debug;
 mod display;
+mod dither;
 mod drawing;
+mod indoor;
+mod layout;
+mod night;
 mod purpleair;
+mod refresh;
 mod result;
+mod screens;
+mod solar;
 mod subway;
 mod update;
 mod weather;
@@ -41,14 +49,25 @@ struct TTDash<'a> {
     weather_display: Option<weather::WeatherDisplay>,
     forecast_timestamp: chrono::DateTime<chrono::Utc>,
     air_quality: Option<purpleair::AirQuality>,
+    air_quality_history: purpleair::History,
     air_quality_timestamp: chrono::DateTime<chrono::Utc>,
+    indoor_air_quality: Option<indoor::IndoorAirQuality>,
+    indoor_air_quality_timestamp: chrono::DateTime<chrono::Utc>,
     bus_time_data: Option<bustime::BusTimeDisplayData>,
     styles: drawing::Styles<'a>,
-    last_redraw: Option<chrono::DateTime<chrono::Utc>>,
+    schedule: screens::Schedule,
+    rotation: layout::Rotation,
+    mirror: bool,
+    screen: Option<Box<dyn display::Screen>>,
+    refresh_policy: refresh::Policy,
+    // During quiet hours, once the panel's been put to sleep.
+    asleep: bool,
+    // Night mode turned on or off with a button, until quiet hours catch up.
+    night_override: Option<bool>,
 }
 
 impl<'a> TTDash<'a> {
-    fn new() -> TTDash<'a> {
+    fn new(schedule: screens::Schedule, rotation: layout::Rotation, mirror: bool, screen: Option<Box<dyn display::Screen>>, refresh_policy: refresh::Policy, accent: Option<image::Rgb<u8>>) -> TTDash<'a> {
         // ln
 
         let font = include_bytes!("/usr/share/fonts/truetype/roboto/unhinted/RobotoCondensed-Regular.ttf");
@@ -64,36 +83,125 @@ impl<'a> TTDash<'a> {
             weather_display: None,
             forecast_timestamp: chrono::Utc::now(),
             air_quality: None,
+            air_quality_history: purpleair::History::new(),
             air_quality_timestamp: chrono::Utc::now(),
+            indoor_air_quality: None,
+            indoor_air_quality_timestamp: chrono::Utc::now(),
             bus_time_data: None,
             styles: drawing::Styles{
                 font_black: font_black,
                 font_bold: font_bold,
                 font: font,
-                color_black: image::Luma([0u8; 1]),
-                color_dark_gray: image::Luma([128u8; 1]),
-                color_light_gray: image::Luma([192u8; 1]),
-                color_white: image::Luma([255u8; 1]),
+                color_black: image::Rgb([0u8; 3]),
+                color_dark_gray: image::Rgb([128u8; 3]),
+                color_light_gray: image::Rgb([192u8; 3]),
+                color_white: image::Rgb([255u8; 3]),
+                color_accent: accent.unwrap_or(image::Rgb([0u8; 3])),
             },
-            last_redraw: None,
+            schedule: schedule,
+            rotation: rotation,
+            mirror: mirror,
+            screen: screen,
+            refresh_policy: refresh_policy,
+            asleep: false,
+            night_override: None,
         }
     }
 
-    fn update_air_quality(&mut self, creds: &purpleair::Credentials, now: &chrono::DateTime<chrono::Utc>) -> result::TTDashResult<()> {
-        self.air_quality = Some(purpleair::get_air_quality(creds)?);
+    fn update_air_quality(&mut self, creds: &purpleair::Credentials, correction: purpleair::Correction, now: &chrono::DateTime<chrono::Utc>) -> result::TTDashResult<()> {
+        let mut air_quality = purpleair::get_air_quality(creds, correction)?;
+        // Humidity feeds the EPA correction. It and temperature are measured
+        // inside the sensors' housings, so they're logged rather than shown.
+        let reading = |value: Option<f32>| value.map(|value| format!("{:.0}", value)).unwrap_or("?".to_string());
+        info!("PM2.5 {:.1} ug/m3 with {:?} correction, {:.1} as measured at {}% humidity and {}F.",
+              air_quality.pm25_ugm3, correction, air_quality.raw_pm25_ugm3,
+              reading(air_quality.humidity), reading(air_quality.temperature_f));
+        info!("Averaged from {} sensors, lowest confidence {}.",
+              air_quality.sensor_count,
+              air_quality.confidence.map(|c| c.to_string()).unwrap_or("?".to_string()));
+        self.air_quality_history.update(now, &mut air_quality);
+        self.air_quality = Some(air_quality);
         self.air_quality_timestamp = *now;
 
         return Ok(());
     }
 
-    fn update_weather(&mut self, now: &chrono::DateTime<chrono::Utc>) -> result::TTDashResult<()> {
-        self.weather_display = Some(weather::get_weather_display(now.timestamp())?);
+    fn update_indoor_air_quality(&mut self, source: &indoor::Source, now: &chrono::DateTime<chrono::Utc>) -> result::TTDashResult<()> {
+        self.indoor_air_quality = Some(indoor::get_indoor_air_quality(source)?);
+        self.indoor_air_quality_timestamp = *now;
+
+        return Ok(());
+    }
+
+    fn update_weather(&mut self, now: &chrono::DateTime<chrono::Utc>, location: &weather::Location) -> result::TTDashResult<()> {
+        self.weather_display = Some(weather::get_weather_display(now.timestamp(), location)?);
         self.forecast_timestamp = *now;
 
         return Ok(());
     }
 
-    fn one_iteration(&mut self, display: bool, png_out: Option<&str>, prev_processed_data: &subway::ProcessedData, auto_update: bool, purpleair_creds: Option<&purpleair::Credentials>, mta_bustime_creds: Option<&String>) -> result::TTDashResult<Option<subway::ProcessedData>> {
+    // Blanks the panel, or leaves tomorrow's forecast on it, and puts it to
+    // sleep until quiet hours are over.
+    fn start_quiet_hours(&mut self, png_out: Option<&str>, wake_date: chrono::NaiveDate, night_screen: bool, location: &weather::Location) -> result::TTDashResult<()> {
+        let now = chrono::Utc::now();
+        if night_screen {
+            match self.update_weather(&now, location) {
+                Ok(_) => {},
+                Err(err) => error!("Error updating weather: {:?}", err),
+            }
+        }
+        let layout = &self.schedule.current().layout;
+        let imgbuf = if night_screen {
+            drawing::generate_night_image(layout, self.weather_display.as_ref(), wake_date, &self.styles)?
+        } else {
+            image::RgbImage::from_pixel(layout.width, layout.height, self.styles.color_white)
+        };
+        let oriented = layout::orient(&imgbuf, self.rotation, self.mirror);
+
+        if let Some(png_out) = png_out {
+            oriented.save(png_out)?;
+        }
+        if let Some(screen) = self.screen.as_mut() {
+            screen.show(&oriented, display::RefreshMode::Full)?;
+            screen.sleep()?;
+        }
+        self.refresh_policy.shown(&imgbuf, refresh::Decision::Full, &now);
+        self.asleep = true;
+        return Ok(());
+    }
+
+    fn draw(&self, layout: &layout::Layout, data: &subway::ProcessedData, location: &weather::Location) -> result::TTDashResult<image::RgbImage> {
+        return drawing::generate_image(
+            layout,
+            data,
+            self.weather_display.as_ref(),
+            self.air_quality.as_ref(),
+            self.indoor_air_quality.as_ref(),
+            self.bus_time_data.as_ref(),
+            update::local_version().ok().map(|v| v.to_string()),
+            &location.timezone,
+            &self.styles);
+    }
+
+    // A PNG of the named screen, as it would be drawn now, whether or not
+    // it's the one up.
+    fn preview(&self, name: &str, data: &subway::ProcessedData, location: &weather::Location) -> result::TTDashResult<Vec<u8>> {
+        let screen = self.schedule.find(name).ok_or(result::make_error(&format!("No screen named '{}'", name)))?;
+        let imgbuf = self.draw(&screen.layout, data, location)?;
+        let mut png = std::io::Cursor::new(vec![]);
+        imgbuf.write_to(&mut png, image::ImageFormat::Png)?;
+        return Ok(png.into_inner());
+    }
+
+    fn press(&mut self, action: buttons::Action) {
+        match action {
+            buttons::Action::Refresh => self.refresh_policy.force_full(),
+            buttons::Action::NextScreen => self.schedule.skip(),
+            buttons::Action::ToggleNight => self.night_override = Some(!self.asleep),
+        }
+    }
+
+    fn one_iteration(&mut self, png_out: Option<&str>, prev_processed_data: &subway::ProcessedData, auto_update: bool, purpleair_creds: Option<&purpleair::Credentials>, purpleair_correction: purpleair::Correction, indoor_source: Option<&indoor::Source>, mta_bustime_creds: Option<&String>, location: &weather::Location, quiet_hours: Option<&night::QuietHours>, night_screen: bool) -> result::TTDashResult<Option<subway::ProcessedData>> {
         if auto_update {
             match update::binary_update_available() {
                 Some(target) => {
@@ -111,6 +219,30 @@ impl<'a> TTDash<'a> {
             }
         }
 
+        // Nothing is fetched or drawn during quiet hours, or in night mode.
+        let local_now = chrono::Utc::now().with_timezone(&location.timezone);
+        let quiet = quiet_hours.map(|q| q.contains(local_now.time())).unwrap_or(false);
+        if self.night_override == Some(quiet) {
+            self.night_override = None;
+        }
+        if self.night_override.unwrap_or(quiet) {
+            if !self.asleep {
+                let wake_date = match quiet_hours {
+                    Some(quiet_hours) => quiet_hours.wake_date(&local_now),
+                    None => local_now.date_naive().succ_opt().unwrap_or(local_now.date_naive()),
+                };
+                match quiet_hours {
+                    Some(quiet_hours) if quiet => info!("Quiet hours until {}.", quiet_hours.end),
+                    _ => info!("Night mode until the button's pressed again."),
+                }
+                self.start_quiet_hours(png_out, wake_date, night_screen, location)?;
+            }
+            return Ok(None);
+        }
+        if self.asleep {
+            info!("Night's over, waking up.");
+            self.asleep = false;
+        }
 
         let processed_data = subway::fetch_and_process_data()?;
 
@@ -118,7 +250,7 @@ impl<'a> TTDash<'a> {
         // TODO(mrjones): Don't fetch every time
         let now = chrono::Utc::now();
         if self.weather_display.is_none() || (now.timestamp() - self.forecast_timestamp.timestamp() > 60 * 30) {
-            match self.update_weather(&now) {
+            match self.update_weather(&now, location) {
                 Ok(_) => {},
                 Err(err) => error!("Error updating weather: {:?}", err),
             }
@@ -126,7 +258,7 @@ impl<'a> TTDash<'a> {
 
         if purpleair_creds.is_some() {
             if self.air_quality.is_none() || (now.timestamp() - self.air_quality_timestamp.timestamp() > 60) {
-                match self.update_air_quality(purpleair_creds.unwrap(), &now) {
+                match self.update_air_quality(purpleair_creds.unwrap(), purpleair_correction, &now) {
                     Ok(_) => { info!("AQ: {:?}", self.air_quality); },
                     Err(err) => { error!("Error updating air quality: {:?}", err); },
                 }
@@ -134,6 +266,15 @@ impl<'a> TTDash<'a> {
 
         }
 
+        if let Some(indoor_source) = indoor_source {
+            if self.indoor_air_quality.is_none() || (now.timestamp() - self.indoor_air_quality_timestamp.timestamp() > 60) {
+                match self.update_indoor_air_quality(indoor_source, &now) {
+                    Ok(_) => { info!("Indoor AQ: {:?}", self.indoor_air_quality); },
+                    Err(err) => { error!("Error updating indoor air quality: {:?}", err); },
+                }
+            }
+        }
+
         if mta_bustime_creds.is_some() {
             let bustimes = bustime::get_garfield_bus_arrivals(mta_bustime_creds.unwrap().as_ref());
             debug!("FINAL BUSTIMES: {:?}", bustimes);
@@ -162,55 +303,41 @@ impl<'a> TTDash<'a> {
             }
         }
 
-        let mut needs_redraw = false;
-
-        let data_went_back_in_time = processed_data.data_timestamp < prev_processed_data.data_timestamp;
-        if data_went_back_in_time {
+        if processed_data.data_timestamp < prev_processed_data.data_timestamp {
             debug!("Ignoring data ({}) that's older than what's already displayed ({}).",
                   processed_data.data_timestamp,
                   prev_processed_data.data_timestamp);
+            return Ok(None);
         }
 
-        if prev_processed_data.big_countdown != processed_data.big_countdown &&
-            !data_went_back_in_time {
-            debug!("Updating bignum {:?} -> {:?}",
-                     prev_processed_data.big_countdown,
-                     processed_data.big_countdown);
-            needs_redraw = true;
-        } else if self.last_redraw.is_none() {
-            // Probably never happens in practice?
-            info!("Drawing for the first time.");
-            needs_redraw = true;
-        } else if self.last_redraw.is_some() {
-            let seconds_since_redraw = now.timestamp() - self.last_redraw.unwrap().timestamp();
-            needs_redraw = seconds_since_redraw > 60 * 30;
-            if needs_redraw {
-                info!("Redrawing since it's been too long.");
-            }
+        let local_time = now.with_timezone(&location.timezone).time();
+        if self.schedule.update(&now, local_time, !processed_data.alerts.is_empty()) {
+            info!("Showing the {} screen.", self.schedule.current().name());
+            self.refresh_policy.change_layout(&self.schedule.current().layout);
         }
+        let imgbuf = self.draw(&self.schedule.current().layout, &processed_data, location)?;
 
-        if needs_redraw {
-            let imgbuf = drawing::generate_image(
-                &processed_data,
-                self.weather_display.as_ref(),
-                self.air_quality.as_ref(),
-                self.bus_time_data.as_ref(),
-                update::local_version().ok().map(|v| v.to_string()),
-                &self.styles)?;
-
-            if png_out.is_some() {
-                let _ = imgbuf.save(png_out.unwrap())?;
-            }
+        let decision = self.refresh_policy.decide(&imgbuf, &now);
+        let mode = match decision {
+            refresh::Decision::Skip => {
+                debug!("Not refreshing.");
+                return Ok(None);
+            },
+            refresh::Decision::Partial => display::RefreshMode::Partial,
+            refresh::Decision::Full => display::RefreshMode::Full,
+        };
+        debug!("Refreshing: {:?}", mode);
+        let oriented = layout::orient(&imgbuf, self.rotation, self.mirror);
+
+        if png_out.is_some() {
+            let _ = oriented.save(png_out.unwrap())?;
+        }
 
-            if display {
-                display::setup_and_display_image(&imgbuf)?;
-            }
-            self.last_redraw = Some(now);
-            return Ok(Some(processed_data));
-        } else {
-            debug!("Not refreshing.");
-            return Ok(None)
+        if let Some(screen) = self.screen.as_mut() {
+            screen.show(&oriented, mode)?;
         }
+        self.refresh_policy.shown(&imgbuf, decision, &now);
+        return Ok(Some(processed_data));
     }
 }
 
@@ -272,10 +399,32 @@ fn main() {
     opts.optopt("p", "debug-port", "Port to run a debug server on.", "PORT");
     opts.optflag("u", "auto-update", "Run the auto-updater.");
 
-    opts.optopt("", "purpleair-credentials-file", "Name of a file containing JSON {key: xx, id: xxx} value with purpleair credentials.", "FILE");
+    opts.optopt("", "purpleair-credentials-file", "Name of a file containing JSON {key: xx, id: xxx, read_key: xxx, sensor_ids: [...], bounding_box: {nwlat, nwlng, selat, selng}} value with a PurpleAir API read key and the sensors to average.", "FILE");
+    opts.optopt("", "purpleair-correction", "Correction applied to PurpleAir PM2.5 before computing AQI: none, epa, lrapa or aqandu (default: epa).", "CORRECTION");
+
+    opts.optopt("", "indoor-air-quality", "Indoor air quality source: pms5003:DEVICE or sds011:DEVICE for a serial sensor, or the URL of a local PurpleAir sensor's /json endpoint.", "SOURCE");
 
     opts.optopt("", "mta-bustime-credentials-file", "Name of a file containing the MTA bustime API key", "FILE");
 
+    opts.optopt("", "display-backend", "Where to show the dashboard: spi for the panel, or png:FILE or gif:FILE to draw it without one (default: spi).", "BACKEND");
+    opts.optopt("", "dither", "How to reduce the image to the panel's gray levels: none, floyd-steinberg or ordered (default: none).", "DITHER");
+    opts.optopt("", "panel", "E-paper panel model: 7in5, 7in5_v2, 4in2, 2in9_v2, or three color 7in5b_v2, 4in2b or 4in2c (default: 7in5).", "MODEL");
+    opts.optopt("", "full-refresh-minutes", "On panels that support partial refresh, the longest to go without a full refresh to clear ghosting; 0 always does full refreshes (default: 30).", "MINUTES");
+    opts.optopt("", "rotate", "Degrees to turn the dashboard clockwise for a panel mounted on its side or upside down: 0, 90, 180 or 270 (default: 0).", "DEGREES");
+    opts.optflag("", "mirror", "Mirror the dashboard left to right, before any rotation.");
+    opts.optopt("", "layout", "JSON file describing where to draw each section of the commute screen, sized to the panel once rotated (default: the built in layout for that size).", "FILE");
+    opts.optopt("", "screens", "Screens to take turns showing, any of commute, weather and alerts, each optionally limited to local times like commute@07:00-09:30. The alerts screen is only shown while there are service alerts, and right away when they start (default: commute).", "NAME,...");
+    opts.optopt("", "screen-dwell-seconds", "How long to show each screen before moving on to the next (default: 60).", "SECONDS");
+
+    opts.optopt("", "quiet-hours", "Local times to stop polling and put the panel to sleep, and when to wake up again, e.g. 23:00-06:30.", "HH:MM-HH:MM");
+    opts.optflag("", "night-screen", "During quiet hours, show the next day's forecast instead of a blank panel.");
+
+    opts.optmulti("", "button", "A push button from a GPIO pin (BCM numbering) to ground, and what pressing it does: refresh, next-screen or night. Repeat for each button, e.g. --button 5:refresh --button 6:night.", "PIN:ACTION");
+
+    opts.optopt("", "latitude", "Latitude used for sunrise/sunset (default: NYC).", "DEGREES");
+    opts.optopt("", "longitude", "Longitude used for sunrise/sunset, east-positive (default: NYC).", "DEGREES");
+    opts.optopt("", "timezone", "IANA timezone for clock times and daily charts (default: America/New_York).", "TZ");
+
     let matches = opts.parse(&args[1..]).expect("parse opts");
 
     let display = !matches.opt_present("skip-display");
@@ -287,20 +436,85 @@ fn main() {
         matches.opt_str("purpleair-credentials-file").map(
             |file| purpleair::credentials_from_file(file)
                 .expect("while reading purpleair-credentials-file"));
+    let purpleair_correction: purpleair::Correction =
+        matches.opt_get_default("purpleair-correction", purpleair::Correction::Epa)
+        .expect("parse purpleair-correction");
+    let indoor_source: Option<indoor::Source> =
+        matches.opt_get("indoor-air-quality").expect("parse indoor-air-quality");
     let mta_bustime_creds: Option<String> =
         matches.opt_str("mta-bustime-credentials-file").map(
             |file| std::fs::read_to_string(file)
                 .expect("while reading purpleair-credentials-file"));
+    let display_backend: display::Backend =
+        matches.opt_get_default("display-backend", display::Backend::Spi).expect("parse display-backend");
+    let dither: dither::Dither =
+        matches.opt_get_default("dither", dither::Dither::None).expect("parse dither");
+    let panel_model: display::Model =
+        matches.opt_get_default("panel", display::Model::Epd7in5).expect("parse panel");
+    let panel = panel_model.panel();
+    let accent = panel.accent();
+    let full_refresh_minutes: i64 =
+        matches.opt_get_default("full-refresh-minutes", 30).expect("parse full-refresh-minutes");
+    let rotation: layout::Rotation =
+        matches.opt_get_default("rotate", layout::Rotation::Rotate0).expect("parse rotate");
+    let mirror = matches.opt_present("mirror");
+    let (drawing_width, drawing_height) = rotation.drawing_size(panel.width(), panel.height());
+    let layout = match matches.opt_str("layout") {
+        Some(file) => layout::Layout::from_file(file).expect("while reading layout"),
+        None => layout::Layout::builtin(drawing_width, drawing_height).expect("while picking a layout"),
+    };
+    if layout.width != drawing_width || layout.height != drawing_height {
+        panic!("The {}x{} layout doesn't match the {} panel ({}x{} as rotated)",
+               layout.width, layout.height, panel.name(), drawing_width, drawing_height);
+    }
+    let screen_specs: Vec<screens::Spec> = matches.opt_str("screens").unwrap_or("commute".to_string()).split(',')
+        .map(|spec| spec.parse().expect("parse screens"))
+        .collect();
+    let screen_dwell_seconds: i64 =
+        matches.opt_get_default("screen-dwell-seconds", 60).expect("parse screen-dwell-seconds");
+    let quiet_hours: Option<night::QuietHours> =
+        matches.opt_get("quiet-hours").expect("parse quiet-hours");
+    let night_screen = matches.opt_present("night-screen");
+    let buttons: Vec<buttons::Button> = matches.opt_strs("button").iter()
+        .map(|button| button.parse().expect("parse button"))
+        .collect();
+    let location = weather::Location{
+        latitude: matches.opt_get_default("latitude", 40.7128).expect("parse latitude"),
+        longitude: matches.opt_get_default("longitude", -74.0060).expect("parse longitude"),
+        timezone: matches.opt_get_default("timezone", chrono_tz::America::New_York).expect("parse timezone"),
+    };
 
-    info!("Running with config: display={} one-shot={} debug-port={:?} auto-update={} local-png={:?}, purpleair-credentials={:?} mta-bustime-credentials={:?}", display, one_shot, debug_port, auto_update, local_png, purpleair_creds, mta_bustime_creds);
+    info!("Running with config: display={} display-backend={:?} panel={:?} rotate={:?} mirror={} dither={:?} full-refresh-minutes={} one-shot={} debug-port={:?} auto-update={} quiet-hours={:?} night-screen={} buttons={:?} screens={:?} screen-dwell-seconds={} local-png={:?}, purpleair-credentials={:?} purpleair-correction={:?} indoor-air-quality={:?} mta-bustime-credentials={:?} location={:?}", display, display_backend, panel_model, rotation, mirror, dither, full_refresh_minutes, one_shot, debug_port, auto_update, quiet_hours, night_screen, buttons, screen_specs, screen_dwell_seconds, local_png, purpleair_creds, purpleair_correction, indoor_source, mta_bustime_creds, location);
 
     let mut prev_processed_data = subway::ProcessedData::empty();
-    let mut ttdash = TTDash::new();
+    let screen = if display {
+        Some(display::open_screen(&display_backend, panel, dither)
+             .expect("while opening the display"))
+    } else {
+        None
+    };
+    let partial_refresh = screen.as_ref().map(|s| s.supports_partial_refresh()).unwrap_or(false) && full_refresh_minutes > 0;
+    let screens: Vec<screens::Screen> = screen_specs.iter().map(|spec| screens::Screen{
+        spec: *spec,
+        layout: screens::layout(spec.kind, &layout).expect("while laying out screens"),
+    }).collect();
+    let screen_names: Vec<String> = screens.iter().map(|screen| screen.name().to_string()).collect();
+    let schedule = screens::Schedule::new(screens, chrono::Duration::seconds(screen_dwell_seconds));
+    let refresh_policy = refresh::Policy::new(&schedule.current().layout, partial_refresh, chrono::Duration::minutes(full_refresh_minutes));
+    let mut ttdash = TTDash::new(schedule, rotation, mirror, screen, refresh_policy, accent);
+
+    let (request_sender, requests) = std::sync::mpsc::channel();
+    let _listener = if buttons.is_empty() {
+        None
+    } else {
+        Some(buttons::listen(&buttons, request_sender.clone()).expect("while setting up buttons"))
+    };
 
     match debug_port {
         Some(port) => {
             let local_png = local_png.clone();
-            std::thread::spawn(move || { debug::run_debug_server(&port, local_png); });
+            let request_sender = request_sender.clone();
+            std::thread::spawn(move || { debug::run_debug_server(&port, local_png, screen_names, request_sender); });
         },
         None => {},
     }
@@ -310,7 +524,7 @@ fn main() {
     }
 
     loop {
-        match ttdash.one_iteration(display, local_png.as_ref().map(String::as_ref), &prev_processed_data, auto_update, purpleair_creds.as_ref(), mta_bustime_creds.as_ref()) {
+        match ttdash.one_iteration(local_png.as_ref().map(String::as_ref), &prev_processed_data, auto_update, purpleair_creds.as_ref(), purpleair_correction, indoor_source.as_ref(), mta_bustime_creds.as_ref(), &location, quiet_hours.as_ref(), night_screen) {
             Err(err) => error!("{}", err),
             Ok(processed_data) => {
                 if let Some(processed_data) = processed_data {
@@ -323,6 +537,21 @@ fn main() {
             break;
         }
 
-        std::thread::sleep(std::time::Duration::from_secs(5));
+        // Just watching the clock during quiet hours. Button presses are
+        // handled straight away, and previews drawn in the meantime.
+        let wait = std::time::Duration::from_secs(if ttdash.asleep { 60 } else { 5 });
+        let deadline = std::time::Instant::now() + wait;
+        while let Some(remaining) = deadline.checked_duration_since(std::time::Instant::now()) {
+            match requests.recv_timeout(remaining) {
+                Ok(debug::Request::Press(action)) => {
+                    ttdash.press(action);
+                    break;
+                },
+                Ok(debug::Request::Preview(name, reply)) => {
+                    let _ = reply.send(ttdash.preview(&name, &prev_processed_data, &location));
+                },
+                Err(_) => break,
+            }
+        }
     }
 }
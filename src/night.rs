@@ -0,0 +1,92 @@
+// Quiet hours: a daily stretch, like overnight, when nobody's looking at the
+// dashboard, so there's no point polling for trains or refreshing the panel.
+extern crate chrono;
+extern crate std;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuietHours {
+    pub start: chrono::NaiveTime,
+    // When to wake up again.
+    pub end: chrono::NaiveTime,
+}
+
+// HH:MM-HH:MM in local time, e.g. 23:00-06:30, which runs past midnight.
+impl std::str::FromStr for QuietHours {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<QuietHours, String> {
+        let parse = |time: &str| chrono::NaiveTime::parse_from_str(time, "%H:%M")
+            .map_err(|err| format!("Bad time '{}' in quiet hours '{}': {}", time, s, err));
+        return match s.split_once('-') {
+            Some((start, end)) => {
+                let quiet_hours = QuietHours{start: parse(start)?, end: parse(end)?};
+                if quiet_hours.start == quiet_hours.end {
+                    return Err(format!("Quiet hours '{}' start and end at the same time", s));
+                }
+                Ok(quiet_hours)
+            },
+            None => Err(format!("Unknown quiet hours '{}', expected HH:MM-HH:MM", s)),
+        };
+    }
+}
+
+impl QuietHours {
+    pub fn contains(&self, time: chrono::NaiveTime) -> bool {
+        if self.start < self.end {
+            return self.start <= time && time < self.end;
+        }
+        return time >= self.start || time < self.end;
+    }
+
+    // The day quiet hours that are on at `now` end, which is tomorrow when
+    // they started before midnight.
+    pub fn wake_date<Tz: chrono::TimeZone>(&self, now: &chrono::DateTime<Tz>) -> chrono::NaiveDate {
+        let today = now.date_naive();
+        if now.time() < self.end {
+            return today;
+        }
+        return today.succ_opt().unwrap_or(today);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QuietHours;
+
+    fn time(hour: u32, minute: u32) -> chrono::NaiveTime {
+        return chrono::NaiveTime::from_hms_opt(hour, minute, 0).unwrap();
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Ok(QuietHours{start: time(23, 0), end: time(6, 30)}), "23:00-06:30".parse());
+        assert!("23:00".parse::<QuietHours>().is_err());
+        assert!("23:00-25:00".parse::<QuietHours>().is_err());
+        assert!("07:00-07:00".parse::<QuietHours>().is_err());
+    }
+
+    #[test]
+    fn contains() {
+        let overnight: QuietHours = "23:00-06:30".parse().unwrap();
+        assert!(overnight.contains(time(23, 0)));
+        assert!(overnight.contains(time(2, 0)));
+        assert!(!overnight.contains(time(6, 30)));
+        assert!(!overnight.contains(time(12, 0)));
+
+        let afternoon: QuietHours = "13:00-15:00".parse().unwrap();
+        assert!(afternoon.contains(time(14, 59)));
+        assert!(!afternoon.contains(time(15, 0)));
+        assert!(!afternoon.contains(time(23, 0)));
+    }
+
+    #[test]
+    fn wake_date() {
+        use chrono::TimeZone;
+
+        let overnight: QuietHours = "23:00-06:30".parse().unwrap();
+        let tz = chrono_tz::America::New_York;
+        let monday = chrono::NaiveDate::from_ymd_opt(2024, 3, 4).unwrap();
+        assert_eq!(monday.succ_opt().unwrap(), overnight.wake_date(&tz.with_ymd_and_hms(2024, 3, 4, 23, 15, 0).unwrap()));
+        assert_eq!(monday, overnight.wake_date(&tz.with_ymd_and_hms(2024, 3, 4, 1, 0, 0).unwrap()));
+    }
+}
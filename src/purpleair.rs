@@ -1,22 +1,246 @@
 extern crate anyhow;
+extern crate chrono;
 extern crate reqwest;
 extern crate serde;
 extern crate serde_json;
-extern crate serde_with;
 
 use anyhow::Context;
+use crate::aqi;
 use crate::result;
-use serde_with::{DisplayFromStr, serde_as};
+
+// Everything in the v1 response that we use. Averages, humidity and
+// temperature come straight from the sensor; humidity and temperature read
+// low and high respectively since they're measured inside the housing.
+// Only the cf=1 readings are requested per channel, for validate_channels.
+const FIELDS: &str = "pm2.5_atm,pm2.5_cf_1,pm2.5_cf_1_a,pm2.5_cf_1_b,pm2.5_10minute,pm2.5_60minute,humidity,temperature,confidence";
+
+// EPA's QA for PurpleAir drops readings where channels A and B differ by
+// more than this much *and* by more than MAX_RELATIVE_DIFFERENCE. The same
+// test is used to reject sensors that are far from the median of all sensors.
+const MAX_DIFFERENCE_UGM3: f32 = 5.0;
+const MAX_RELATIVE_DIFFERENCE: f32 = 0.7;
+
+// Sensors in a bounding box that haven't reported in this long are skipped.
+const MAX_SENSOR_AGE_SECONDS: u32 = 3600;
+
+// Sensors whose own confidence (0-100) is below this are skipped. Sensors
+// that don't report one are kept.
+const MIN_CONFIDENCE: u32 = 50;
+
+// NowCast looks at the last 12 hours.
+const NOWCAST_HOURS: i64 = 12;
+// The 10 minute average has to move this much (and at least 10%) away from
+// the hourly average to count as a trend.
+const MIN_TREND_UGM3: f32 = 1.0;
 
 #[derive(Debug)]
 pub struct AirQuality {
+    // pm2.5 (cf=atm) as reported by the sensors, before any correction.
     pub raw_pm25_ugm3: f32,
+    // After applying the configured Correction; this is what the AQI uses.
+    pub pm25_ugm3: f32,
+    pub aqi: aqi::Aqi,
+    // The sensors' own averages, replaced by History with averages of
+    // corrected readings.
+    pub pm25_10min_ugm3: Option<f32>,
+    pub pm25_60min_ugm3: Option<f32>,
+    pub humidity: Option<f32>,
+    pub temperature_f: Option<f32>,
+    // PurpleAir's own 0-100 estimate of how well the channels agree. The
+    // lowest of all sensors used.
+    pub confidence: Option<u32>,
+    // How many sensors were averaged, after dropping outliers.
+    pub sensor_count: usize,
+    // Set by History; the AQI is based on this once there's enough history.
+    pub nowcast_ugm3: Option<f32>,
+    pub trend: Trend,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trend {
+    Rising,
+    Steady,
+    Falling,
+}
+
+impl AirQuality {
+    // What the AQI is computed from: NowCast if possible, then the 10 minute
+    // average, so a brief spike doesn't flash an alarming number.
+    pub fn smoothed_pm25_ugm3(&self) -> f32 {
+        return self.nowcast_ugm3.or(self.pm25_10min_ugm3).unwrap_or(self.pm25_ugm3);
+    }
+}
+
+// Rolling in-memory history of corrected pm2.5 readings.
+#[derive(Debug, Default)]
+pub struct History {
+    readings: std::collections::VecDeque<(chrono::DateTime<chrono::Utc>, f32)>,
+}
+
+impl History {
+    pub fn new() -> History {
+        return History::default();
+    }
+
+    // Records `air_quality` and replaces its averages, NowCast, trend and
+    // AQI with ones computed from the history.
+    pub fn update(&mut self, now: &chrono::DateTime<chrono::Utc>, air_quality: &mut AirQuality) {
+        self.readings.push_back((*now, air_quality.pm25_ugm3));
+        let oldest = *now - chrono::Duration::hours(NOWCAST_HOURS);
+        while self.readings.front().map_or(false, |(t, _)| *t <= oldest) {
+            self.readings.pop_front();
+        }
+
+        let average_10min = self.average(now, chrono::Duration::minutes(10));
+        let average_60min = self.average(now, chrono::Duration::hours(1));
+        air_quality.pm25_10min_ugm3 = average_10min;
+        air_quality.pm25_60min_ugm3 = average_60min;
+        air_quality.nowcast_ugm3 = self.nowcast(now);
+        air_quality.trend = match (average_10min, average_60min) {
+            (Some(recent), Some(hourly)) => trend(recent, hourly),
+            _ => Trend::Steady,
+        };
+        air_quality.aqi = aqi::from_pm25(air_quality.smoothed_pm25_ugm3());
+    }
+
+    // Mean of readings in (end - duration, end].
+    fn average(&self, end: &chrono::DateTime<chrono::Utc>, duration: chrono::Duration) -> Option<f32> {
+        let start = *end - duration;
+        return mean(self.readings.iter()
+                    .filter(|(t, _)| *t > start && *t <= *end)
+                    .map(|(_, pm25)| *pm25));
+    }
+
+    // EPA's NowCast for PM: a weighted average of the last 12 hourly
+    // averages, where the weight falls off faster when air quality is
+    // changing quickly. Needs two of the last three hours.
+    // https://usepa.servicenowservices.com/airnow?id=kb_article_view&sysparm_article=KB0011856
+    fn nowcast(&self, now: &chrono::DateTime<chrono::Utc>) -> Option<f32> {
+        let hourly = (0..NOWCAST_HOURS)
+            .map(|i| self.average(&(*now - chrono::Duration::hours(i)), chrono::Duration::hours(1)))
+            .collect::<Vec<_>>();
+        if hourly.iter().take(3).filter(|h| h.is_some()).count() < 2 {
+            return None;
+        }
+
+        let known = hourly.iter().filter_map(|h| *h);
+        let min = known.clone().fold(std::f32::INFINITY, f32::min);
+        let max = known.fold(std::f32::NEG_INFINITY, f32::max);
+        let weight = if max > 0.0 { (min / max).max(0.5) } else { 1.0 };
+
+        let mut numerator = 0.0;
+        let mut denominator = 0.0;
+        for (i, average) in hourly.iter().enumerate() {
+            if let Some(average) = average {
+                numerator += weight.powi(i as i32) * average;
+                denominator += weight.powi(i as i32);
+            }
+        }
+        return Some(numerator / denominator);
+    }
+}
+
+fn trend(recent: f32, hourly: f32) -> Trend {
+    let threshold = MIN_TREND_UGM3.max(0.1 * hourly);
+    if recent - hourly > threshold {
+        return Trend::Rising;
+    } else if hourly - recent > threshold {
+        return Trend::Falling;
+    }
+    return Trend::Steady;
+}
+
+// Published corrections for PurpleAir's Plantower sensors, which read high
+// compared to regulatory monitors, especially when it's humid.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Correction {
+    // Uses pm2.5 (cf=atm) as-is.
+    None,
+    // US-wide correction from the EPA, extended for wildfire smoke.
+    // https://www.epa.gov/air-sensor-toolbox/technical-approaches-sensor-data-airnow-fire-and-smoke-map
+    Epa,
+    // Lane Regional Air Protection Agency, tuned for wood smoke.
+    Lrapa,
+    // University of Utah AQ&U study.
+    AqAndU,
+}
+
+impl std::str::FromStr for Correction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Correction, String> {
+        return match s.to_lowercase().as_ref() {
+            "none" => Ok(Correction::None),
+            "epa" => Ok(Correction::Epa),
+            "lrapa" => Ok(Correction::Lrapa),
+            "aqandu" => Ok(Correction::AqAndU),
+            _ => Err(format!("Unknown correction '{}', expected one of none, epa, lrapa, aqandu", s)),
+        };
+    }
+}
+
+fn epa_correction(pm25_cf1: f32, humidity: f32) -> f32 {
+    let pa = pm25_cf1;
+    let rh = humidity;
+    if pa < 30.0 {
+        return 0.524 * pa - 0.0862 * rh + 5.75;
+    } else if pa < 50.0 {
+        let w = pa / 20.0 - 1.5;
+        return (0.786 * w + 0.524 * (1.0 - w)) * pa - 0.0862 * rh + 5.75;
+    } else if pa < 210.0 {
+        return 0.786 * pa - 0.0862 * rh + 5.75;
+    } else if pa < 260.0 {
+        let w = pa / 50.0 - 4.2;
+        return (0.69 * w + 0.786 * (1.0 - w)) * pa
+            - 0.0862 * rh * (1.0 - w)
+            + 2.966 * w
+            + 5.75 * (1.0 - w)
+            + 8.84e-4 * pa * pa * w;
+    } else {
+        return 2.966 + 0.69 * pa + 8.84e-4 * pa * pa;
+    }
+}
+
+// The EPA correction needs humidity; without it this falls back to the
+// uncorrected value rather than failing.
+pub fn correct_pm25(correction: Correction, pm25_cf1: f32, pm25_atm: f32, humidity: Option<f32>) -> f32 {
+    let corrected = match (correction, humidity) {
+        (Correction::None, _) => pm25_atm,
+        (Correction::Epa, Some(humidity)) => epa_correction(pm25_cf1, humidity),
+        (Correction::Epa, None) => {
+            warn!("No humidity reading, skipping EPA correction.");
+            pm25_atm
+        },
+        (Correction::Lrapa, _) => 0.5 * pm25_atm - 0.66,
+        (Correction::AqAndU, _) => 0.778 * pm25_atm + 2.65,
+    };
+    return corrected.max(0.0);
 }
 
+// `id` is the sensor index and `key` is an API read key from
+// https://develop.purpleair.com. A private sensor also needs its own
+// `read_key`. Readings from `id`, `sensor_ids` and all outdoor sensors in
+// `bounding_box` are averaged.
 #[derive(Debug, Deserialize)]
 pub struct Credentials {
+    #[serde(default)]
     pub id: String,
     pub key: String,
+    #[serde(default)]
+    pub read_key: Option<String>,
+    #[serde(default)]
+    pub sensor_ids: Vec<String>,
+    #[serde(default)]
+    pub bounding_box: Option<BoundingBox>,
+}
+
+// Corners of a box of sensors, named like the v1 API's query parameters.
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct BoundingBox {
+    pub nwlat: f64,
+    pub nwlng: f64,
+    pub selat: f64,
+    pub selng: f64,
 }
 
 pub fn credentials_from_file<P: AsRef<std::path::Path>>(path: P) -> result::TTDashResult<Credentials> {
@@ -29,33 +253,183 @@ pub fn credentials_from_file<P: AsRef<std::path::Path>>(path: P) -> result::TTDa
     return Ok(creds);
 }
 
-pub fn get_air_quality(credentials: &Credentials) -> result::TTDashResult<AirQuality> {
-    return get_air_quality_ext(&credentials.id, &credentials.key, real_fetch_json_fn);
+pub fn get_air_quality(credentials: &Credentials, correction: Correction) -> result::TTDashResult<AirQuality> {
+    return get_air_quality_ext(credentials, correction, real_fetch_json_fn);
 }
 
-fn get_air_quality_ext(id: &str, key: &str, fetch_json_fn: fn(&str) -> result::TTDashResult<String>) -> result::TTDashResult<AirQuality> {
-    let raw_json = fetch_json_fn(
-        &format!("https://www.purpleair.com/json?show={}&key={}", id, key))?;
+fn get_air_quality_ext(credentials: &Credentials, correction: Correction, fetch_json_fn: fn(&str, &str) -> result::TTDashResult<String>) -> result::TTDashResult<AirQuality> {
+    let mut sensors = vec![];
+    if !credentials.id.is_empty() {
+        match fetch_sensor(&credentials.id, credentials.read_key.as_ref(), &credentials.key, fetch_json_fn) {
+            Ok(sensor) => sensors.push(sensor),
+            Err(e) => warn!("Skipping PurpleAir sensor {}: {:?}", credentials.id, e),
+        }
+    }
+    for id in &credentials.sensor_ids {
+        match fetch_sensor(id, None, &credentials.key, fetch_json_fn) {
+            Ok(sensor) => sensors.push(sensor),
+            Err(e) => warn!("Skipping PurpleAir sensor {}: {:?}", id, e),
+        }
+    }
+    if let Some(bounding_box) = &credentials.bounding_box {
+        match fetch_bounding_box(bounding_box, &credentials.key, fetch_json_fn) {
+            Ok(mut found) => sensors.append(&mut found),
+            Err(e) => warn!("Skipping PurpleAir sensors in {:?}: {:?}", bounding_box, e),
+        }
+    }
 
+    // The bounding box may contain sensors that were also listed by id.
+    sensors.sort_by_key(|s| s.sensor_index);
+    sensors.dedup_by_key(|s| s.sensor_index);
 
-    let response: PurpleAirResponse = serde_json::from_str(&raw_json)?;
+    let sensors = sensors.into_iter()
+        .filter(|s| match validate_channels(s.pm25_cf1_a_ugm3, s.pm25_cf1_b_ugm3) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Skipping PurpleAir sensor {}: {:?}", s.sensor_index, e);
+                false
+            },
+        })
+        .filter(|s| match s.confidence {
+            Some(confidence) if confidence < MIN_CONFIDENCE => {
+                warn!("Skipping PurpleAir sensor {}: confidence {}", s.sensor_index, confidence);
+                false
+            },
+            _ => true,
+        })
+        .filter(|s| s.pm25_atm_ugm3.is_some() && s.pm25_cf1_ugm3.is_some())
+        .collect::<Vec<_>>();
+    let sensors = reject_outliers(sensors);
+    if sensors.is_empty() {
+        return Err(result::make_error("No PurpleAir sensors with usable data"));
+    }
 
-    let first_result_with_data = response.results.iter()
-        .filter(|r| r.raw_pm25_ugm3.is_some())
-        .nth(0)
-        .ok_or(result::make_error("Result didn't have one valid result"))?;
+    let pm25_atm_ugm3 = mean(sensors.iter().filter_map(|s| s.pm25_atm_ugm3)).unwrap();
+    let humidity = mean(sensors.iter().filter_map(|s| s.humidity));
+    let pm25_ugm3 = correct_pm25(
+        correction,
+        mean(sensors.iter().filter_map(|s| s.pm25_cf1_ugm3)).unwrap(),
+        pm25_atm_ugm3,
+        humidity);
 
+    let stats = sensors.iter().map(|s| s.stats.clone().unwrap_or_default()).collect::<Vec<_>>();
     return Ok(AirQuality{
-        raw_pm25_ugm3: first_result_with_data.raw_pm25_ugm3.ok_or(
-            result::make_error("pm2.5 missing"))?,
+        raw_pm25_ugm3: pm25_atm_ugm3,
+        pm25_ugm3: pm25_ugm3,
+        aqi: aqi::from_pm25(pm25_ugm3),
+        pm25_10min_ugm3: mean(stats.iter().filter_map(|s| s.pm25_10min_ugm3)),
+        pm25_60min_ugm3: mean(stats.iter().filter_map(|s| s.pm25_60min_ugm3)),
+        humidity: humidity,
+        temperature_f: mean(sensors.iter().filter_map(|s| s.temperature_f)),
+        confidence: sensors.iter().filter_map(|s| s.confidence).min(),
+        sensor_count: sensors.len(),
+        nowcast_ugm3: None,
+        trend: Trend::Steady,
         });
 }
 
-fn real_fetch_json_fn(url: &str) -> result::TTDashResult<String> {
+fn fetch_sensor(id: &str, read_key: Option<&String>, api_key: &str, fetch_json_fn: fn(&str, &str) -> result::TTDashResult<String>) -> result::TTDashResult<PurpleAirSensor> {
+    let mut url = format!("https://api.purpleair.com/v1/sensors/{}?fields={}", id, FIELDS);
+    if let Some(read_key) = read_key {
+        url.push_str(&format!("&read_key={}", read_key));
+    }
+    let raw_json = fetch_json_fn(&url, api_key)?;
+
+    let response: PurpleAirResponse = serde_json::from_str(&raw_json)
+        .context("while parsing PurpleAir response")?;
+    return Ok(response.sensor);
+}
+
+// Only outdoor sensors; indoor ones would drag the average down.
+fn fetch_bounding_box(bounding_box: &BoundingBox, api_key: &str, fetch_json_fn: fn(&str, &str) -> result::TTDashResult<String>) -> result::TTDashResult<Vec<PurpleAirSensor>> {
+    let raw_json = fetch_json_fn(
+        &format!("https://api.purpleair.com/v1/sensors?fields={}&location_type=0&max_age={}&nwlat={}&nwlng={}&selat={}&selng={}",
+                 FIELDS, MAX_SENSOR_AGE_SECONDS,
+                 bounding_box.nwlat, bounding_box.nwlng, bounding_box.selat, bounding_box.selng),
+        api_key)?;
+
+    let response: PurpleAirSensorsResponse = serde_json::from_str(&raw_json)
+        .context("while parsing PurpleAir sensors response")?;
+
+    // Rows are arrays in the order of `fields`, with the stats flattened in.
+    let mut sensors = vec![];
+    for row in response.data {
+        let mut sensor = serde_json::Map::new();
+        let mut stats = serde_json::Map::new();
+        for (field, value) in response.fields.iter().zip(row.into_iter()) {
+            if field.ends_with("minute") {
+                stats.insert(field.clone(), value);
+            } else {
+                sensor.insert(field.clone(), value);
+            }
+        }
+        sensor.insert("stats".to_string(), serde_json::Value::Object(stats));
+        sensors.push(serde_json::from_value(serde_json::Value::Object(sensor))
+                     .context("while parsing PurpleAir sensors row")?);
+    }
+    return Ok(sensors);
+}
+
+fn mean<I: Iterator<Item = f32>>(values: I) -> Option<f32> {
+    let values = values.collect::<Vec<_>>();
+    if values.is_empty() {
+        return None;
+    }
+    return Some(values.iter().sum::<f32>() / values.len() as f32);
+}
+
+fn too_different(a: f32, b: f32, reference: f32) -> bool {
+    let difference = (a - b).abs();
+    return difference > MAX_DIFFERENCE_UGM3 && difference > MAX_RELATIVE_DIFFERENCE * reference;
+}
+
+// Drops sensors that are far from the median. With only two sensors that
+// disagree there's no telling which is right, so both are dropped.
+fn reject_outliers(sensors: Vec<PurpleAirSensor>) -> Vec<PurpleAirSensor> {
+    let mut values = sensors.iter().filter_map(|s| s.pm25_atm_ugm3).collect::<Vec<_>>();
+    if values.is_empty() {
+        return sensors;
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let median = if values.len() % 2 == 0 {
+        (values[values.len() / 2 - 1] + values[values.len() / 2]) / 2.0
+    } else {
+        values[values.len() / 2]
+    };
+
+    return sensors.into_iter()
+        .filter(|s| {
+            let value = s.pm25_atm_ugm3.unwrap_or(median);
+            if too_different(value, median, median) {
+                warn!("Skipping PurpleAir sensor {}: pm2.5 {:.1} is far from median {:.1}",
+                      s.sensor_index, value, median);
+                return false;
+            }
+            return true;
+        })
+        .collect();
+}
+
+// Single-channel sensors only report A, which is trusted as-is.
+pub fn validate_channels(a: Option<f32>, b: Option<f32>) -> result::TTDashResult<()> {
+    if let (Some(a), Some(b)) = (a, b) {
+        if too_different(a, b, (a + b) / 2.0) {
+            return Err(result::make_error(&format!(
+                "PurpleAir channels disagree: A={:.1} B={:.1}", a, b)));
+        }
+    }
+    return Ok(());
+}
+
+fn real_fetch_json_fn(url: &str, api_key: &str) -> result::TTDashResult<String> {
     use std::io::Read;
 
     let client = reqwest::blocking::Client::new();
-    let mut response = client.get(url).header(reqwest::header::USER_AGENT, "ttdash from http://mrjon.es").send()?;
+    let mut response = client.get(url)
+        .header(reqwest::header::USER_AGENT, "ttdash from http://mrjon.es")
+        .header("X-API-Key", api_key)
+        .send()?
+        .error_for_status()?;
     let mut response_body = String::new();
     response.read_to_string(&mut response_body)?;
     return Ok(response_body);
@@ -64,27 +438,150 @@ fn real_fetch_json_fn(url: &str) -> result::TTDashResult<String> {
 
 #[derive(Serialize, Deserialize)]
 struct PurpleAirResponse {
-    results: std::vec::Vec<PurpleAirResult>,
+    sensor: PurpleAirSensor,
 }
 
-#[serde_as]
 #[derive(Serialize, Deserialize)]
-struct PurpleAirResult {
-    #[serde(rename = "ID")]
-    id: i32,
+struct PurpleAirSensorsResponse {
+    fields: std::vec::Vec<String>,
+    data: std::vec::Vec<std::vec::Vec<serde_json::Value>>,
+}
 
-    #[serde(rename = "Label")]
-    label: String,
+#[derive(Serialize, Deserialize)]
+struct PurpleAirSensor {
+    sensor_index: i32,
+
+    #[serde(default, rename = "pm2.5_atm")]
+    pm25_atm_ugm3: Option<f32>,
+
+    #[serde(default, rename = "pm2.5_cf_1")]
+    pm25_cf1_ugm3: Option<f32>,
+
+    #[serde(default, rename = "pm2.5_cf_1_a")]
+    pm25_cf1_a_ugm3: Option<f32>,
+
+    #[serde(default, rename = "pm2.5_cf_1_b")]
+    pm25_cf1_b_ugm3: Option<f32>,
 
-    #[serde_as(as = "Option<DisplayFromStr>")]
-    #[serde(default, rename = "p_2_5_um")]
-    raw_pm25_ugm3: Option<f32>,
+    #[serde(default)]
+    humidity: Option<f32>,
+
+    #[serde(default, rename = "temperature")]
+    temperature_f: Option<f32>,
+
+    #[serde(default)]
+    confidence: Option<u32>,
+
+    #[serde(default)]
+    stats: Option<PurpleAirStats>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct PurpleAirStats {
+    #[serde(default, rename = "pm2.5_10minute")]
+    pm25_10min_ugm3: Option<f32>,
+
+    #[serde(default, rename = "pm2.5_60minute")]
+    pm25_60min_ugm3: Option<f32>,
 }
 
 
 #[cfg(test)]
 mod tests {
     use super::result;
+    use super::correct_pm25;
+    use super::Correction;
+
+    fn assert_close(expected: f32, actual: f32) {
+        assert!((expected - actual).abs() < 0.01, "expected {} got {}", expected, actual);
+    }
+
+    fn creds() -> super::Credentials {
+        return super::Credentials{
+            id: "12345".to_string(),
+            key: "key".to_string(),
+            read_key: None,
+            sensor_ids: vec![],
+            bounding_box: None,
+        };
+    }
+
+    fn sensor_json(index: i32, pm25: f32) -> String {
+        return format!(
+            r#"{{"sensor": {{"sensor_index": {}, "pm2.5_atm": {}, "pm2.5_cf_1": {}, "pm2.5_cf_1_a": {}, "pm2.5_cf_1_b": {}, "humidity": 30}}}}"#,
+            index, pm25, pm25, pm25, pm25);
+    }
+
+    // Sensor n reads 1.32 (12345), 2, 3, or 500 (the faulty neighbor, 4).
+    fn fake_sensors_fetch_fn(url: &str, _api_key: &str) -> result::TTDashResult<String> {
+        if url.starts_with("https://api.purpleair.com/v1/sensors/12345?") {
+            return Ok(std::fs::read_to_string("testdata/purpleair.json").expect("error reading purpleair.json"));
+        } else if url.starts_with("https://api.purpleair.com/v1/sensors/2?") {
+            return Ok(sensor_json(2, 2.0));
+        } else if url.starts_with("https://api.purpleair.com/v1/sensors/3?") {
+            return Ok(sensor_json(3, 3.0));
+        } else if url.starts_with("https://api.purpleair.com/v1/sensors/4?") {
+            return Ok(sensor_json(4, 500.0));
+        } else if url.starts_with("https://api.purpleair.com/v1/sensors?") {
+            assert!(url.contains("&location_type=0&"), "{}", url);
+            assert!(url.contains("&nwlat=40.7&nwlng=-74&selat=40.6&selng=-73.9"), "{}", url);
+            return Ok(r#"{
+                "fields": ["sensor_index", "pm2.5_atm", "pm2.5_cf_1", "pm2.5_cf_1_a", "pm2.5_cf_1_b", "pm2.5_10minute", "confidence"],
+                "data": [
+                    [12345, 1.32, 1.32, 1.15, 1.49, 1.47, 100],
+                    [5, 4.0, 4.0, 4.0, 4.0, 4.5, 90],
+                    [6, 20.0, 20.0, 1.0, 39.0, 20.0, 10],
+                    [7, 4.0, 4.0, 4.0, 4.0, 4.5, 20]
+                ]}"#.to_string());
+        }
+        return Err(result::make_error(&format!("Unexpected url {}", url)));
+    }
+
+    #[test]
+    fn multiple_sensors_reject_outliers() {
+        let mut creds = creds();
+        creds.sensor_ids = vec!["2".to_string(), "3".to_string(), "4".to_string()];
+
+        let aq = super::get_air_quality_ext(&creds, Correction::None, fake_sensors_fetch_fn).expect("Get air quality failed");
+
+        assert_eq!(3, aq.sensor_count);
+        assert_close((1.32 + 2.0 + 3.0) / 3.0, aq.pm25_ugm3);
+        assert_close((27.0 + 30.0 + 30.0) / 3.0, aq.humidity.unwrap());
+        // Only reported by 12345
+        assert_eq!(Some(1.47), aq.pm25_10min_ugm3);
+    }
+
+    #[test]
+    fn two_disagreeing_sensors() {
+        let mut creds = creds();
+        creds.sensor_ids = vec!["4".to_string()];
+        assert!(super::get_air_quality_ext(&creds, Correction::None, fake_sensors_fetch_fn).is_err());
+
+        // A sensor that fails to fetch is skipped.
+        creds.sensor_ids = vec!["2".to_string(), "99".to_string()];
+        let aq = super::get_air_quality_ext(&creds, Correction::None, fake_sensors_fetch_fn).expect("Get air quality failed");
+        assert_eq!(2, aq.sensor_count);
+    }
+
+    #[test]
+    fn bounding_box() {
+        let mut creds = creds();
+        creds.bounding_box = Some(super::BoundingBox{nwlat: 40.7, nwlng: -74.0, selat: 40.6, selng: -73.9});
+
+        let aq = super::get_air_quality_ext(&creds, Correction::None, fake_sensors_fetch_fn).expect("Get air quality failed");
+
+        // 12345 is listed twice and counted once; 6's channels disagree and
+        // 7's confidence is too low.
+        assert_eq!(2, aq.sensor_count);
+        assert_close((1.32 + 4.0) / 2.0, aq.pm25_ugm3);
+        assert_close((1.47 + 4.5) / 2.0, aq.pm25_10min_ugm3.unwrap());
+        assert_eq!(Some(90), aq.confidence);
+
+        // Without a sensor id
+        creds.id = "".to_string();
+        let aq = super::get_air_quality_ext(&creds, Correction::None, fake_sensors_fetch_fn).expect("Get air quality failed");
+        assert_eq!(2, aq.sensor_count);
+    }
 
     #[test]
     fn json_parse() {
@@ -94,18 +591,194 @@ mod tests {
         let response: super::PurpleAirResponse = serde_json::from_str(&raw_json)
             .expect("Error parsing JSON");
 
-        assert_eq!(response.results[0].id, 12345);
-        assert_eq!(response.results[0].raw_pm25_ugm3, Some(4.66));
+        assert_eq!(response.sensor.sensor_index, 12345);
+        assert_eq!(response.sensor.pm25_cf1_ugm3, Some(1.32));
+        assert_eq!(response.sensor.pm25_atm_ugm3, Some(1.32));
+        assert_eq!(response.sensor.humidity, Some(27.0));
+        assert_eq!(response.sensor.confidence, Some(100));
+        assert_eq!(response.sensor.stats.unwrap().pm25_60min_ugm3, Some(2.35));
     }
 
     #[test]
     fn fetch_air_quality() {
-        let fake_fetch_fn = |_url: &str| -> result::TTDashResult<String> {
+        let fake_fetch_fn = |url: &str, api_key: &str| -> result::TTDashResult<String> {
+            assert!(url.starts_with("https://api.purpleair.com/v1/sensors/12345?fields="), "{}", url);
+            assert!(!url.contains("read_key"));
+            assert_eq!("key", api_key);
             return Ok(std::fs::read_to_string("testdata/purpleair.json").expect("error reading purpleair.json"));
         };
 
-        let aq = super::get_air_quality_ext("1", "key", fake_fetch_fn).expect("Get air quality failed");
+        let aq = super::get_air_quality_ext(&creds(), Correction::None, fake_fetch_fn).expect("Get air quality failed");
+
+        assert_eq!(1.32, aq.raw_pm25_ugm3);
+        assert_eq!(1.32, aq.pm25_ugm3);
+        assert_eq!(7, aq.aqi.value);
+        assert_eq!(Some(1.47), aq.pm25_10min_ugm3);
+        assert_eq!(Some(2.35), aq.pm25_60min_ugm3);
+        assert_eq!(Some(89.0), aq.temperature_f);
+        assert_eq!(Some(100), aq.confidence);
+        assert_eq!(1, aq.sensor_count);
+
+        let aq = super::get_air_quality_ext(&creds(), Correction::Epa, fake_fetch_fn).expect("Get air quality failed");
+
+        // 0.524 * 1.32 - 0.0862 * 27 + 5.75
+        assert_close(4.114, aq.pm25_ugm3);
+        assert_eq!(23, aq.aqi.value);
+        assert_eq!(super::aqi::Category::Good, aq.aqi.category);
+    }
+
+    #[test]
+    fn private_sensor_read_key() {
+        let fake_fetch_fn = |url: &str, _api_key: &str| -> result::TTDashResult<String> {
+            assert!(url.ends_with("&read_key=secret"), "{}", url);
+            return Ok(std::fs::read_to_string("testdata/purpleair.json").expect("error reading purpleair.json"));
+        };
+
+        let mut creds = creds();
+        creds.read_key = Some("secret".to_string());
+        super::get_air_quality_ext(&creds, Correction::None, fake_fetch_fn).expect("Get air quality failed");
+    }
+
+    #[test]
+    fn channel_validation() {
+        assert!(super::validate_channels(Some(1.15), Some(1.49)).is_ok());
+        // Big relative difference, but within 5 ug/m3
+        assert!(super::validate_channels(Some(0.5), Some(4.0)).is_ok());
+        // Big absolute difference, but within 70%
+        assert!(super::validate_channels(Some(100.0), Some(130.0)).is_ok());
+        assert!(super::validate_channels(Some(2.0), Some(40.0)).is_err());
+        // Single channel sensors
+        assert!(super::validate_channels(Some(40.0), None).is_ok());
+        assert!(super::validate_channels(None, None).is_ok());
+
+        let fake_fetch_fn = |_url: &str, _api_key: &str| -> result::TTDashResult<String> {
+            return Ok(r#"{"sensor": {"sensor_index": 1, "pm2.5_atm": 21, "pm2.5_cf_1": 21, "pm2.5_cf_1_a": 2, "pm2.5_cf_1_b": 40}}"#.to_string());
+        };
+        assert!(super::get_air_quality_ext(&creds(), Correction::None, fake_fetch_fn).is_err());
+    }
+
+    fn reading(pm25_ugm3: f32) -> super::AirQuality {
+        return super::AirQuality{
+            raw_pm25_ugm3: pm25_ugm3,
+            pm25_ugm3: pm25_ugm3,
+            aqi: super::aqi::from_pm25(pm25_ugm3),
+            pm25_10min_ugm3: None,
+            pm25_60min_ugm3: None,
+            humidity: None,
+            temperature_f: None,
+            confidence: None,
+            sensor_count: 1,
+            nowcast_ugm3: None,
+            trend: super::Trend::Steady,
+        };
+    }
+
+    // Feeds one reading a minute, oldest first; returns the last result.
+    fn run_history(history: &mut super::History, start: i64, values: &[f32]) -> super::AirQuality {
+        use chrono::TimeZone;
+
+        let mut last = None;
+        for (i, value) in values.iter().enumerate() {
+            let mut aq = reading(*value);
+            history.update(&chrono::Utc.timestamp_opt(start + 60 * i as i64, 0).unwrap(), &mut aq);
+            last = Some(aq);
+        }
+        return last.unwrap();
+    }
+
+    #[test]
+    fn history_averages_and_trend() {
+        let mut history = super::History::new();
+        let mut values = vec![10.0; 60];
+        values.extend(vec![20.0; 10]);
+        let aq = run_history(&mut history, 1600000000, &values);
+
+        assert_close(20.0, aq.pm25_10min_ugm3.unwrap());
+        assert_close((50.0 * 10.0 + 10.0 * 20.0) / 60.0, aq.pm25_60min_ugm3.unwrap());
+        assert_eq!(super::Trend::Rising, aq.trend);
+
+        let aq = run_history(&mut history, 1600000000 + 70 * 60, &vec![10.0; 10]);
+        assert_eq!(super::Trend::Falling, aq.trend);
+
+        let aq = run_history(&mut history, 1600000000 + 80 * 60, &vec![11.0; 60]);
+        assert_eq!(super::Trend::Steady, aq.trend);
+    }
 
-        assert_eq!(4.66, aq.raw_pm25_ugm3);
+    #[test]
+    fn nowcast() {
+        let mut history = super::History::new();
+        let aq = run_history(&mut history, 1600000000, &vec![10.0; 12 * 60]);
+        assert_close(10.0, aq.nowcast_ugm3.unwrap());
+
+        // Weight bottoms out at 0.5:
+        // (100 + 10 * (0.5 + ... + 0.5^11)) / (1 + 0.5 + ... + 0.5^11)
+        let mut history = super::History::new();
+        let mut values = vec![10.0; 11 * 60];
+        values.extend(vec![100.0; 60]);
+        let aq = run_history(&mut history, 1600000000, &values);
+        assert_close(55.011, aq.nowcast_ugm3.unwrap());
+        assert_eq!(super::aqi::from_pm25(55.011), aq.aqi);
+    }
+
+    #[test]
+    fn nowcast_needs_two_recent_hours() {
+        let mut history = super::History::new();
+        let aq = run_history(&mut history, 1600000000, &vec![10.0; 30]);
+        assert_eq!(None, aq.nowcast_ugm3);
+        assert_close(10.0, aq.smoothed_pm25_ugm3());
+
+        // Readings older than 12 hours are dropped.
+        let aq = run_history(&mut history, 1600000000 + 13 * 3600, &vec![10.0; 1]);
+        assert_eq!(None, aq.nowcast_ugm3);
+        assert_eq!(1, history.readings.len());
+    }
+
+    #[test]
+    fn brief_spike() {
+        let mut history = super::History::new();
+        let mut values = vec![5.0; 119];
+        values.push(300.0);
+        let aq = run_history(&mut history, 1600000000, &values);
+
+        // Hourly averages are (59 * 5 + 300) / 60 and 5, weight 5 / 9.9167.
+        assert_close(8.269, aq.nowcast_ugm3.unwrap());
+        assert_eq!(super::aqi::Category::Good, aq.aqi.category);
+        assert_eq!(super::Trend::Rising, aq.trend);
+    }
+
+    #[test]
+    fn epa_correction() {
+        assert_close(6.68, correct_pm25(Correction::Epa, 10.0, 8.0, Some(50.0)));
+        assert_close(0.786 * 100.0 - 0.0862 * 50.0 + 5.75, correct_pm25(Correction::Epa, 100.0, 80.0, Some(50.0)));
+        assert_close(2.966 + 0.69 * 300.0 + 8.84e-4 * 300.0 * 300.0, correct_pm25(Correction::Epa, 300.0, 250.0, Some(50.0)));
+
+        // Never negative
+        assert_eq!(0.0, correct_pm25(Correction::Epa, 0.0, 0.0, Some(100.0)));
+
+        // Falls back to cf=atm without humidity
+        assert_eq!(8.0, correct_pm25(Correction::Epa, 10.0, 8.0, None));
+    }
+
+    #[test]
+    fn epa_correction_is_continuous() {
+        for boundary in vec![30.0, 50.0, 210.0, 260.0] {
+            let below = super::epa_correction(boundary - 0.001, 40.0);
+            let at = super::epa_correction(boundary, 40.0);
+            assert!((below - at).abs() < 0.05, "discontinuity at {}: {} vs {}", boundary, below, at);
+        }
+    }
+
+    #[test]
+    fn lrapa_and_aqandu_corrections() {
+        assert_close(4.34, correct_pm25(Correction::Lrapa, 12.0, 10.0, None));
+        assert_close(10.43, correct_pm25(Correction::AqAndU, 12.0, 10.0, None));
+        assert_eq!(0.0, correct_pm25(Correction::Lrapa, 1.0, 1.0, None));
+    }
+
+    #[test]
+    fn parse_correction() {
+        assert_eq!(Ok(Correction::Epa), "EPA".parse::<Correction>());
+        assert_eq!(Ok(Correction::AqAndU), "aqandu".parse::<Correction>());
+        assert!("bogus".parse::<Correction>().is_err());
     }
 }
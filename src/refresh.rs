@@ -0,0 +1,269 @@
+// When to refresh the panel, and how. A full refresh flashes the whole panel
+// and clears ghosting; a partial one is quick and quiet but leaves a little
+// ghosting behind each time. So rather than redrawing on a timer, each new
+// frame is scored against what's on screen: how much of it changed, how long
+// each section has been out of date, and how long it's been since the last
+// full refresh.
+extern crate chrono;
+extern crate image;
+extern crate std;
+
+use crate::layout;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    Skip,
+    Partial,
+    Full,
+}
+
+// What people look at the dashboard for, so changes are shown right away.
+const URGENT_SECTIONS : [layout::Section; 1] = [layout::Section::BigCountdown];
+
+// Once this much of the screen has changed it's worth showing right away,
+// whichever sections it's in.
+const REFRESH_FRACTION : f32 = 0.1;
+// Past this, a partial refresh would cover most of the panel anyway, so it
+// may as well be a full one and clear the ghosting.
+const FULL_FRACTION : f32 = 0.5;
+
+pub struct Policy {
+    regions: Vec<layout::Region>,
+    partial_refresh: bool,
+    full_refresh_interval: chrono::Duration,
+    // How long a section can be out of date before it's refreshed.
+    max_staleness: chrono::Duration,
+    shown: Option<image::RgbImage>,
+    last_full_refresh: Option<chrono::DateTime<chrono::Utc>>,
+    // When each of `regions` first stopped matching what's on screen.
+    stale_since: Vec<Option<chrono::DateTime<chrono::Utc>>>,
+    // Asked for a full refresh, whatever changed.
+    forced: bool,
+}
+
+impl Policy {
+    // For frames drawn with `layout`, before they're rotated for the panel.
+    // Without `partial_refresh` every refresh flashes the panel, so sections
+    // are left out of date for longer.
+    pub fn new(layout: &layout::Layout, partial_refresh: bool, full_refresh_interval: chrono::Duration) -> Policy {
+        let regions = layout.regions();
+        let region_count = regions.len();
+        return Policy{
+            regions: regions,
+            partial_refresh: partial_refresh,
+            full_refresh_interval: full_refresh_interval,
+            max_staleness: chrono::Duration::minutes(if partial_refresh { 1 } else { 10 }),
+            shown: None,
+            last_full_refresh: None,
+            stale_since: vec![None; region_count],
+            forced: false,
+        };
+    }
+
+    // The next frame gets a full refresh, even if nothing changed.
+    pub fn force_full(&mut self) {
+        self.forced = true;
+    }
+
+    // For frames drawn with a different layout, like another screen's. The
+    // whole panel changes, so that's a full refresh too.
+    pub fn change_layout(&mut self, layout: &layout::Layout) {
+        self.regions = layout.regions();
+        self.stale_since = vec![None; self.regions.len()];
+        self.forced = true;
+    }
+
+    pub fn decide(&mut self, image: &image::RgbImage, now: &chrono::DateTime<chrono::Utc>) -> Decision {
+        let shown = match self.shown {
+            Some(ref shown) if shown.dimensions() == image.dimensions() && !self.forced => shown,
+            _ => return Decision::Full,
+        };
+
+        let whole = layout::Rect::new(0, 0, image.width() as i32, image.height() as i32);
+        let changed = changed_pixels(shown, image, &whole) as f32 / (image.width() * image.height()) as f32;
+        let mut urgent = changed >= REFRESH_FRACTION;
+        for (region, stale_since) in self.regions.iter().zip(self.stale_since.iter_mut()) {
+            if changed_pixels(shown, image, &region.rect) == 0 {
+                *stale_since = None;
+                continue;
+            }
+            let since = *stale_since.get_or_insert(*now);
+            if URGENT_SECTIONS.contains(&region.section) || *now - since >= self.max_staleness {
+                debug!("{:?} has been out of date since {}.", region.section, since);
+                urgent = true;
+            }
+        }
+        if !urgent {
+            return Decision::Skip;
+        }
+
+        let full_refresh_due = match self.last_full_refresh {
+            Some(last) => *now - last >= self.full_refresh_interval,
+            None => true,
+        };
+        if !self.partial_refresh || full_refresh_due || changed >= FULL_FRACTION {
+            return Decision::Full;
+        }
+        return Decision::Partial;
+    }
+
+    // Once `image` is on screen, after `decide` said to refresh.
+    pub fn shown(&mut self, image: &image::RgbImage, decision: Decision, now: &chrono::DateTime<chrono::Utc>) {
+        match decision {
+            Decision::Skip => return,
+            Decision::Partial => {},
+            Decision::Full => {
+                self.last_full_refresh = Some(*now);
+                self.forced = false;
+            },
+        }
+        self.shown = Some(image.clone());
+        for stale_since in self.stale_since.iter_mut() {
+            *stale_since = None;
+        }
+    }
+}
+
+fn changed_pixels(old: &image::RgbImage, new: &image::RgbImage, rect: &layout::Rect) -> usize {
+    let right = std::cmp::min(rect.right(), new.width() as i32);
+    let bottom = std::cmp::min(rect.bottom(), new.height() as i32);
+    let mut changed = 0;
+    for y in std::cmp::max(0, rect.y)..bottom {
+        for x in std::cmp::max(0, rect.x)..right {
+            if old.get_pixel(x as u32, y as u32) != new.get_pixel(x as u32, y as u32) {
+                changed += 1;
+            }
+        }
+    }
+    return changed;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Decision;
+    use super::Policy;
+    use crate::layout;
+
+    // A countdown across the top quarter, and the upcoming trains below it.
+    const LAYOUT : &str = r#"{
+        "width": 64,
+        "height": 64,
+        "root": {"column": [
+            {"size": 16, "section": "big_countdown"},
+            {"section": "upcoming_trains"}
+        ]}
+    }"#;
+
+    fn policy(partial_refresh: bool) -> Policy {
+        let layout = layout::Layout::from_json(LAYOUT).unwrap();
+        return Policy::new(&layout, partial_refresh, chrono::Duration::minutes(30));
+    }
+
+    fn blank() -> image::RgbImage {
+        return image::RgbImage::from_pixel(64, 64, image::Rgb([255, 255, 255]));
+    }
+
+    // `blank` with `count` black pixels from (0, y) along the row.
+    fn marked(y: u32, count: u32) -> image::RgbImage {
+        let mut imgbuf = blank();
+        for i in 0..count {
+            imgbuf.put_pixel(i % 64, y + i / 64, image::Rgb([0, 0, 0]));
+        }
+        return imgbuf;
+    }
+
+    fn at(seconds: i64) -> chrono::DateTime<chrono::Utc> {
+        use chrono::TimeZone;
+        return chrono::Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap() + chrono::Duration::seconds(seconds);
+    }
+
+    // Decides on each (seconds, frame) in turn, showing those it says to.
+    fn run(policy: &mut Policy, frames: &[(i64, image::RgbImage)]) -> Vec<Decision> {
+        return frames.iter().map(|(seconds, frame)| {
+            let decision = policy.decide(frame, &at(*seconds));
+            policy.shown(frame, decision, &at(*seconds));
+            return decision;
+        }).collect();
+    }
+
+    #[test]
+    fn nothing_changed() {
+        assert_eq!(
+            vec![Decision::Full, Decision::Skip, Decision::Skip],
+            run(&mut policy(true), &[(0, blank()), (5, blank()), (3600, blank())]));
+    }
+
+    #[test]
+    fn countdown_is_urgent() {
+        assert_eq!(
+            vec![Decision::Full, Decision::Partial, Decision::Partial],
+            run(&mut policy(true), &[(0, blank()), (5, marked(0, 1)), (10, marked(1, 1))]));
+        // Without partial refresh, it's worth a flash.
+        assert_eq!(
+            vec![Decision::Full, Decision::Full],
+            run(&mut policy(false), &[(0, blank()), (5, marked(0, 1))]));
+    }
+
+    #[test]
+    fn stale_sections() {
+        let train = marked(20, 1);
+        assert_eq!(
+            vec![Decision::Full, Decision::Skip, Decision::Skip, Decision::Partial, Decision::Skip],
+            run(&mut policy(true), &[(0, blank()), (5, train.clone()), (30, train.clone()), (65, train.clone()), (70, train)]));
+
+        // A change that's undone before it's shown never was.
+        assert_eq!(
+            vec![Decision::Full, Decision::Skip, Decision::Skip, Decision::Skip, Decision::Skip],
+            run(&mut policy(true), &[(0, blank()), (5, marked(20, 1)), (30, blank()), (65, marked(20, 1)), (100, marked(20, 1))]));
+
+        // Flashing the panel is put off for longer.
+        assert_eq!(
+            vec![Decision::Full, Decision::Skip, Decision::Skip, Decision::Full],
+            run(&mut policy(false), &[(0, blank()), (5, marked(20, 1)), (65, marked(20, 1)), (605, marked(20, 1))]));
+    }
+
+    #[test]
+    fn changed_fraction() {
+        // 10% of the screen is shown right away; past half, in full.
+        assert_eq!(
+            vec![Decision::Full, Decision::Skip, Decision::Partial, Decision::Full],
+            run(&mut policy(true), &[(0, blank()), (5, marked(20, 400)), (10, marked(20, 420)), (15, marked(0, 2600))]));
+    }
+
+    #[test]
+    fn full_refresh_interval() {
+        assert_eq!(
+            vec![Decision::Full, Decision::Partial, Decision::Full, Decision::Partial],
+            run(&mut policy(true), &[(0, blank()), (60, marked(0, 1)), (1800, marked(1, 1)), (1860, marked(2, 1))]));
+    }
+
+    #[test]
+    fn forced() {
+        let mut policy = policy(true);
+        run(&mut policy, &[(0, blank())]);
+        policy.force_full();
+        assert_eq!(
+            vec![Decision::Full, Decision::Skip],
+            run(&mut policy, &[(5, blank()), (10, blank())]));
+    }
+
+    #[test]
+    fn layout_changed() {
+        let mut policy = policy(true);
+        run(&mut policy, &[(0, blank())]);
+        policy.change_layout(&layout::Layout::from_json(r#"{
+            "width": 64, "height": 64, "root": {"section": "alerts"}
+        }"#).unwrap());
+        // No more countdown to hurry for.
+        assert_eq!(
+            vec![Decision::Full, Decision::Skip],
+            run(&mut policy, &[(5, blank()), (10, marked(0, 1))]));
+    }
+
+    #[test]
+    fn size_changed() {
+        let mut policy = policy(true);
+        run(&mut policy, &[(0, blank())]);
+        assert_eq!(Decision::Full, policy.decide(&image::RgbImage::new(32, 32), &at(5)));
+    }
+}
@@ -21,6 +21,9 @@ pub enum TTDashError {
     XmlError(serde_xml_rs::Error),
     ProstDecodeError(prost::DecodeError),
     SpiError(rppal::spi::Error),
+    // The e-paper panel stayed busy for this long.
+    PanelNotResponding(std::time::Duration),
+    UartError(rppal::uart::Error),
     ImageError(image::ImageError),
     AnyhowError(anyhow::Error),
 }
@@ -59,6 +62,12 @@ impl std::fmt::Display for TTDashError {
             TTDashError::SpiError(ref err) => {
                 return write!(f, "SPI Error: {}", err);
             },
+            TTDashError::PanelNotResponding(ref waited) => {
+                return write!(f, "Panel not responding: still busy after {:?}", waited);
+            },
+            TTDashError::UartError(ref err) => {
+                return write!(f, "UART Error: {}", err);
+            },
             TTDashError::ImageError(ref err) => {
                 return write!(f, "Image Error: {}", err);
             },
@@ -81,6 +90,8 @@ impl std::error::Error for TTDashError {
             TTDashError::XmlError(_) => "XmlError",
             TTDashError::ProstDecodeError(_) => "ProstDecodeError",
             TTDashError::SpiError(_) => "SpiError",
+            TTDashError::PanelNotResponding(_) => "PanelNotResponding",
+            TTDashError::UartError(_) => "UartError",
             TTDashError::ImageError(_) => "ImageError",
             TTDashError::AnyhowError(_) => "AnyhowError",
         }
@@ -139,6 +150,12 @@ impl From<rppal::spi::Error> for TTDashError {
     }
 }
 
+impl From<rppal::uart::Error> for TTDashError {
+    fn from(err: rppal::uart::Error) -> TTDashError {
+        return TTDashError::UartError(err);
+    }
+}
+
 impl From<image::ImageError> for TTDashError {
     fn from(err: image::ImageError) -> TTDashError {
         return TTDashError::ImageError(err);
@@ -0,0 +1,289 @@
+// The dashboard can take turns showing a few screens, each a layout of its
+// own, rather than fitting everything into one: the commute, the weather in
+// more detail, and service alerts. Which one's up depends on the time of
+// day, how long the current one's been up, and whether there are alerts.
+extern crate chrono;
+extern crate std;
+
+use crate::layout;
+use crate::night;
+use crate::result;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    // Trains and buses, with the weather and air quality alongside: the
+    // original dashboard.
+    Commute,
+    // Today and the next few days, with an hourly graph.
+    Weather,
+    // Service alerts, only shown while there are any.
+    Alerts,
+}
+
+impl std::str::FromStr for Kind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Kind, String> {
+        return match s {
+            "commute" => Ok(Kind::Commute),
+            "weather" => Ok(Kind::Weather),
+            "alerts" => Ok(Kind::Alerts),
+            _ => Err(format!("Unknown screen '{}', expected commute, weather or alerts", s)),
+        };
+    }
+}
+
+impl Kind {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Kind::Commute => "commute",
+            Kind::Weather => "weather",
+            Kind::Alerts => "alerts",
+        };
+    }
+}
+
+// A screen and when it can be shown.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Spec {
+    pub kind: Kind,
+    // Local times the screen is limited to, if any.
+    pub hours: Option<night::QuietHours>,
+}
+
+// NAME or NAME@HH:MM-HH:MM, e.g. commute@07:00-09:30.
+impl std::str::FromStr for Spec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Spec, String> {
+        return match s.split_once('@') {
+            Some((kind, hours)) => Ok(Spec{kind: kind.parse()?, hours: Some(hours.parse()?)}),
+            None => Ok(Spec{kind: s.parse()?, hours: None}),
+        };
+    }
+}
+
+pub struct Screen {
+    pub spec: Spec,
+    pub layout: layout::Layout,
+}
+
+impl Screen {
+    pub fn name(&self) -> &'static str {
+        return self.spec.kind.name();
+    }
+
+    fn showable(&self, local_time: chrono::NaiveTime, alerts: bool) -> bool {
+        if self.spec.kind == Kind::Alerts && !alerts {
+            return false;
+        }
+        return self.spec.hours.map(|hours| hours.contains(local_time)).unwrap_or(true);
+    }
+}
+
+// Screens other than the commute share its size and scale, and lay
+// themselves out to suit.
+pub fn layout(kind: Kind, commute: &layout::Layout) -> result::TTDashResult<layout::Layout> {
+    let size = |pixels: i32| (pixels as f32 * commute.scale).round() as i32;
+    let title = if commute.width >= commute.height {
+        format!(r#"{{"size": {}, "row": [{{"size": {}}}, {{"size": {}, "section": "title"}}, {{"section": "air_quality"}}]}}"#,
+                size(45), size(10), size(180))
+    } else {
+        format!(r#"{{"size": {}, "padding": {}, "section": "title"}}, {{"size": {}, "padding": {}, "section": "air_quality"}}"#,
+                size(52), size(6), size(56), size(4))
+    };
+    let body = match kind {
+        Kind::Commute => return Ok(commute.clone()),
+        Kind::Weather if commute.width >= commute.height => format!(
+            r#"{{"row": [{{"size": {}, "section": "current_weather"}}, {{"size": {}}}, {{"section": "daily_forecast"}}]}},
+               {{"size": 1, "section": "divider"}},
+               {{"padding": {}, "section": "hourly_forecast"}}"#,
+            commute.width as i32 * 3 / 8, size(16), size(10)),
+        Kind::Weather => format!(
+            r#"{{"section": "current_weather"}},
+               {{"padding": {}, "section": "daily_forecast"}},
+               {{"size": 1, "section": "divider"}},
+               {{"padding": {}, "section": "hourly_forecast"}}"#,
+            size(10), size(10)),
+        Kind::Alerts => format!(r#"{{"padding": {}, "section": "alerts"}}"#, size(20)),
+    };
+    return layout::Layout::from_json(&format!(
+        r#"{{"width": {}, "height": {}, "scale": {}, "root": {{"column": [{}, {{"size": 1, "section": "divider"}}, {}]}}}}"#,
+        commute.width, commute.height, commute.scale, title, body));
+}
+
+// Takes turns through the screens, each for `dwell`, skipping any that
+// can't be shown right now. The alerts screen jumps the queue when alerts
+// first come up.
+pub struct Schedule {
+    screens: Vec<Screen>,
+    dwell: chrono::Duration,
+    current: usize,
+    // When the current screen went up.
+    since: Option<chrono::DateTime<chrono::Utc>>,
+    alerts: bool,
+    // Asked to move on without waiting out the dwell time.
+    skip: bool,
+}
+
+impl Schedule {
+    // The first screen's shown when none of them can be.
+    pub fn new(screens: Vec<Screen>, dwell: chrono::Duration) -> Schedule {
+        assert!(!screens.is_empty(), "no screens");
+        return Schedule{
+            screens: screens,
+            dwell: dwell,
+            current: 0,
+            since: None,
+            alerts: false,
+            skip: false,
+        };
+    }
+
+    pub fn current(&self) -> &Screen {
+        return &self.screens[self.current];
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Screen> {
+        return self.screens.iter().find(|screen| screen.name() == name);
+    }
+
+    // Moves on at the next update.
+    pub fn skip(&mut self) {
+        self.skip = true;
+    }
+
+    // Picks the screen to show now. Returns whether it changed.
+    pub fn update(&mut self, now: &chrono::DateTime<chrono::Utc>, local_time: chrono::NaiveTime, alerts: bool) -> bool {
+        let alerts_started = alerts && !self.alerts;
+        self.alerts = alerts;
+        let due = self.skip || self.since.map(|since| *now - since >= self.dwell).unwrap_or(false);
+        self.skip = false;
+
+        let showable: Vec<bool> = self.screens.iter().map(|screen| screen.showable(local_time, alerts)).collect();
+        let alerts_screen = self.screens.iter().position(|screen| screen.spec.kind == Kind::Alerts);
+        let next = match alerts_screen {
+            Some(alerts_screen) if alerts_started && showable[alerts_screen] => alerts_screen,
+            _ if due || !showable[self.current] => {
+                let count = self.screens.len();
+                (1..=count).map(|i| (self.current + i) % count).find(|i| showable[*i]).unwrap_or(0)
+            },
+            _ => self.current,
+        };
+
+        let changed = next != self.current;
+        if changed || due || self.since.is_none() {
+            self.since = Some(*now);
+        }
+        self.current = next;
+        return changed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Kind;
+    use super::Schedule;
+    use super::Screen;
+    use super::Spec;
+    use crate::layout;
+
+    fn time(hour: u32, minute: u32) -> chrono::NaiveTime {
+        return chrono::NaiveTime::from_hms_opt(hour, minute, 0).unwrap();
+    }
+
+    fn at(seconds: i64) -> chrono::DateTime<chrono::Utc> {
+        use chrono::TimeZone;
+        return chrono::Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap() + chrono::Duration::seconds(seconds);
+    }
+
+    fn schedule(specs: &[&str]) -> Schedule {
+        let commute = layout::Layout::builtin(640, 384).unwrap();
+        let screens = specs.iter().map(|spec| {
+            let spec: Spec = spec.parse().unwrap();
+            return Screen{spec: spec, layout: super::layout(spec.kind, &commute).unwrap()};
+        }).collect();
+        return Schedule::new(screens, chrono::Duration::seconds(60));
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Ok(Spec{kind: Kind::Weather, hours: None}), "weather".parse());
+        assert_eq!(Ok(Spec{kind: Kind::Commute, hours: Some("07:00-09:30".parse().unwrap())}), "commute@07:00-09:30".parse());
+        assert!("sports".parse::<Spec>().is_err());
+        assert!("commute@07:00".parse::<Spec>().is_err());
+    }
+
+    #[test]
+    fn layouts() {
+        for (width, height) in vec![(640, 384), (800, 480), (400, 300), (128, 296), (384, 640), (480, 800), (300, 400), (296, 128)] {
+            let commute = layout::Layout::builtin(width, height).unwrap();
+            for kind in vec![Kind::Commute, Kind::Weather, Kind::Alerts] {
+                let layout = super::layout(kind, &commute).unwrap();
+                assert_eq!((width, height, commute.scale), (layout.width, layout.height, layout.scale));
+                let sections: Vec<layout::Section> = layout.regions().iter().map(|region| region.section).collect();
+                assert_eq!(kind == Kind::Commute, sections.contains(&layout::Section::BigCountdown), "{:?} {}x{}", kind, width, height);
+                assert_eq!(kind == Kind::Weather, sections.contains(&layout::Section::HourlyForecast), "{:?} {}x{}", kind, width, height);
+                assert_eq!(kind == Kind::Alerts, sections.contains(&layout::Section::Alerts), "{:?} {}x{}", kind, width, height);
+            }
+        }
+    }
+
+    #[test]
+    fn dwell() {
+        let mut schedule = schedule(&["commute", "weather", "alerts"]);
+        let noon = time(12, 0);
+        let mut shown = vec![];
+        for seconds in vec![0, 30, 60, 90, 120, 180] {
+            schedule.update(&at(seconds), noon, false);
+            shown.push(schedule.current().name());
+        }
+        // Without alerts, the alerts screen is skipped.
+        assert_eq!(vec!["commute", "commute", "weather", "weather", "commute", "weather"], shown);
+
+        // Skipping moves on straight away, and restarts the dwell time.
+        schedule.skip();
+        assert!(schedule.update(&at(200), noon, false));
+        assert_eq!("commute", schedule.current().name());
+        assert!(!schedule.update(&at(250), noon, false));
+    }
+
+    #[test]
+    fn alerts() {
+        let mut schedule = schedule(&["commute", "weather", "alerts"]);
+        let noon = time(12, 0);
+        schedule.update(&at(0), noon, false);
+        assert!(schedule.update(&at(10), noon, true));
+        assert_eq!("alerts", schedule.current().name());
+
+        // Then back into the rotation, alerts included.
+        let mut shown = vec![];
+        for seconds in vec![70, 130, 190] {
+            schedule.update(&at(seconds), noon, true);
+            shown.push(schedule.current().name());
+        }
+        assert_eq!(vec!["commute", "weather", "alerts"], shown);
+
+        // And off the screen once they're over.
+        assert!(schedule.update(&at(200), noon, false));
+        assert_eq!("commute", schedule.current().name());
+    }
+
+    #[test]
+    fn hours() {
+        let mut schedule = schedule(&["commute@07:00-10:00", "weather"]);
+        schedule.update(&at(0), time(8, 0), false);
+        assert_eq!("commute", schedule.current().name());
+        // Out of its hours, a screen gives way right away, and without any
+        // others it can show, the schedule stays on the rest.
+        assert!(schedule.update(&at(10), time(10, 0), false));
+        assert_eq!("weather", schedule.current().name());
+        assert!(!schedule.update(&at(100), time(11, 0), false));
+        assert_eq!("weather", schedule.current().name());
+
+        // The first screen's the fallback when there's nothing else.
+        let mut schedule = self::schedule(&["weather@07:00-10:00", "alerts"]);
+        schedule.update(&at(0), time(12, 0), false);
+        assert_eq!("weather", schedule.current().name());
+    }
+}
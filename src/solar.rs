@@ -0,0 +1,113 @@
+// Sunrise and sunset times, computed locally using the "sunrise equation":
+// https://en.wikipedia.org/wiki/Sunrise_equation
+// Good to within a minute or two at mid-latitudes, which is plenty for
+// shading a chart.
+extern crate chrono;
+extern crate std;
+
+const JULIAN_DATE_UNIX_EPOCH: f64 = 2440587.5;
+const JULIAN_DATE_J2000: f64 = 2451545.0;
+const SECONDS_PER_DAY: f64 = 86400.0;
+
+// Accounts for atmospheric refraction and the radius of the solar disc.
+const SUNRISE_ELEVATION_DEGREES: f64 = -0.833;
+const EARTH_AXIAL_TILT_DEGREES: f64 = 23.4397;
+
+#[derive(Debug, Clone, Copy)]
+pub struct SunTimes {
+    pub sunrise: chrono::DateTime<chrono::Utc>,
+    pub sunset: chrono::DateTime<chrono::Utc>,
+}
+
+fn julian_date_to_utc(julian_date: f64) -> Option<chrono::DateTime<chrono::Utc>> {
+    let seconds = (julian_date - JULIAN_DATE_UNIX_EPOCH) * SECONDS_PER_DAY;
+    return chrono::DateTime::from_timestamp(seconds.round() as i64, 0);
+}
+
+// Returns None during polar day or polar night, when the sun doesn't cross
+// the horizon on the given date. Longitude is east-positive.
+pub fn sun_times(date: chrono::NaiveDate, latitude: f64, longitude: f64) -> Option<SunTimes> {
+    use chrono::Datelike;
+
+    // Whole days between J2000 (noon UTC, Jan 1 2000) and noon UTC on `date`.
+    let days_since_j2000 = (date.num_days_from_ce()
+        - chrono::NaiveDate::from_ymd_opt(2000, 1, 1)?.num_days_from_ce()) as f64;
+
+    let mean_solar_noon = days_since_j2000 + 0.0008 - longitude / 360.0;
+    let mean_anomaly = (357.5291 + 0.98560028 * mean_solar_noon).rem_euclid(360.0).to_radians();
+    let center = 1.9148 * mean_anomaly.sin()
+        + 0.0200 * (2.0 * mean_anomaly).sin()
+        + 0.0003 * (3.0 * mean_anomaly).sin();
+    let ecliptic_longitude =
+        (mean_anomaly.to_degrees() + center + 180.0 + 102.9372).rem_euclid(360.0).to_radians();
+    let solar_transit = JULIAN_DATE_J2000 + mean_solar_noon
+        + 0.0053 * mean_anomaly.sin()
+        - 0.0069 * (2.0 * ecliptic_longitude).sin();
+
+    let declination = (ecliptic_longitude.sin() * EARTH_AXIAL_TILT_DEGREES.to_radians().sin()).asin();
+    let latitude = latitude.to_radians();
+    let cos_hour_angle = (SUNRISE_ELEVATION_DEGREES.to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+    if cos_hour_angle.abs() > 1.0 {
+        return None;
+    }
+    let hour_angle_days = cos_hour_angle.acos().to_degrees() / 360.0;
+
+    return Some(SunTimes{
+        sunrise: julian_date_to_utc(solar_transit - hour_angle_days)?,
+        sunset: julian_date_to_utc(solar_transit + hour_angle_days)?,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate chrono;
+    extern crate chrono_tz;
+
+    use super::sun_times;
+
+    const NYC_LAT: f64 = 40.7128;
+    const NYC_LNG: f64 = -74.0060;
+
+    fn assert_close(expected_local: &str, actual: &chrono::DateTime<chrono::Utc>) {
+        use chrono::TimeZone;
+
+        let expected = chrono_tz::US::Eastern.from_local_datetime(
+            &chrono::NaiveDateTime::parse_from_str(expected_local, "%Y-%m-%d %H:%M")
+                .expect("parsing expected time"))
+            .unwrap();
+        let delta = (expected.timestamp() - actual.timestamp()).abs();
+        assert!(delta <= 120, "expected {} got {} ({}s off)",
+                expected, actual.with_timezone(&chrono_tz::US::Eastern), delta);
+    }
+
+    // Reference values from the NOAA solar calculator:
+    // https://gml.noaa.gov/grad/solcalc/
+    #[test]
+    fn nyc_summer_solstice() {
+        let times = sun_times(chrono::NaiveDate::from_ymd_opt(2020, 6, 20).unwrap(), NYC_LAT, NYC_LNG).unwrap();
+        assert_close("2020-06-20 05:25", &times.sunrise);
+        assert_close("2020-06-20 20:31", &times.sunset);
+    }
+
+    #[test]
+    fn nyc_winter_solstice() {
+        let times = sun_times(chrono::NaiveDate::from_ymd_opt(2020, 12, 21).unwrap(), NYC_LAT, NYC_LNG).unwrap();
+        assert_close("2020-12-21 07:17", &times.sunrise);
+        assert_close("2020-12-21 16:32", &times.sunset);
+    }
+
+    #[test]
+    fn nyc_equinox() {
+        let times = sun_times(chrono::NaiveDate::from_ymd_opt(2021, 3, 20).unwrap(), NYC_LAT, NYC_LNG).unwrap();
+        assert_close("2021-03-20 06:59", &times.sunrise);
+        assert_close("2021-03-20 19:08", &times.sunset);
+    }
+
+    #[test]
+    fn polar_night_and_day() {
+        // Tromsø, Norway
+        assert!(sun_times(chrono::NaiveDate::from_ymd_opt(2020, 12, 21).unwrap(), 69.6492, 18.9553).is_none());
+        assert!(sun_times(chrono::NaiveDate::from_ymd_opt(2020, 6, 21).unwrap(), 69.6492, 18.9553).is_none());
+    }
+}
@@ -6,6 +6,13 @@ use crate::drawing;
 use crate::result;
 use crate::webclient_api;
 
+// An unplanned service change, like delays or a suspension.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alert {
+    pub lines: Vec<String>,
+    pub summary: String,
+}
+
 pub struct ProcessedData {
     pub upcoming_trains: Vec<(i64, String)>,
     pub upcoming_outbound_trains: Vec<(i64, String)>,
@@ -13,6 +20,9 @@ pub struct ProcessedData {
     pub big_countdown_line: Option<String>,
     pub station_name: String,
     pub data_timestamp: i64,
+    pub alerts: Vec<Alert>,
+    // Lines with any of `alerts`.
+    pub alerted_lines: Vec<String>,
 }
 
 impl ProcessedData {
@@ -24,6 +34,8 @@ impl ProcessedData {
             big_countdown_line: None,
             station_name: "".to_string(),
             data_timestamp: 0,
+            alerts: vec![],
+            alerted_lines: vec![],
         };
     }
 }
@@ -75,6 +87,7 @@ fn process_data(data: &webclient_api::StationStatus) -> result::TTDashResult<Pro
         outbound_arrivals.sort_by_key(|x| x.0);
         let first_arrival_ts = arrivals[0].0;
         let first_arrival_line = arrivals[0].1.clone();
+        let alerts = alerts(data);
 
         return Ok(ProcessedData{
             upcoming_trains: arrivals,
@@ -83,6 +96,84 @@ fn process_data(data: &webclient_api::StationStatus) -> result::TTDashResult<Pro
             big_countdown_line: Some(first_arrival_line),
             station_name: data.name().to_string(),
             data_timestamp: data.data_timestamp(),
+            alerted_lines: alerted_lines(&alerts),
+            alerts: alerts,
         });
     }
 }
+
+fn alerts(data: &webclient_api::StationStatus) -> Vec<Alert> {
+    return data.status_message.iter()
+        .filter(|message| !message.planned())
+        .map(|message| {
+            let mut lines: Vec<String> = message.affected_line.iter()
+                .map(|affected| affected.line().to_string())
+                .collect();
+            lines.sort();
+            lines.dedup();
+            return Alert{lines: lines, summary: message.summary().to_string()};
+        })
+        .collect();
+}
+
+fn alerted_lines(alerts: &[Alert]) -> Vec<String> {
+    let mut lines: Vec<String> = alerts.iter().flat_map(|alert| alert.lines.iter().cloned()).collect();
+    lines.sort();
+    lines.dedup();
+    return lines;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::webclient_api;
+
+    fn message(summary: &str, lines: &[&str], planned: bool) -> webclient_api::SubwayStatusMessage {
+        return webclient_api::SubwayStatusMessage{
+            summary: Some(summary.to_string()),
+            affected_line: lines.iter().map(|line| webclient_api::AffectedLineStatus{
+                line: Some(line.to_string()),
+                ..Default::default()
+            }).collect(),
+            planned: Some(planned),
+            ..Default::default()
+        };
+    }
+
+    fn station(messages: Vec<webclient_api::SubwayStatusMessage>) -> webclient_api::StationStatus {
+        return webclient_api::StationStatus{
+            name: Some("36 St".to_string()),
+            status_message: messages,
+            ..Default::default()
+        };
+    }
+
+    #[test]
+    fn alerts() {
+        let station = station(vec![
+            message("Northbound R and N trains are delayed", &["R", "N", "R"], false),
+            message("Weekend work", &["W"], true),
+            message("Suspended", &["Q"], false),
+        ]);
+        assert_eq!(
+            vec![
+                super::Alert{lines: vec!["N".to_string(), "R".to_string()], summary: "Northbound R and N trains are delayed".to_string()},
+                super::Alert{lines: vec!["Q".to_string()], summary: "Suspended".to_string()},
+            ],
+            super::alerts(&station));
+        assert!(super::alerts(&self::station(vec![])).is_empty());
+    }
+
+    #[test]
+    fn alerted_lines() {
+        // Planned work doesn't count, unless the line has other trouble too.
+        let station = station(vec![
+            message("Delays", &["R", "N"], false),
+            message("Weekend work", &["W", "R"], true),
+            message("Suspended", &["Q"], false),
+        ]);
+        assert_eq!(vec!["N", "Q", "R"], super::alerted_lines(&super::alerts(&station)));
+
+        let station = self::station(vec![message("Weekend work", &["W"], true)]);
+        assert!(super::alerted_lines(&super::alerts(&station)).is_empty());
+    }
+}
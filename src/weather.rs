@@ -18,6 +18,7 @@ extern crate serde_xml_rs;
 extern crate std;
 
 use crate::result;
+use crate::solar;
 
 use anyhow::Context;
 
@@ -40,6 +41,7 @@ struct NwsApiPeriod {
     number: i32,
     start_time: String,
     end_time: String,
+    is_daytime: bool,
     temperature: i32,
     short_forecast: String,
     wind_speed: String,
@@ -101,14 +103,25 @@ pub struct HourlyForecast {
     pub time: chrono::DateTime<chrono::FixedOffset>,
     pub temperature: i32,
 }
+*/
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Condition {
+    Sunny,
+    Cloudy,
+    Rain,
+    Snow,
+    Thunder,
+    Fog,
+}
 
+#[derive(Debug)]
 pub struct DailyForecast {
-    pub label: String,
-    pub temperature: i32,
+    pub start_time: chrono::DateTime<chrono::FixedOffset>,
+    pub is_daytime: bool,
     pub short_forecast: String,
+    pub condition: Option<Condition>,
 }
-*/
 
 #[derive(Debug, Clone)]
 pub struct GridForecastEntry {
@@ -124,11 +137,12 @@ pub struct GridForecast {
     pub dew_point: Vec<GridForecastEntry>,
 }
 
-#[derive(Debug)]
+// Each field is None if no forecast entry covered that hour.
+#[derive(Debug, Default)]
 pub struct DenseGridHour {
-    pub precip_prob: f32,
-    pub temperature: f32,
-    pub dew_point: f32,
+    pub precip_prob: Option<f32>,
+    pub temperature: Option<f32>,
+    pub dew_point: Option<f32>,
 }
 
 #[derive(Debug)]
@@ -136,12 +150,27 @@ pub struct DenseGridForecast {
     pub hours: std::collections::BTreeMap<chrono::DateTime<chrono::FixedOffset>, DenseGridHour>,
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub timezone: chrono_tz::Tz,
+}
+
 pub struct WeatherDisplayDay {
     pub min_t: f32,
     pub max_t: f32,
 
-    pub max_dew_point: f32,
+    pub max_dew_point: Option<f32>,
+
+    pub condition: Option<Condition>,
+    pub short_forecast: Option<String>,
 
+    // None during polar day/night.
+    pub sunrise: Option<chrono::DateTime<chrono_tz::Tz>>,
+    pub sunset: Option<chrono::DateTime<chrono_tz::Tz>>,
+
+    // Keyed by hours_since_midnight, so DST days have 23 or 25 entries.
     pub precip_by_hour: std::collections::BTreeMap<u32, f32>,
 }
 
@@ -150,15 +179,55 @@ pub struct WeatherDisplay {
     pub overall_max_t: f32,
     pub current_t: f32,
 
+    // Forecast temperature for each upcoming hour, in local time.
+    pub hourly_temps: std::collections::BTreeMap<chrono::DateTime<chrono_tz::Tz>, f32>,
+
     pub days: std::collections::BTreeMap<chrono::Date<chrono_tz::Tz>, WeatherDisplayDay>,
 }
 
+impl WeatherDisplay {
+    // Hourly temperatures from the hour `now` is in. The forecast starts an
+    // hour before it was fetched, and can go on being shown long after.
+    pub fn upcoming_temps(&self, now: i64) -> impl Iterator<Item=(&chrono::DateTime<chrono_tz::Tz>, &f32)> {
+        return self.hourly_temps.iter().filter(move |(time, _)| time.timestamp() + 3600 > now);
+    }
+}
+
 fn ctof(c: f32) -> f32 {
     return 32.0 + c * 9.0 / 5.0;
 }
 
-pub fn get_weather_display(now: i64) -> result::TTDashResult<WeatherDisplay> {
-    return get_weather_display_ext(now, real_fetch);
+// Hours elapsed since the start of the local day. Unlike hour(), this is
+// distinct for every hour of a DST transition day (0 through 24 when the
+// clocks fall back, 0 through 22 when they spring forward).
+pub fn hours_since_midnight(time: &chrono::DateTime<chrono_tz::Tz>) -> f32 {
+    let day_start = start_of_day(time.date_naive(), time.timezone()).unwrap_or(*time);
+    return (time.timestamp() - day_start.timestamp()) as f32 / 3600.0;
+}
+
+// How long the local day is: 24 hours, or 23 or 25 on DST transition days.
+pub fn hours_in_day(date: chrono::NaiveDate, timezone: chrono_tz::Tz) -> i32 {
+    let next_date = match date.succ_opt() {
+        Some(next_date) => next_date,
+        None => return 24,
+    };
+    return match (start_of_day(date, timezone), start_of_day(next_date, timezone)) {
+        (Some(start), Some(end)) => ((end.timestamp() - start.timestamp()) / 3600) as i32,
+        _ => 24,
+    };
+}
+
+fn start_of_day(date: chrono::NaiveDate, timezone: chrono_tz::Tz) -> Option<chrono::DateTime<chrono_tz::Tz>> {
+    use chrono::TimeZone;
+
+    let midnight = date.and_hms_opt(0, 0, 0).unwrap();
+    // Some zones (e.g. America/Havana) skip midnight itself on DST days.
+    return timezone.from_local_datetime(&midnight).earliest()
+        .or(timezone.from_local_datetime(&(midnight + chrono::Duration::hours(1))).earliest());
+}
+
+pub fn get_weather_display(now: i64, location: &Location) -> result::TTDashResult<WeatherDisplay> {
+    return get_weather_display_ext(now, location, real_fetch);
 }
 
 fn get_temperature_f(properties: NwsApiProperties) -> Option<f32> {
@@ -166,10 +235,7 @@ fn get_temperature_f(properties: NwsApiProperties) -> Option<f32> {
     return Some(ctof(properties.temperature?.value?));
 }
 
-fn get_weather_display_ext(now: i64, fetch_fn: fn(&str) -> result::TTDashResult<String>) -> result::TTDashResult<WeatherDisplay> {
-    use chrono::Timelike;
-    use chrono::TimeZone;
-
+fn get_weather_display_ext(now: i64, location: &Location, fetch_fn: fn(&str) -> result::TTDashResult<String>) -> result::TTDashResult<WeatherDisplay> {
     let grid_forecast = fetch_grid_forecast(fetch_fn)?;
     let dense_forecast = densify_grid_forecast(&grid_forecast)?;
 
@@ -188,7 +254,28 @@ fn get_weather_display_ext(now: i64, fetch_fn: fn(&str) -> result::TTDashResult<
     let current_t_f: Option<f32> = Some(fetch_current_temperature_xml(fetch_fn)?);
     println!("current_t_f: {:?}", current_t_f);
 
+    let daily_forecast = match fetch_daily_forecast(fetch_fn) {
+        Ok(daily_forecast) => daily_forecast,
+        Err(err) => {
+            error!("Error fetching daily forecast: {:?}", err);
+            vec![]
+        },
+    };
+
+    // Prefer the daytime period for each date, falling back to the overnight
+    // one (e.g. "Tonight" once the afternoon period has passed).
+    let mut forecasts_by_date = std::collections::BTreeMap::new();
+    for forecast in &daily_forecast {
+        let date = forecast.start_time.with_timezone(&location.timezone).date();
+        let have_daytime = forecasts_by_date.get(&date)
+            .map(|f: &&DailyForecast| f.is_daytime).unwrap_or(false);
+        if !have_daytime {
+            forecasts_by_date.insert(date, forecast);
+        }
+    }
+
     let mut days = std::collections::BTreeMap::new();
+    let mut hourly_temps = std::collections::BTreeMap::new();
 
     let mut current_date = None;
     let mut min_t = None;
@@ -201,23 +288,32 @@ fn get_weather_display_ext(now: i64, fetch_fn: fn(&str) -> result::TTDashResult<
     let mut first_forecast_t = None;
 
     for (hour, values) in &dense_forecast.hours {
-        let local_time = chrono_tz::US::Eastern.timestamp(hour.timestamp(), 0);
+        let local_time = hour.with_timezone(&location.timezone);
         if hour.timestamp() < min_ts {
             continue;
         }
 
         if Some(local_time.date()) != current_date {
-            match current_date {
-                Some(current_date) => {
+            match (current_date, min_t, max_t) {
+                (Some(current_date), Some(min_t), Some(max_t)) => {
                     // Ending an old day
+                    let sun_times = solar::sun_times(
+                        current_date.naive_local(), location.latitude, location.longitude);
                     days.insert(current_date, WeatherDisplayDay{
-                        min_t: min_t.unwrap(),
-                        max_t: max_t.unwrap(),
-                        max_dew_point: max_dew_point.unwrap(),
+                        min_t: min_t,
+                        max_t: max_t,
+                        max_dew_point: max_dew_point,
+                        condition: forecasts_by_date.get(&current_date).and_then(|f| f.condition),
+                        short_forecast: forecasts_by_date.get(&current_date).map(|f| f.short_forecast.clone()),
+                        sunrise: sun_times.map(|t| t.sunrise.with_timezone(&location.timezone)),
+                        sunset: sun_times.map(|t| t.sunset.with_timezone(&location.timezone)),
                         precip_by_hour: precip_by_hour,
                     });
                 },
-                None => {},
+                (Some(current_date), _, _) => {
+                    warn!("No temperature forecast for {}, skipping day.", current_date);
+                },
+                _ => {},
             }
 
             // Starting a new day
@@ -228,95 +324,157 @@ fn get_weather_display_ext(now: i64, fetch_fn: fn(&str) -> result::TTDashResult<
             precip_by_hour = std::collections::BTreeMap::new();
         }
 
-        if first_forecast_t.is_none() {
-            first_forecast_t = Some(values.temperature);
-        }
+        if let Some(temperature) = values.temperature {
+            if first_forecast_t.is_none() {
+                first_forecast_t = Some(temperature);
+            }
+
+            if min_t.is_none() || temperature < min_t.unwrap() {
+                min_t = Some(temperature);
+            }
+
+            if max_t.is_none() || temperature > max_t.unwrap() {
+                max_t = Some(temperature);
+            }
 
-        if min_t.is_none() || values.temperature < min_t.unwrap() {
-            min_t = Some(values.temperature);
+            hourly_temps.insert(local_time, temperature);
         }
 
-        if max_t.is_none() || values.temperature > max_t.unwrap() {
-            max_t = Some(values.temperature);
+        if let Some(dew_point) = values.dew_point {
+            if max_dew_point.is_none() || dew_point > max_dew_point.unwrap() {
+                max_dew_point = Some(dew_point);
+            }
         }
 
-        if max_dew_point.is_none() || values.dew_point > max_dew_point.unwrap() {
-            max_dew_point = Some(values.dew_point);
+        if let Some(precip_prob) = values.precip_prob {
+            precip_by_hour.insert(hours_since_midnight(&local_time) as u32, precip_prob);
         }
+    }
 
-        precip_by_hour.insert(local_time.hour(), values.precip_prob);
+    let known_temps: Vec<f32> = dense_forecast.hours.values().filter_map(|h| h.temperature).collect();
+    if known_temps.is_empty() {
+        return Err(result::make_error("No temperature data"));
     }
 
     return Ok(WeatherDisplay{
-        overall_min_t: dense_forecast.hours.iter().min_by_key(|(_,e)| e.temperature as u32).ok_or(result::make_error("No overall_min_t data"))?.1.temperature,
-        overall_max_t: dense_forecast.hours.iter().max_by_key(|(_,e)| e.temperature as u32).ok_or(result::make_error("No overall_max_t data"))?.1.temperature,
+        overall_min_t: known_temps.iter().cloned().fold(std::f32::INFINITY, f32::min),
+        overall_max_t: known_temps.iter().cloned().fold(std::f32::NEG_INFINITY, f32::max),
         current_t: current_t_f.or(first_forecast_t).ok_or(result::make_error("No current_t data"))?,
+        hourly_temps: hourly_temps,
         days: days,
     });
 }
 
-// Parses: "PT1H" -> 1 hour, "PT13H" -> 13 hours, etc
+// Parses ISO 8601 durations: "PT1H", "P1DT12H", "P2W", "PT0.5S",
+// "P1Y2M3DT4H5M6.5S", etc.
 // https://en.wikipedia.org/wiki/ISO_8601#Durations
-// TODO(mrjones): Parse day/month/year durations as well?
+//
+// Years and months don't have a fixed length, so they're treated as 365 and
+// 30 days respectively. NWS only uses them for very long-lived values.
 fn parse_duration(input: &str) -> result::TTDashResult<chrono::Duration> {
     let required_prefix = "P";
+    let bad_duration = |reason: &str| {
+        return result::make_error(&format!("Bad duration string '{}': {}", input, reason));
+    };
 
     if !input.starts_with(required_prefix) {
         return Err(result::make_error(&format!("Malformed duration {}", input)));
     }
 
-    // TODO(mrjones): File bug against maplit, for not being able to use hashmap! here
-    let mut time_parsers : std::collections::HashMap<char, fn(i64) -> chrono::Duration> = std::collections::HashMap::new();
-    time_parsers.insert('H', chrono::Duration::hours);
-    time_parsers.insert('M', chrono::Duration::minutes);
-    time_parsers.insert('S', chrono::Duration::seconds);
-
-    let mut date_parsers : std::collections::HashMap<char, fn(i64) -> chrono::Duration> = std::collections::HashMap::new();
-    date_parsers.insert('D', chrono::Duration::days);
+    // Units in the order they must appear, with their length in seconds.
+    let date_units: Vec<(char, f64)> = vec![
+        ('Y', 365.0 * 86400.0), ('M', 30.0 * 86400.0), ('W', 7.0 * 86400.0), ('D', 86400.0)];
+    let time_units: Vec<(char, f64)> = vec![
+        ('H', 3600.0), ('M', 60.0), ('S', 1.0)];
 
     let mut result = chrono::Duration::seconds(0);
-    let mut acc = 0;
+    let mut acc = String::new();
+    let mut num_components = 0;
 
     #[derive(PartialEq)]
     enum ParseRegion { DatePart, TimePart }
 
     let mut parse_region = ParseRegion::DatePart;
+    // Index of the next unit allowed in the current region, to reject
+    // out-of-order or repeated units like "PT1S1H".
+    let mut next_unit = 0;
+    let mut time_components = 0;
 
-    for (i, c) in input.chars().skip(required_prefix.len()).enumerate() {
+    for c in input.chars().skip(required_prefix.len()) {
         if c == 'T' {
+            if parse_region == ParseRegion::TimePart || !acc.is_empty() {
+                return Err(bad_duration("unexpected 'T'"));
+            }
             parse_region = ParseRegion::TimePart;
+            next_unit = 0;
+        } else if c.is_ascii_digit() || c == '.' || c == ',' {
+            acc.push(if c == ',' { '.' } else { c });
         } else {
-            match c.to_digit(10) {
-                Some(d) => acc = (10 * acc) + (d as i64),
-                None => {
-                    let parsers = if parse_region == ParseRegion::DatePart { &date_parsers} else { &time_parsers };
-                    match parsers.get(&c) {
-                        Some(num_to_duration_fn) => {
-                            result = result + num_to_duration_fn(acc);
-                            acc = 0;
-                        },
-                        None => {
-                            return Err(result::make_error(&format!("Bad duration string '{}' at char #{}. ", input, i)));
-                        }
-                    }
-                },
+            let units = if parse_region == ParseRegion::DatePart { &date_units } else { &time_units };
+            let unit_index = units.iter().skip(next_unit).position(|(u, _)| *u == c)
+                .map(|i| i + next_unit)
+                .ok_or(bad_duration(&format!("unexpected '{}'", c)))?;
+            let value: f64 = acc.parse()
+                .map_err(|_| bad_duration(&format!("bad number '{}'", acc)))?;
+
+            let nanos = (value * units[unit_index].1 * 1e9).round();
+            // `as i64` would saturate, so reject anything that won't fit.
+            if !(nanos < i64::MAX as f64) {
+                return Err(bad_duration("too long"));
+            }
+            result = result.checked_add(&chrono::Duration::nanoseconds(nanos as i64))
+                .ok_or(bad_duration("too long"))?;
+            acc.clear();
+            next_unit = unit_index + 1;
+            num_components += 1;
+            if parse_region == ParseRegion::TimePart {
+                time_components += 1;
             }
         }
     }
 
+    if !acc.is_empty() {
+        return Err(bad_duration(&format!("number '{}' has no unit", acc)));
+    }
+    if num_components == 0 {
+        return Err(bad_duration("no components"));
+    }
+    if parse_region == ParseRegion::TimePart && time_components == 0 {
+        return Err(bad_duration("no time components after 'T'"));
+    }
+
     return Ok(result);
 }
 
+// Parses ISO 8601 intervals in any of the forms NWS emits:
+//   <start>/<duration>  e.g. "2019-08-12T10:00:00+00:00/PT1H"
+//   <start>/<end>       e.g. "2019-08-12T10:00:00+00:00/2019-08-12T11:00:00+00:00"
+//   <duration>/<end>    e.g. "PT1H/2019-08-12T11:00:00+00:00"
 fn parse_time_and_duration(input: &str) -> result::TTDashResult<(chrono::DateTime<chrono::FixedOffset>, chrono::Duration)> {
     let parts: Vec<&str> = input.split("/").collect();
 
-    if parts.len() < 2 {
+    if parts.len() != 2 {
         return Err(result::make_error(&format!(
             "Couldn't parse time+duration string: '{}'", input)));
     }
 
-    return Ok((chrono::DateTime::parse_from_rfc3339(parts[0])?,
-               parse_duration(parts[1])?));
+    if parts[0].starts_with("P") {
+        let duration = parse_duration(parts[0])?;
+        let end = chrono::DateTime::parse_from_rfc3339(parts[1])?;
+        return Ok((end - duration, duration));
+    }
+
+    let start = chrono::DateTime::parse_from_rfc3339(parts[0])?;
+    if parts[1].starts_with("P") {
+        return Ok((start, parse_duration(parts[1])?));
+    }
+
+    let end = chrono::DateTime::parse_from_rfc3339(parts[1])?;
+    if end < start {
+        return Err(result::make_error(&format!(
+            "Interval ends before it starts: '{}'", input)));
+    }
+    return Ok((start, end - start));
 }
 
 fn parse_grid_entry(entry: &NwsApiGridValue) -> result::TTDashResult<GridForecastEntry> {
@@ -393,67 +551,97 @@ pub fn fetch_grid_forecast(fetch_fn: fn(&str) -> result::TTDashResult<String>) -
     });
 }
 
+// Returns the start of every clock hour that [time, time + duration)
+// overlaps. e.g. "10:30/PT20M" -> [10:00], "10:30/PT1H" -> [10:00, 11:00].
+// A zero-length entry still counts for the hour it falls in.
+fn covered_hours(entry: &GridForecastEntry) -> Vec<chrono::DateTime<chrono::FixedOffset>> {
+    use chrono::Timelike;
+
+    let end = entry.time + entry.duration;
+    let mut hour = entry.time
+        - chrono::Duration::seconds(entry.time.timestamp().rem_euclid(3600))
+        - chrono::Duration::nanoseconds(entry.time.nanosecond() as i64);
+
+    let mut result = vec![hour];
+    hour = hour + chrono::Duration::hours(1);
+    while hour < end {
+        result.push(hour);
+        hour = hour + chrono::Duration::hours(1);
+    }
+    return result;
+}
+
+// Later entries win when two entries of the same series cover the same hour.
 pub fn densify_grid_forecast(sparse: &GridForecast) -> result::TTDashResult<DenseGridForecast> {
     let mut result = DenseGridForecast{
         hours: std::collections::BTreeMap::new(),
     };
 
     for precip_entry in &sparse.precip_prob {
-        for i in 0..precip_entry.duration.num_hours() {
-            let hour = precip_entry.time + chrono::Duration::hours(i);
-            let mut hour_entry = result.hours.entry(hour)
-                .or_insert(DenseGridHour{precip_prob: 0.0, temperature: 0.0, dew_point: 0.0});
-
-            hour_entry.precip_prob = precip_entry.value;
+        for hour in covered_hours(precip_entry) {
+            result.hours.entry(hour).or_insert_with(DenseGridHour::default)
+                .precip_prob = Some(precip_entry.value);
         }
     }
 
     for temp_entry in &sparse.temp {
-        for i in 0..temp_entry.duration.num_hours() {
-            let hour = temp_entry.time + chrono::Duration::hours(i);
-            let mut hour_entry = result.hours.entry(hour)
-                .or_insert(DenseGridHour{precip_prob: 0.0, temperature: 0.0, dew_point: 0.0});
-
-            hour_entry.temperature = temp_entry.value;
+        for hour in covered_hours(temp_entry) {
+            result.hours.entry(hour).or_insert_with(DenseGridHour::default)
+                .temperature = Some(temp_entry.value);
         }
     }
 
     for dew_point_entry in &sparse.dew_point {
-        for i in 0..dew_point_entry.duration.num_hours() {
-            let hour = dew_point_entry.time + chrono::Duration::hours(i);
-            let mut hour_entry = result.hours.entry(hour)
-                .or_insert(DenseGridHour{precip_prob: 0.0, temperature: 0.0, dew_point: 0.0});
-
-            hour_entry.dew_point = dew_point_entry.value;
+        for hour in covered_hours(dew_point_entry) {
+            result.hours.entry(hour).or_insert_with(DenseGridHour::default)
+                .dew_point = Some(dew_point_entry.value);
         }
     }
 
     return Ok(result);
 }
 
-/*
-pub fn fetch_daily_forecast() -> result::TTDashResult<Vec<DailyForecast>> {
-    use std::io::Read;
-
-    let url = format!("https://api.weather.gov/gridpoints/OKX/32,34/forecast");
-
-    let mut response = reqwest::get(&url)?;
-    let mut response_body = String::new();
-    response.read_to_string(&mut response_body)?;
+// Maps NWS "shortForecast" strings (e.g. "Chance Rain Showers then Mostly
+// Clear") onto a Condition. Wherever they come in the forecast, the most
+// significant weather wins: thunder over snow over rain, and so on down.
+fn parse_condition(short_forecast: &str) -> Option<Condition> {
+    let short_forecast = short_forecast.to_lowercase();
+    let keywords = vec![
+        (Condition::Thunder, vec!["thunder", "t-storm"]),
+        (Condition::Snow, vec!["snow", "flurries", "sleet", "blizzard", "ice pellets", "freezing rain", "freezing drizzle"]),
+        (Condition::Rain, vec!["rain", "showers", "drizzle"]),
+        (Condition::Fog, vec!["fog", "haze", "mist", "smoke"]),
+        (Condition::Sunny, vec!["sunny", "clear"]),
+        (Condition::Cloudy, vec!["cloudy", "overcast"]),
+    ];
+
+    for (condition, words) in keywords {
+        if words.iter().any(|w| short_forecast.contains(w)) {
+            return Some(condition);
+        }
+    }
+    return None;
+}
 
-    let forecast: NwsApiForecast = serde_json::from_str(&response_body)?;
+pub fn fetch_daily_forecast(fetch_fn: fn(&str) -> result::TTDashResult<String>) -> result::TTDashResult<Vec<DailyForecast>> {
+    let url = format!("https://api.weather.gov/gridpoints/OKX/33,32/forecast");
+    let response_body = fetch_fn(&url).context("while fetching daily forecast")?;
+    let forecast: NwsApiForecast = serde_json::from_str(&response_body)
+        .with_context(|| format!("while parsing json: \"{}\"", response_body))?;
 
     let mut result = vec![];
-    for period in forecast.properties.periods {
+    for period in forecast.properties.periods.ok_or(result::make_error("No forecast periods"))? {
         result.push(DailyForecast{
-            label: period.name,
-            temperature: period.temperature,
+            start_time: chrono::DateTime::parse_from_rfc3339(&period.start_time)?,
+            is_daytime: period.is_daytime,
+            condition: parse_condition(&period.short_forecast),
             short_forecast: period.short_forecast,
         });
     }
     return Ok(result);
 }
 
+/*
 pub fn fetch_hourly_forecast() -> result::TTDashResult<Vec<HourlyForecast>> {
     use std::io::Read;
 
@@ -479,9 +667,13 @@ pub fn fetch_hourly_forecast() -> result::TTDashResult<Vec<HourlyForecast>> {
 #[cfg(test)]
 mod tests {
     extern crate chrono;
+    extern crate quickcheck;
 
+    use super::parse_condition;
     use super::parse_duration;
+    use super::parse_time_and_duration;
     use super::ctof;
+    use super::Condition;
 
     #[test]
     fn simple_time_durations() {
@@ -508,6 +700,330 @@ mod tests {
         assert_eq!(chrono::Duration::hours(36), parse_duration("P1DT12H").unwrap());
     }
 
+    #[test]
+    fn week_month_year_durations() {
+        assert_eq!(chrono::Duration::weeks(2), parse_duration("P2W").unwrap());
+        assert_eq!(chrono::Duration::days(30), parse_duration("P1M").unwrap());
+        assert_eq!(chrono::Duration::days(365 + 60 + 3) + chrono::Duration::hours(4),
+                   parse_duration("P1Y2M3DT4H").unwrap());
+    }
+
+    #[test]
+    fn fractional_durations() {
+        assert_eq!(chrono::Duration::milliseconds(500), parse_duration("PT0.5S").unwrap());
+        assert_eq!(chrono::Duration::milliseconds(1250), parse_duration("PT1,25S").unwrap());
+        assert_eq!(chrono::Duration::minutes(90), parse_duration("PT1.5H").unwrap());
+    }
+
+    #[test]
+    fn malformed_durations() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("P").is_err());
+        assert!(parse_duration("PT").is_err());
+        assert!(parse_duration("1H").is_err());
+        assert!(parse_duration("PT1H30").is_err());   // Trailing digits without a unit
+        assert!(parse_duration("PT1S1H").is_err());   // Out of order
+        assert!(parse_duration("PT1H1H").is_err());   // Repeated
+        assert!(parse_duration("P1H").is_err());      // Time unit in the date part
+        assert!(parse_duration("PT1D").is_err());     // Date unit in the time part
+        assert!(parse_duration("P1DTT1H").is_err());
+        assert!(parse_duration("PT1.2.3S").is_err());
+        assert!(parse_duration("P999999999999Y").is_err());  // Too long
+    }
+
+    #[test]
+    fn interval_forms() {
+        let start = chrono::DateTime::parse_from_rfc3339("2019-08-12T10:00:00+00:00").unwrap();
+        let expected = (start, chrono::Duration::hours(2));
+
+        assert_eq!(expected, parse_time_and_duration("2019-08-12T10:00:00+00:00/PT2H").unwrap());
+        assert_eq!(expected, parse_time_and_duration("2019-08-12T10:00:00+00:00/2019-08-12T12:00:00+00:00").unwrap());
+        assert_eq!(expected, parse_time_and_duration("PT2H/2019-08-12T12:00:00+00:00").unwrap());
+
+        assert!(parse_time_and_duration("2019-08-12T10:00:00+00:00").is_err());
+        assert!(parse_time_and_duration("2019-08-12T10:00:00+00:00/PT1H/PT1H").is_err());
+        assert!(parse_time_and_duration("2019-08-12T12:00:00+00:00/2019-08-12T10:00:00+00:00").is_err());
+    }
+
+    #[test]
+    fn duration_components_property() {
+        fn prop(weeks: u8, days: u8, hours: u8, minutes: u8, seconds: u8, millis: u16) -> bool {
+            let millis = millis % 1000;
+            let input = format!("P{}W{}DT{}H{}M{}.{:03}S", weeks, days, hours, minutes, seconds, millis);
+            let expected = chrono::Duration::weeks(weeks as i64)
+                + chrono::Duration::days(days as i64)
+                + chrono::Duration::hours(hours as i64)
+                + chrono::Duration::minutes(minutes as i64)
+                + chrono::Duration::seconds(seconds as i64)
+                + chrono::Duration::milliseconds(millis as i64);
+            return parse_duration(&input).ok() == Some(expected);
+        }
+        quickcheck::quickcheck(prop as fn(u8, u8, u8, u8, u8, u16) -> bool);
+    }
+
+    #[test]
+    fn duration_omitted_components_property() {
+        // Any subset of components, in order, parses to the sum of its parts.
+        fn prop(days: Option<u16>, hours: Option<u16>, minutes: Option<u16>, seconds: Option<u16>) -> bool {
+            let mut input = "P".to_string();
+            let mut expected = chrono::Duration::seconds(0);
+            if let Some(d) = days {
+                input.push_str(&format!("{}D", d));
+                expected = expected + chrono::Duration::days(d as i64);
+            }
+            if hours.is_some() || minutes.is_some() || seconds.is_some() {
+                input.push('T');
+            }
+            for (value, unit, seconds_per_unit) in vec![(hours, 'H', 3600), (minutes, 'M', 60), (seconds, 'S', 1)] {
+                if let Some(v) = value {
+                    input.push_str(&format!("{}{}", v, unit));
+                    expected = expected + chrono::Duration::seconds(v as i64 * seconds_per_unit);
+                }
+            }
+
+            let parsed = parse_duration(&input);
+            if input == "P" {
+                return parsed.is_err();
+            }
+            return parsed.ok() == Some(expected);
+        }
+        quickcheck::quickcheck(prop as fn(Option<u16>, Option<u16>, Option<u16>, Option<u16>) -> bool);
+    }
+
+    #[test]
+    fn interval_forms_agree_property() {
+        fn prop(start_offset_minutes: u32, duration_minutes: u16, tz_offset_hours: i8) -> bool {
+            use chrono::TimeZone;
+
+            // Keep within a decade so the year stays RFC 3339 representable.
+            let start_offset_minutes = start_offset_minutes % (10 * 365 * 24 * 60);
+            let offset = chrono::FixedOffset::east_opt((tz_offset_hours % 12) as i32 * 3600).unwrap();
+            let start = offset.timestamp_opt(1565600000 + start_offset_minutes as i64 * 60, 0).unwrap();
+            let duration = chrono::Duration::minutes(duration_minutes as i64);
+            let end = start + duration;
+            let duration_str = format!("PT{}M", duration_minutes);
+
+            let forms = vec![
+                format!("{}/{}", start.to_rfc3339(), duration_str),
+                format!("{}/{}", start.to_rfc3339(), end.to_rfc3339()),
+                format!("{}/{}", duration_str, end.to_rfc3339()),
+            ];
+            return forms.iter().all(|f| parse_time_and_duration(f).ok() == Some((start, duration)));
+        }
+        quickcheck::quickcheck(prop as fn(u32, u16, i8) -> bool);
+    }
+
+    #[test]
+    fn short_forecast_conditions() {
+        assert_eq!(Some(Condition::Sunny), parse_condition("Sunny"));
+        assert_eq!(Some(Condition::Sunny), parse_condition("Mostly Clear"));
+        assert_eq!(Some(Condition::Cloudy), parse_condition("Partly Cloudy"));
+        assert_eq!(Some(Condition::Rain), parse_condition("Chance Rain Showers then Mostly Clear"));
+        assert_eq!(Some(Condition::Thunder), parse_condition("Showers And Thunderstorms Likely"));
+        assert_eq!(Some(Condition::Snow), parse_condition("Rain And Snow Likely"));
+        assert_eq!(Some(Condition::Fog), parse_condition("Patchy Fog"));
+        assert_eq!(Some(Condition::Snow), parse_condition("Chance Freezing Rain"));
+        assert_eq!(Some(Condition::Snow), parse_condition("Rain And Ice Pellets"));
+        // "ice" alone is part of too many other words.
+        assert_eq!(Some(Condition::Sunny), parse_condition("Sunny And Nice"));
+        assert_eq!(Some(Condition::Fog), parse_condition("Patchy Freezing Fog"));
+        assert_eq!(None, parse_condition("Breezy"));
+    }
+
+    #[test]
+    fn fetch_daily_forecast_golden_test() {
+        let golden_fetcher = |_: &str| {
+            // curl 'https://api.weather.gov/gridpoints/OKX/33,32/forecast' > testdata/nwsforecast.json
+            return Ok(std::fs::read_to_string("testdata/nwsforecast.json")
+                .expect("Something went wrong reading the file"));
+        };
+
+        let forecast = super::fetch_daily_forecast(golden_fetcher).unwrap();
+
+        assert_eq!(7, forecast.len());
+        assert_eq!("2019-08-12T15:00:00-04:00", forecast[0].start_time.to_rfc3339());
+        assert!(forecast[0].is_daytime);
+        assert_eq!(Some(Condition::Sunny), forecast[0].condition);
+        assert!(!forecast[1].is_daytime);
+        assert_eq!("2019-08-13T06:00:00-04:00", forecast[2].start_time.to_rfc3339());
+        assert_eq!(Some(Condition::Thunder), forecast[2].condition);
+    }
+
+    #[test]
+    fn hours_since_midnight_across_dst() {
+        use chrono::TimeZone;
+        let at = |ts: i64| chrono_tz::US::Eastern.timestamp_opt(ts, 0).unwrap();
+
+        // 2019-11-03: 1am happens twice
+        assert_eq!(0.0, super::hours_since_midnight(&at(1572753600)));  // 00:00 EDT
+        assert_eq!(1.0, super::hours_since_midnight(&at(1572757200)));  // 01:00 EDT
+        assert_eq!(2.0, super::hours_since_midnight(&at(1572760800)));  // 01:00 EST
+        assert_eq!(3.0, super::hours_since_midnight(&at(1572764400)));  // 02:00 EST
+        assert_eq!(24.0, super::hours_since_midnight(&at(1572840000))); // 23:00 EST
+
+        // 2019-03-10: 2am never happens
+        assert_eq!(2.0, super::hours_since_midnight(&at(1552201200)));  // 03:00 EDT
+        assert_eq!(22.0, super::hours_since_midnight(&at(1552273200))); // 23:00 EDT
+    }
+
+    #[test]
+    fn hours_in_day_across_dst() {
+        let date = |y, m, d| chrono::NaiveDate::from_ymd_opt(y, m, d).unwrap();
+
+        assert_eq!(25, super::hours_in_day(date(2019, 11, 3), chrono_tz::US::Eastern));
+        assert_eq!(23, super::hours_in_day(date(2019, 3, 10), chrono_tz::US::Eastern));
+        assert_eq!(24, super::hours_in_day(date(2019, 8, 12), chrono_tz::US::Eastern));
+        assert_eq!(24, super::hours_in_day(date(2019, 11, 3), chrono_tz::UTC));
+    }
+
+    #[test]
+    fn upcoming_temps() {
+        use chrono::TimeZone;
+        let at = |ts: i64| chrono_tz::US::Eastern.timestamp_opt(ts, 0).unwrap();
+
+        // 10:00 to 13:00 EDT, 2019-08-12
+        let weather_display = super::WeatherDisplay{
+            overall_min_t: 70.0,
+            overall_max_t: 73.0,
+            current_t: 71.0,
+            hourly_temps: (0..4).map(|i| (at(1565618400 + i * 3600), 70.0 + i as f32)).collect(),
+            days: std::collections::BTreeMap::new(),
+        };
+        let temps = |now: i64| -> Vec<f32> {
+            return weather_display.upcoming_temps(now).map(|(_, t)| *t).collect();
+        };
+        assert_eq!(vec![70.0, 71.0, 72.0, 73.0], temps(1565618400));
+        // 11:30, then 13:00 and after.
+        assert_eq!(vec![71.0, 72.0, 73.0], temps(1565623800));
+        assert_eq!(vec![73.0], temps(1565629200));
+        assert!(temps(1565632800).is_empty());
+    }
+
+    // One-hour entries starting at start_ts, where each value is the index
+    // of its entry.
+    fn fake_series_json(start_ts: i64, num_hours: i64) -> String {
+        let values: Vec<String> = (0..num_hours).map(|i| {
+            let time = chrono::DateTime::from_timestamp(start_ts + i * 3600, 0).unwrap();
+            return format!("{{\"validTime\": \"{}/PT1H\", \"value\": {}}}", time.to_rfc3339(), i);
+        }).collect();
+        return format!("{{\"uom\": \"wmoUnit:percent\", \"values\": [{}]}}", values.join(","));
+    }
+
+    fn fake_grid_json(start_ts: i64, num_hours: i64) -> String {
+        let series = fake_series_json(start_ts, num_hours);
+        return format!(
+            "{{\"properties\": {{\"temperature\": {}, \"probabilityOfPrecipitation\": {}, \"dewpoint\": {}}}}}",
+            series, series, series);
+    }
+
+    // Answers the current observation and daily forecast endpoints with
+    // minimal documents, and the gridpoint endpoint with `grid_json`.
+    fn fake_fetch(url: &str, grid_json: fn() -> String) -> super::result::TTDashResult<String> {
+        if url.ends_with(".xml") {
+            return Ok("<current_observation><temp_f>50.0</temp_f></current_observation>".to_string());
+        } else if url.ends_with("/forecast") {
+            return Ok("{\"properties\": {\"periods\": []}}".to_string());
+        }
+        return Ok(grid_json());
+    }
+
+    fn dst_precip_by_hour(fetch_fn: fn(&str) -> super::result::TTDashResult<String>, now: i64) -> std::collections::BTreeMap<u32, f32> {
+        let location = super::Location{latitude: 40.7128, longitude: -74.0060, timezone: chrono_tz::US::Eastern};
+        let result = super::get_weather_display_ext(now + 3600, &location, fetch_fn).unwrap();
+        let (_date, first_data) = result.days.iter().nth(0).expect("couldn't fetch first day");
+        return first_data.precip_by_hour.clone();
+    }
+
+    #[test]
+    fn precip_by_hour_fall_back() {
+        // Midnight EDT, 2019-11-03
+        let fetcher = |url: &str| fake_fetch(url, || fake_grid_json(1572753600, 30));
+
+        let precip_by_hour = dst_precip_by_hour(fetcher, 1572753600);
+        assert_eq!(25, precip_by_hour.len());
+        for (hour, precip) in precip_by_hour {
+            assert_eq!(hour as f32, precip);
+        }
+    }
+
+    #[test]
+    fn precip_by_hour_spring_forward() {
+        // Midnight EST, 2019-03-10
+        let fetcher = |url: &str| fake_fetch(url, || fake_grid_json(1552194000, 30));
+
+        let precip_by_hour = dst_precip_by_hour(fetcher, 1552194000);
+        assert_eq!(23, precip_by_hour.len());
+        for (hour, precip) in precip_by_hour {
+            assert_eq!(hour as f32, precip);
+        }
+    }
+
+    fn grid_entry(time: &str, duration: &str, value: f32) -> super::GridForecastEntry {
+        return super::GridForecastEntry{
+            time: chrono::DateTime::parse_from_rfc3339(time).unwrap(),
+            duration: parse_duration(duration).unwrap(),
+            value: value,
+        };
+    }
+
+    fn hour(time: &str) -> chrono::DateTime<chrono::FixedOffset> {
+        return chrono::DateTime::parse_from_rfc3339(time).unwrap();
+    }
+
+    #[test]
+    fn densify_partial_and_misaligned_intervals() {
+        let sparse = super::GridForecast{
+            precip_prob: vec![
+                grid_entry("2019-08-12T10:30:00+00:00", "PT20M", 10.0),
+                grid_entry("2019-08-12T11:30:00+00:00", "PT1H", 20.0),
+            ],
+            temp: vec![
+                grid_entry("2019-08-12T10:00:00+00:00", "P1DT6H", 25.0),
+            ],
+            dew_point: vec![],
+        };
+
+        let dense = super::densify_grid_forecast(&sparse).unwrap();
+
+        assert_eq!(Some(10.0), dense.hours[&hour("2019-08-12T10:00:00+00:00")].precip_prob);
+        assert_eq!(Some(20.0), dense.hours[&hour("2019-08-12T11:00:00+00:00")].precip_prob);
+        assert_eq!(Some(20.0), dense.hours[&hour("2019-08-12T12:00:00+00:00")].precip_prob);
+        assert_eq!(None, dense.hours[&hour("2019-08-12T13:00:00+00:00")].precip_prob);
+
+        assert_eq!(30, dense.hours.values().filter(|h| h.temperature == Some(25.0)).count());
+        assert_eq!(Some(25.0), dense.hours[&hour("2019-08-13T15:00:00+00:00")].temperature);
+        assert!(!dense.hours.contains_key(&hour("2019-08-13T16:00:00+00:00")));
+
+        assert!(dense.hours.values().all(|h| h.dew_point.is_none()));
+    }
+
+    #[test]
+    fn aggregates_ignore_unknown_hours() {
+        // Midnight EDT, 2019-08-12. Temperatures only cover the first 10
+        // hours; precip and dew point cover 30.
+        let fetcher = |url: &str| fake_fetch(url, || {
+            let full = fake_series_json(1565582400, 30);
+            return format!(
+                "{{\"properties\": {{\"temperature\": {}, \"probabilityOfPrecipitation\": {}, \"dewpoint\": {}}}}}",
+                fake_series_json(1565582400, 10), full, full);
+        });
+
+        let location = super::Location{latitude: 40.7128, longitude: -74.0060, timezone: chrono_tz::US::Eastern};
+        let result = super::get_weather_display_ext(1565582400 + 3600, &location, fetcher).unwrap();
+
+        assert_eq!(ctof(0.0), result.overall_min_t);
+        assert_eq!(ctof(9.0), result.overall_max_t);
+        assert_eq!(10, result.hourly_temps.len());
+
+        let (_date, first_data) = result.days.iter().nth(0).expect("couldn't fetch first day");
+        assert_eq!(ctof(0.0), first_data.min_t);
+        assert_eq!(ctof(9.0), first_data.max_t);
+        assert_eq!(Some(ctof(23.0)), first_data.max_dew_point);
+        assert_eq!(24, first_data.precip_by_hour.len());
+        assert_eq!("06:04", first_data.sunrise.expect("sunrise").format("%H:%M").to_string());
+        assert_eq!("20:00", first_data.sunset.expect("sunset").format("%H:%M").to_string());
+    }
+
     #[test]
     fn fetch_golden_test() {
         let golden_fetcher = |_: &str| {
@@ -522,16 +1038,20 @@ mod tests {
         // GMT is 4 hours ahead
         let golden_timestamp = 1565638425;
 
-        let result = super::get_weather_display_ext(golden_timestamp, golden_fetcher).unwrap();
+        let location = super::Location{latitude: 40.7128, longitude: -74.0060, timezone: chrono_tz::US::Eastern};
+        let result = super::get_weather_display_ext(golden_timestamp, &location, golden_fetcher).unwrap();
 
         assert_eq!(ctof(20.5555555555556), result.overall_min_t);
         assert_eq!(ctof(30.000000000000057), result.overall_max_t);
         assert_eq!(ctof(30.000000000000057), result.current_t);
+        assert_eq!(Some(&ctof(30.000000000000057)), result.hourly_temps.values().nth(0));
 
         let (_first_date, first_data) = result.days.iter().nth(0).expect("couldn't fetch first day");
         assert_eq!(ctof(25.5555555555556), first_data.min_t);
         assert_eq!(ctof(30.000000000000057), first_data.max_t);
-        assert_eq!(ctof(17.777777777777828), first_data.max_dew_point);
+        assert_eq!(Some(ctof(17.777777777777828)), first_data.max_dew_point);
+        assert_eq!("06:04", first_data.sunrise.expect("sunrise").format("%H:%M").to_string());
+        assert_eq!("20:00", first_data.sunset.expect("sunset").format("%H:%M").to_string());
 
         let mut expected_precip = std::collections::BTreeMap::new();
         expected_precip.insert(15, 0.0);  // Don't really _need_ old points